@@ -92,10 +92,36 @@ class Crypto(object):
     def _compute_shared_secret(self, priv_key:int, other_pub_key:int) -> int:
         return (other_pub_key ** priv_key) % self.p
 
+# The python_compat vectors in chat/test_vectors.json are shared with the Rust
+# crypto_utils tests, so both implementations are held to the same bytes.
+def check_test_vectors(path:str) -> int:
+    with open(path) as f:
+        vectors = json.load(f)["python_compat"]
+    for vector in vectors:
+        dh1 = Crypto()
+        dh2 = Crypto()
+        assert (dh1.p, dh1.g) == (vector["p"], vector["g"])
+        dh1.priv_key = vector["a_priv"]
+        dh2.priv_key = vector["b_priv"]
+        pub1 = Crypto._serialize_key(dh1._mk_pub_key(dh1.priv_key))
+        pub2 = Crypto._serialize_key(dh2._mk_pub_key(dh2.priv_key))
+        assert pub1 == vector["a_pub"].encode()
+        assert pub2 == vector["b_pub"].encode()
+
+        dh1.handshake(pub2)
+        dh2.handshake(pub1)
+        assert dh1.aes_secret == bytes.fromhex(vector["shared_secret"])
+        assert dh1.aes_secret == dh2.aes_secret == bytes.fromhex(vector["key"])
+        for message in vector["messages"]:
+            plaintext = bytes.fromhex(message["plaintext"])
+            ciphertext = bytes.fromhex(message["ciphertext"])
+            assert dh1.encrypt(plaintext) == ciphertext
+            assert dh2.decrypt(ciphertext) == plaintext
+    return len(vectors)
+
 # Want to test your work?
 # Run this file with python3.
 if __name__ == '__main__':
-    # write some tests here
-    dh1 = Crypto()
-    dh2 = Crypto()
-    pass
\ No newline at end of file
+    import os
+    path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "..", "test_vectors.json")
+    print(f"{check_test_vectors(path)} test vectors passed")
@@ -0,0 +1,364 @@
+use crypto_utils::{first_message_ephemeral, PrekeyBundle, RatchetSession, UserIdentity};
+use std::collections::{HashMap, HashSet};
+
+// One-time prekeys published at login; each gives one new conversation's first messages
+// forward secrecy, and once they run out sessions still start without it
+const ONE_TIME_PREKEYS: usize = 20;
+
+// If both ends start a session at once there's briefly more than one; keep a few, newest first.
+// New ones only come from bundles we asked for and first messages we haven't seen before,
+// so neither a replay nor a stray bundle can push out the one in use.
+const MAX_SESSIONS_PER_USER: usize = 3;
+
+/** End-to-end encrypted direct messages to other users.
+    The server only ever sees `/prekeys`, `/bundle` and `/dm` lines with opaque hex in them:
+    it stores our prekey bundle, hands out bundles on request and relays messages as they are.
+    The identity is kept in a key file between runs, so peers see the same fingerprint.
+*/
+pub struct DirectMessages {
+    identity: UserIdentity,
+    sessions: HashMap<String, Vec<RatchetSession>>,
+    // Ephemeral keys of the first messages we started sessions from, so none starts two
+    accepted: HashMap<String, HashSet<[u8; 32]>>,
+    // Messages waiting for the recipient's prekey bundle
+    pending: HashMap<String, Vec<String>>,
+}
+
+// What a line turned into: lines for the server, and lines for the user
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub send: Vec<String>,
+    pub show: Vec<String>,
+}
+
+impl Outcome {
+    fn show(line: String) -> Outcome {
+        Outcome {
+            send: Vec::new(),
+            show: vec![line],
+        }
+    }
+}
+
+impl DirectMessages {
+    pub fn new(identity: UserIdentity) -> DirectMessages {
+        DirectMessages {
+            identity,
+            sessions: HashMap::new(),
+            accepted: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn fingerprint(&self) -> String {
+        self.identity.public_key().fingerprint()
+    }
+
+    // The server only takes this once it has granted us a username
+    pub fn publish(&mut self) -> String {
+        let bundle = self.identity.prekey_bundle(ONE_TIME_PREKEYS);
+        format!("/prekeys {}", bundle.to_hex())
+    }
+
+    // `/msg <user> <text>`: encrypt straight away, or ask for their bundle first
+    pub fn compose(&mut self, user: &str, text: &str) -> Outcome {
+        if let Some(session) = self.sessions.get_mut(user).and_then(|s| s.first_mut()) {
+            return match session.encrypt(text.as_bytes()) {
+                Ok(message) => Outcome {
+                    send: vec![format!("/dm {} {}", user, hex::encode(message))],
+                    show: vec![format!("To {} (direct): {}", user, text)],
+                },
+                Err(e) => Outcome::show(format!("Could not encrypt for {}: {}", user, e)),
+            };
+        }
+        let pending = self.pending.entry(user.to_string()).or_default();
+        pending.push(text.to_string());
+        // one request is enough, however many messages pile up while it's answered
+        if pending.len() > 1 {
+            return Outcome::default();
+        }
+        Outcome {
+            send: vec![format!("/bundle {}", user)],
+            show: Vec::new(),
+        }
+    }
+
+    // A line from the server, or None if it isn't about direct messages
+    pub fn handle(&mut self, line: &str) -> Option<Outcome> {
+        if let Some(rest) = line.strip_prefix("/bundle ") {
+            let (user, bundle) = rest.split_once(' ').unwrap_or((rest, ""));
+            Some(self.bundle_arrived(user, bundle))
+        } else if let Some(rest) = line.strip_prefix("/dm ") {
+            let (user, message) = rest.split_once(' ').unwrap_or((rest, ""));
+            Some(self.message_arrived(user, message))
+        } else {
+            None
+        }
+    }
+
+    fn bundle_arrived(&mut self, user: &str, bundle: &str) -> Outcome {
+        // a session we didn't ask for would take over from the one we're using
+        let Some(queued) = self.pending.remove(user) else {
+            return Outcome::show(format!(
+                "Ignored a prekey bundle for {} we didn't ask for",
+                user
+            ));
+        };
+        if bundle.is_empty() {
+            return Outcome::show(format!(
+                "{} can't receive direct messages; dropped {} message(s)",
+                user,
+                queued.len()
+            ));
+        }
+        let session = PrekeyBundle::from_hex(bundle).and_then(|b| self.identity.initiate(&b));
+        let session = match session {
+            Ok(session) => session,
+            Err(e) => return Outcome::show(format!("Bad prekey bundle for {}: {}", user, e)),
+        };
+
+        let mut outcome = Outcome::show(self.started(user, session));
+        for text in queued {
+            let sent = self.compose(user, &text);
+            outcome.send.extend(sent.send);
+            outcome.show.extend(sent.show);
+        }
+        outcome
+    }
+
+    fn message_arrived(&mut self, user: &str, message: &str) -> Outcome {
+        let Ok(message) = hex::decode(message) else {
+            return Outcome::show(format!("Unreadable direct message from {}", user));
+        };
+        // a session that fails leaves no trace, so just try each
+        let sessions = self.sessions.entry(user.to_string()).or_default();
+        let mut error = None;
+        for i in 0..sessions.len() {
+            match sessions[i].decrypt(&message) {
+                Ok(plaintext) => {
+                    // whichever session the peer is using is the one to answer on
+                    let session = sessions.remove(i);
+                    sessions.insert(0, session);
+                    return Self::received(user, &plaintext, Vec::new());
+                }
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        // no session yet, or the peer has started a new one, unless it's an old first message again
+        let ephemeral = first_message_ephemeral(&message);
+        let accepted = self.accepted.entry(user.to_string()).or_default();
+        if ephemeral.is_some_and(|ephemeral| accepted.contains(&ephemeral)) {
+            return Outcome::show(format!("Ignored a replayed direct message from {}", user));
+        }
+        match self.identity.accept(&message) {
+            Ok((session, plaintext)) => {
+                accepted.extend(ephemeral);
+                let started = self.started(user, session);
+                Self::received(user, &plaintext, vec![started])
+            }
+            Err(e) => Outcome::show(format!(
+                "Could not decrypt a direct message from {}: {}",
+                user,
+                error.unwrap_or(e)
+            )),
+        }
+    }
+
+    // Remember a new session, warning if the peer's identity isn't the one we knew
+    fn started(&mut self, user: &str, session: RatchetSession) -> String {
+        let fingerprint = session.peer().fingerprint();
+        let sessions = self.sessions.entry(user.to_string()).or_default();
+        let changed = sessions.first().is_some_and(|s| s.peer() != session.peer());
+        sessions.insert(0, session);
+        sessions.truncate(MAX_SESSIONS_PER_USER);
+        if changed {
+            format!(
+                "WARNING: {}'s identity has changed to {}; check it with them before trusting it",
+                user, fingerprint
+            )
+        } else {
+            format!(
+                "End-to-end session with {}, fingerprint {}",
+                user, fingerprint
+            )
+        }
+    }
+
+    fn received(user: &str, plaintext: &[u8], mut show: Vec<String>) -> Outcome {
+        show.push(format!(
+            "{} (direct): {}",
+            user,
+            String::from_utf8_lossy(plaintext)
+        ));
+        Outcome {
+            send: Vec::new(),
+            show,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What the server does with our lines: keep bundles, hand one out per request, relay messages
+    struct Relay {
+        bundles: HashMap<String, PrekeyBundle>,
+    }
+
+    impl Relay {
+        fn publish(&mut self, user: &str, client: &mut DirectMessages) {
+            let line = client.publish();
+            let bundle = PrekeyBundle::from_hex(line.strip_prefix("/prekeys ").unwrap()).unwrap();
+            self.bundles.insert(user.to_string(), bundle);
+        }
+
+        // Route `from`'s outgoing lines, returning what each recipient is sent
+        fn route(&mut self, from: &str, lines: Vec<String>) -> Vec<(String, String)> {
+            lines
+                .into_iter()
+                .map(|line| {
+                    if let Some(user) = line.strip_prefix("/bundle ") {
+                        let bundle = self.bundles.get_mut(user).unwrap().take_one();
+                        (
+                            from.to_string(),
+                            format!("/bundle {} {}", user, bundle.to_hex()),
+                        )
+                    } else {
+                        let (to, message) =
+                            line.strip_prefix("/dm ").unwrap().split_once(' ').unwrap();
+                        // all the server gets to see is ciphertext
+                        let bytes = hex::decode(message).unwrap();
+                        assert!(!bytes.windows(6).any(|w| w == b"secret"));
+                        (to.to_string(), format!("/dm {} {}", from, message))
+                    }
+                })
+                .collect()
+        }
+    }
+
+    // Deliver lines until nobody has anything left to send, collecting what each user saw
+    fn run(
+        relay: &mut Relay,
+        clients: &mut HashMap<&str, DirectMessages>,
+        mut queue: Vec<(String, String)>,
+    ) -> Vec<(String, String)> {
+        let mut shown = Vec::new();
+        while !queue.is_empty() {
+            let (to, line) = queue.remove(0);
+            let outcome = clients.get_mut(to.as_str()).unwrap().handle(&line).unwrap();
+            shown.extend(outcome.show.into_iter().map(|s| (to.clone(), s)));
+            queue.extend(relay.route(&to, outcome.send));
+        }
+        shown
+    }
+
+    fn users() -> (Relay, HashMap<&'static str, DirectMessages>) {
+        let mut relay = Relay {
+            bundles: HashMap::new(),
+        };
+        let mut clients = HashMap::new();
+        for user in ["alice", "bob"] {
+            let mut client = DirectMessages::new(UserIdentity::generate());
+            relay.publish(user, &mut client);
+            clients.insert(user, client);
+        }
+        (relay, clients)
+    }
+
+    #[test]
+    fn direct_messages_travel_end_to_end_through_the_relay() {
+        let (mut relay, mut clients) = users();
+        let alice = clients.get_mut("alice").unwrap();
+        let first = alice.compose("bob", "a secret");
+        // a second message before the bundle is back doesn't ask again
+        assert!(alice.compose("bob", "another secret").send.is_empty());
+
+        let queue = relay.route("alice", first.send);
+        let shown = run(&mut relay, &mut clients, queue);
+        assert!(shown.contains(&("bob".to_string(), "alice (direct): a secret".to_string())));
+        assert!(shown.contains(&(
+            "bob".to_string(),
+            "alice (direct): another secret".to_string()
+        )));
+
+        let reply = clients
+            .get_mut("bob")
+            .unwrap()
+            .compose("alice", "secret reply");
+        let queue = relay.route("bob", reply.send);
+        let shown = run(&mut relay, &mut clients, queue);
+        assert_eq!(
+            shown,
+            vec![(
+                "alice".to_string(),
+                "bob (direct): secret reply".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn users_who_start_sessions_at_once_still_converge() {
+        let (mut relay, mut clients) = users();
+        let mut queue = Vec::new();
+        for (from, to) in [("alice", "bob"), ("bob", "alice")] {
+            let outcome = clients.get_mut(from).unwrap().compose(to, "hello");
+            queue.extend(relay.route(from, outcome.send));
+        }
+        run(&mut relay, &mut clients, queue);
+
+        for round in 0..2 {
+            for (from, to) in [("alice", "bob"), ("bob", "alice")] {
+                let text = format!("round {}", round);
+                let outcome = clients.get_mut(from).unwrap().compose(to, &text);
+                let queue = relay.route(from, outcome.send);
+                let shown = run(&mut relay, &mut clients, queue);
+                assert_eq!(
+                    shown,
+                    vec![(to.to_string(), format!("{} (direct): {}", from, text))]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn replays_and_stray_bundles_cant_displace_the_session_in_use() {
+        let (mut relay, mut clients) = users();
+        // without a one-time prekey, the prekeys alone wouldn't stop a replay
+        let bundle = clients.get_mut("bob").unwrap().identity.prekey_bundle(0);
+        relay.bundles.insert("bob".to_string(), bundle);
+
+        let alice = clients.get_mut("alice").unwrap();
+        let outcome = alice.compose("bob", "first");
+        let (_, bundle) = relay.route("alice", outcome.send).remove(0);
+        let outcome = alice.handle(&bundle).unwrap();
+        let queue = relay.route("alice", outcome.send);
+        let (_, first) = queue[0].clone();
+        run(&mut relay, &mut clients, queue);
+
+        let bob = clients.get_mut("bob").unwrap();
+        let started = bob.sessions["alice"].len();
+        for _ in 0..MAX_SESSIONS_PER_USER {
+            let replay = bob.handle(&first);
+            assert_eq!(
+                replay.unwrap().show,
+                ["Ignored a replayed direct message from alice"]
+            );
+            // anyone's bundle will do, as long as we never asked for it
+            let stray = bob.publish().replace("/prekeys ", "/bundle alice ");
+            let stray = bob.handle(&stray);
+            assert!(stray.unwrap().show[0].starts_with("Ignored a prekey bundle"));
+        }
+        assert_eq!(bob.sessions["alice"].len(), started);
+
+        for (from, to) in [("bob", "alice"), ("alice", "bob")] {
+            let outcome = clients.get_mut(from).unwrap().compose(to, "still here");
+            let queue = relay.route(from, outcome.send);
+            let shown = run(&mut relay, &mut clients, queue);
+            assert_eq!(
+                shown,
+                vec![(to.to_string(), format!("{} (direct): still here", from))]
+            );
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crypto_utils::ServerPublicKey;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+// File name used under $HOME when no path is given on the command line
+pub const DEFAULT_FILE: &str = ".chat_known_hosts";
+
+/** Trust-on-first-use record of server identity keys, one `host:port key-hex` line per server.
+    The first key a server presents is remembered; a different key later is refused,
+    since it means either the server was re-keyed or someone is in the middle.
+*/
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: HashMap<String, ServerPublicKey>,
+}
+
+impl KnownHosts {
+    // A missing file is just an empty store; it gets created on the first new host
+    pub fn load(path: &Path) -> io::Result<KnownHosts> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut hosts = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // A line we can't read might be the entry for the host we're about to
+            // connect to, so refuse outright rather than silently re-trusting it
+            let entry = line
+                .split_once(char::is_whitespace)
+                .and_then(|(host, key)| Some((host, ServerPublicKey::from_hex(key).ok()?)));
+            match entry {
+                Some((host, key)) => hosts.insert(host.to_string(), key),
+                None => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "{}:{}: malformed known hosts entry",
+                            path.display(),
+                            number + 1
+                        ),
+                    ))
+                }
+            };
+        }
+
+        Ok(KnownHosts {
+            path: path.to_path_buf(),
+            hosts,
+        })
+    }
+
+    /** Accept `key` for `host` if it's the key we saw before, or if we've never seen `host`
+        (in which case it's recorded). Refuse with a loud warning if the key has changed.
+    */
+    pub fn check(&mut self, host: &str, key: &ServerPublicKey) -> io::Result<()> {
+        match self.hosts.get(host) {
+            Some(known) if known == key => Ok(()),
+            Some(known) => {
+                eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                eprintln!("@    WARNING: SERVER IDENTIFICATION HAS CHANGED!          @");
+                eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                eprintln!(
+                    "Someone could be eavesdropping on you right now (man-in-the-middle attack)!"
+                );
+                eprintln!("It is also possible that the server's identity key was replaced.");
+                eprintln!("Expected fingerprint: {}", known.fingerprint());
+                eprintln!("Received fingerprint: {}", key.fingerprint());
+                eprintln!(
+                    "If the change is expected, remove the line for {} from {}.",
+                    host,
+                    self.path.display()
+                );
+                Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("identity key for {} has changed", host),
+                ))
+            }
+            None => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                writeln!(file, "{} {}", host, key.to_hex())?;
+                self.hosts.insert(host.to_string(), *key);
+                eprintln!(
+                    "Permanently added {} ({}) to the list of known hosts.",
+                    host,
+                    key.fingerprint()
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto_utils::ServerIdentity;
+
+    #[test]
+    fn trusts_on_first_use_and_refuses_changed_keys() {
+        let path = std::env::temp_dir().join(format!("known_hosts_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let server = ServerIdentity::generate().public_key();
+        let impostor = ServerIdentity::generate().public_key();
+
+        let mut known_hosts = KnownHosts::load(&path).unwrap();
+        known_hosts.check("chat.internal:4040", &server).unwrap();
+        known_hosts.check("chat.internal:4040", &server).unwrap();
+
+        // the first key must survive a restart of the client
+        let mut known_hosts = KnownHosts::load(&path).unwrap();
+        known_hosts.check("chat.internal:4040", &server).unwrap();
+        let err = known_hosts
+            .check("chat.internal:4040", &impostor)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        known_hosts.check("other.internal:4040", &impostor).unwrap();
+
+        fs::remove_file(&path).unwrap();
+    }
+}
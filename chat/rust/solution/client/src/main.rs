@@ -1,113 +1,166 @@
-use crypto_utils::{Crypto, PrimeDiffieHellman};
+mod direct;
+mod known_hosts;
+
+use crypto_utils::{
+    Crypto, NoiseStaticKey, NoiseXX, PreSharedKey, PythonCompatDiffieHellman, ServerPublicKey,
+    UserIdentity,
+};
+use direct::{DirectMessages, Outcome};
+use encstream::{Credentials, EncryptedStream, KeyExchange, Negotiated};
+use known_hosts::KnownHosts;
 use std::env;
-use std::io::Write;
-use std::io::{self, *};
+use std::io;
 use std::net::TcpStream;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-pub struct ChatServer {
-    socket: TcpStream,
-    crypto: crypto_utils::PrimeDiffieHellman,
+// What the server says once we have a name; only then will it take our prekeys
+const USERNAME_GRANTED: &str = "Username granted!";
+
+// Our long-term keys, under $HOME unless --keys names another directory:
+// the Noise static key the server knows us by, and the identity behind our direct messages
+const NOISE_KEY_FILE: &str = ".chat_noise_key";
+const IDENTITY_FILE: &str = ".chat_identity";
+
+pub struct ChatServer<C = Negotiated> {
+    stream: EncryptedStream<C>,
 }
 
-impl ChatServer {
-    pub fn new(address: &str) -> Self {
-        let socket = match TcpStream::connect(address) {
-            Ok(socket) => socket,
-            Err(e) => panic!("could not connect to server: {}", e),
-        };
-        let crypto = PrimeDiffieHellman::new();
-        ChatServer { socket, crypto }
+impl<C: KeyExchange> ChatServer<C> {
+    /** Connect, agree on a cipher suite with the server and complete its handshake.
+        The server first announces its identity key, which known_hosts must accept for `address`,
+        then sends its DH key signed by that identity; otherwise we hang up before sending anything.
+        The AES key itself is derived from the shared secret (and psk, if given) inside crypto_utils.
+        With credentials we log in to (or register) an account and the server grants its name.
+        Over Noise_XX we authenticate with static_key, so the server sees the same key every time.
+    */
+    pub fn dh_handshake(
+        address: &str,
+        static_key: &NoiseStaticKey,
+        psk: Option<&PreSharedKey>,
+        credentials: Option<&Credentials>,
+        known_hosts: &mut KnownHosts,
+    ) -> io::Result<Self> {
+        let socket = TcpStream::connect(address)?;
+        let check_identity = |server_key: &ServerPublicKey| known_hosts.check(address, server_key);
+        let stream = EncryptedStream::dh_handshake_client_static(
+            socket,
+            static_key,
+            psk,
+            credentials,
+            check_identity,
+        )?;
+        Ok(ChatServer { stream })
     }
+}
 
+impl<C: Crypto + Clone + Default> ChatServer<C> {
     /* Encrypt and send a message to the server */
     pub fn send(&mut self, msg: &str) -> io::Result<()> {
-        let msg_bytes: Vec<u8> = msg.trim().as_bytes().to_vec();
-        let encrypted_msg = self.crypto.encrypt(&msg_bytes);
-        self.socket.write(&encrypted_msg)?;
-        println!("Sent: {}", &msg);
+        self.stream.send(msg)?;
+        println!("Sent: {}", msg.trim());
         Ok(())
     }
 
-    /* Receive a message from the server and decrypt */
+    /* Receive a message from the server and decrypt, skipping forged and replayed records */
 
     pub fn receive(&mut self) -> io::Result<Option<String>> {
-        let raw = self.receive_raw()?;
-        if raw.is_empty() {
-            return Ok(None);
-        }
-        let message = self.crypto.decrypt(&raw);
-        let txt = std::str::from_utf8(&message).ok().map(String::from);
-        Ok(txt)
-    }
-
-    fn receive_raw(&mut self) -> io::Result<Vec<u8>> {
-        let mut data = vec![0_u8; 1024];
-        let bytes_read = self.socket.read(&mut data)?;
-        Ok(data[..bytes_read].to_vec())
+        self.stream.recv()
     }
 
     fn try_clone(&self) -> io::Result<Self> {
         Ok(Self {
-            socket: self.socket.try_clone()?,
-            crypto: self.crypto.clone(),
+            stream: self.stream.try_clone()?,
         })
     }
+}
 
-    /** Complete the Diffie-Hellman handshake
-        Return the key as bytes.
-        Output must be 16 bytes long. (for a 128-bit AES key)
-    */
-    pub fn dh_handshake(&mut self) -> io::Result<()> {
-        let pub_key_bytes = {
-            let mut data = [0_u8; 16]; // using 16 byte buffer
-            self.socket.read(&mut data)?;
-            data
-        };
-
-        let pubkey = self.crypto.init_keys();
-        self.socket.write_all(&pubkey.to_vec())?;
-
-        self.crypto.handshake(&pub_key_bytes);
-        println!("Handshake complete!");
-        Ok(())
+impl ChatServer<PythonCompatDiffieHellman> {
+    // Connect to a chat/py server, which has no identity or framing to speak of
+    pub fn python_compat_handshake(address: &str) -> io::Result<Self> {
+        let socket = TcpStream::connect(address)?;
+        let stream = EncryptedStream::python_compat_handshake_client(socket)?;
+        Ok(ChatServer { stream })
     }
 }
 
-/* Spawn two threads for input from either stdin or the server */
+/* Spawn two threads for input from either stdin or the server.
+Both may need to send, but only through one stream: clones would reuse sequence numbers. */
 
-fn accept_input(chat: ChatServer) -> io::Result<()> {
-    let stream_clone1 = chat.try_clone()?;
-    let stream_clone2 = chat.try_clone()?;
-    thread::spawn(move || handle_stream_server(stream_clone1));
-    thread::spawn(move || handle_stream_stdin(stream_clone2));
+fn accept_input<C: Crypto + Clone + Default + Send + 'static>(
+    chat: ChatServer<C>,
+    identity: UserIdentity,
+) -> io::Result<()> {
+    let receiver = chat.try_clone()?;
+    let sender = Arc::new(Mutex::new(chat));
+    let direct = Arc::new(Mutex::new(DirectMessages::new(identity)));
+    println!(
+        "Your end-to-end fingerprint: {}",
+        direct.lock().unwrap().fingerprint()
+    );
+    let (sender_clone, direct_clone) = (sender.clone(), direct.clone());
+    thread::spawn(move || handle_stream_server(receiver, sender_clone, direct_clone));
+    thread::spawn(move || handle_stream_stdin(sender, direct));
 
     Ok(())
 }
 
-fn handle_stream_server(mut chat: ChatServer) {
+fn handle_stream_server<C: Crypto + Clone + Default>(
+    mut chat: ChatServer<C>,
+    sender: Arc<Mutex<ChatServer<C>>>,
+    direct: Arc<Mutex<DirectMessages>>,
+) {
     loop {
         match chat.receive() {
             Ok(Some(txt)) => {
-                println!("received: {}", txt);
+                let outcome = direct.lock().unwrap().handle(&txt);
+                match outcome {
+                    Some(outcome) => deliver(&sender, outcome),
+                    None => println!("received: {}", txt),
+                }
+                if txt == USERNAME_GRANTED {
+                    let prekeys = direct.lock().unwrap().publish();
+                    deliver(
+                        &sender,
+                        Outcome {
+                            send: vec![prekeys],
+                            show: Vec::new(),
+                        },
+                    );
+                }
             }
             Ok(None) => {
                 println!("disconnected\n");
                 break;
             }
-            _ => {}
+            Err(e) => {
+                eprintln!("Error receiving message from server: {:?}", e);
+                break;
+            }
         };
     }
 }
 
-fn handle_stream_stdin(mut chat: ChatServer) -> io::Result<()> {
+fn handle_stream_stdin<C: Crypto + Clone + Default>(
+    sender: Arc<Mutex<ChatServer<C>>>,
+    direct: Arc<Mutex<DirectMessages>>,
+) -> io::Result<()> {
     loop {
         let mut line = String::new();
         match std::io::stdin().read_line(&mut line).unwrap() {
             0 => continue,
             _ => {
-                if let Err(e) = chat.send(&line) {
+                // `/msg <user> <text>` never reaches the server in the clear
+                if let Some((user, text)) = line
+                    .trim()
+                    .strip_prefix("/msg ")
+                    .and_then(|rest| rest.split_once(' '))
+                {
+                    let outcome = direct.lock().unwrap().compose(user, text);
+                    deliver(&sender, outcome);
+                } else if let Err(e) = sender.lock().unwrap().send(&line) {
                     eprintln!("Error sending message to server: {:?}", e);
                 }
             }
@@ -115,18 +168,179 @@ fn handle_stream_stdin(mut chat: ChatServer) -> io::Result<()> {
     }
 }
 
+// Send the direct-message protocol's lines without echoing their ciphertext, and show the rest
+fn deliver<C: Crypto + Clone + Default>(sender: &Mutex<ChatServer<C>>, outcome: Outcome) {
+    for line in outcome.send {
+        if let Err(e) = sender.lock().unwrap().stream.send(&line) {
+            eprintln!("Error sending message to server: {:?}", e);
+        }
+    }
+    for line in outcome.show {
+        println!("{}", line);
+    }
+}
+
 fn main() {
-    let (_, recv): (_, Receiver<Vec<u8>>) = channel();
-    let args: Vec<String> = env::args().collect();
-    let ip = &args[1];
-    let port = &args[2];
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // Speak chat/py's unauthenticated protocol, to connect to Python servers
+    let python_compat = args.first().map(String::as_str) == Some("--python-compat");
+    if python_compat {
+        args.remove(0);
+    }
+    // Only offer the Noise handshake
+    let noise = args.first().map(String::as_str) == Some("--noise");
+    if noise {
+        args.remove(0);
+    }
+    // Private servers only let in clients that know their pre-shared key
+    let psk = if args.first().map(String::as_str) == Some("--psk") && args.len() >= 2 {
+        let path = args.drain(..2).nth(1).unwrap_or_default();
+        match PreSharedKey::load(Path::new(&path)) {
+            Ok(psk) => Some(psk),
+            Err(e) => {
+                eprintln!("Could not read pre-shared key from {}: {}", path, e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    // Keep our long-term keys somewhere other than $HOME
+    let key_dir = if args.first().map(String::as_str) == Some("--keys") && args.len() >= 2 {
+        Some(PathBuf::from(args.drain(..2).nth(1).unwrap_or_default()))
+    } else {
+        None
+    };
+    // Log in to a registered account, or register one, instead of picking a name as a guest
+    let account = match args.first().map(String::as_str) {
+        Some(flag @ ("--login" | "--register")) if args.len() >= 2 => {
+            let register = flag == "--register";
+            Some((register, args.drain(..2).nth(1).unwrap_or_default()))
+        }
+        _ => None,
+    };
+    let python_compat_misused = python_compat && (noise || psk.is_some() || account.is_some());
+    if python_compat_misused || (args.len() != 2 && args.len() != 3) {
+        eprintln!(
+            "Usage: client [--python-compat|--noise] [--psk <file>] [--keys <dir>] \
+             [--login|--register <user>] <ip> <port> [known hosts file]"
+        );
+        return;
+    }
+    // The password is the first line of stdin, read before the chat takes stdin over
+    let credentials = match account {
+        Some((register, username)) => {
+            eprint!("Password for {}: ", username);
+            let mut password = String::new();
+            if let Err(e) = io::stdin().read_line(&mut password) {
+                eprintln!("Could not read password: {}", e);
+                return;
+            }
+            let password = password.trim_end_matches(['\r', '\n']).to_string();
+            Some(if register {
+                Credentials::Register { username, password }
+            } else {
+                Credentials::Login { username, password }
+            })
+        }
+        None => None,
+    };
+    let ip = &args[0];
+    let port = &args[1];
     let address = format!("{}:{}", ip, port);
 
-    let mut chat = ChatServer::new(&address);
-    chat.dh_handshake();
+    // No identity, known hosts or keys of ours come into it
+    if python_compat {
+        println!("Running in Python compatibility mode: the connection is NOT secure");
+        // Python servers don't relay prekeys, so there's nothing to keep an identity for
+        match ChatServer::python_compat_handshake(&address) {
+            Ok(chat) => converse(chat, UserIdentity::generate()),
+            Err(e) => eprintln!("Handshake with server failed: {}", e),
+        }
+        return;
+    }
+
+    let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    let known_hosts_path = match args.get(2) {
+        Some(path) => PathBuf::from(path),
+        None => home.join(known_hosts::DEFAULT_FILE),
+    };
+    let mut known_hosts = match KnownHosts::load(&known_hosts_path) {
+        Ok(known_hosts) => known_hosts,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", known_hosts_path.display(), e);
+            return;
+        }
+    };
 
-    thread::spawn(move || accept_input(chat));
-    loop {
-        recv.recv();
+    let key_dir = key_dir.unwrap_or(home);
+    let noise_key_path = key_dir.join(NOISE_KEY_FILE);
+    let static_key = match NoiseStaticKey::load_or_generate(&noise_key_path) {
+        Ok(static_key) => static_key,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", noise_key_path.display(), e);
+            return;
+        }
+    };
+    let identity_path = key_dir.join(IDENTITY_FILE);
+    let identity = match UserIdentity::load_or_generate(&identity_path) {
+        Ok(identity) => identity,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", identity_path.display(), e);
+            return;
+        }
+    };
+
+    let credentials = credentials.as_ref();
+    if noise {
+        run::<NoiseXX>(
+            &address,
+            &static_key,
+            identity,
+            psk.as_ref(),
+            credentials,
+            &mut known_hosts,
+        );
+    } else {
+        run::<Negotiated>(
+            &address,
+            &static_key,
+            identity,
+            psk.as_ref(),
+            credentials,
+            &mut known_hosts,
+        );
     }
 }
+
+fn run<C: KeyExchange + Send + 'static>(
+    address: &str,
+    static_key: &NoiseStaticKey,
+    identity: UserIdentity,
+    psk: Option<&PreSharedKey>,
+    credentials: Option<&Credentials>,
+    known_hosts: &mut KnownHosts,
+) {
+    let handshake =
+        ChatServer::<C>::dh_handshake(address, static_key, psk, credentials, known_hosts);
+    let chat = match handshake {
+        Ok(chat) => chat,
+        Err(e) => {
+            eprintln!("Handshake with server failed: {}", e);
+            return;
+        }
+    };
+    println!("Cipher suite: {}", chat.stream.cipher_suite());
+    converse(chat, identity);
+}
+
+// Chat over an established connection, with one thread for the server and one for stdin
+fn converse<C: Crypto + Clone + Default + Send + 'static>(
+    chat: ChatServer<C>,
+    identity: UserIdentity,
+) {
+    let (_send, recv): (_, Receiver<Vec<u8>>) = channel();
+    thread::spawn(move || accept_input(chat, identity));
+    // Nothing is ever sent on this channel; it just parks the main thread while the workers run
+    let _ = recv.recv();
+}
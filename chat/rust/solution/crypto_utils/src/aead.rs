@@ -0,0 +1,27 @@
+use crate::rng::SessionRng;
+use crate::{backend, CryptoError, KeyBytes};
+
+// AES-GCM takes a 96-bit nonce and produces a 128-bit tag
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+// Output is nonce || ciphertext || tag, with a fresh random nonce per message
+pub fn seal(key: &KeyBytes, rng: &SessionRng, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut nonce = [0_u8; NONCE_LEN];
+    rng.with(|rng| rng.fill_bytes(&mut nonce));
+    let sealed = backend::gcm_seal(key, &nonce, &[], plaintext)?;
+
+    let mut output = Vec::with_capacity(NONCE_LEN + sealed.len());
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&sealed);
+    Ok(output)
+}
+
+// Inverse of seal()
+pub fn open(key: &KeyBytes, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if data.len() < NONCE_LEN + TAG_LEN {
+        return Err(CryptoError::Truncated);
+    }
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+    backend::gcm_open(key, nonce, &[], sealed)
+}
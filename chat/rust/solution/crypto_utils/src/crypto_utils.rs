@@ -1,116 +1,280 @@
+mod aead;
+mod error;
+mod groups;
+mod identity;
+mod noise;
+mod psk;
+mod python_compat;
+mod ratchet;
+mod rng;
+mod secret;
+mod srp;
+mod stream;
+mod x25519;
+mod x3dh;
+
+// Where AES comes from: OpenSSL by default, RustCrypto with the rustcrypto feature
+#[cfg(not(feature = "rustcrypto"))]
+#[path = "openssl_backend.rs"]
+mod backend;
+#[cfg(feature = "rustcrypto")]
+#[path = "rustcrypto_backend.rs"]
+mod backend;
+
+#[cfg(not(any(feature = "openssl", feature = "rustcrypto")))]
+compile_error!("crypto_utils needs a cipher backend: enable the openssl or rustcrypto feature");
+
+use hkdf::Hkdf;
+use num::bigint::RandBigInt;
 use num::BigUint;
-use openssl::symm::{Cipher, Crypter, Mode};
-use rand::Rng;
+use rng::SessionRng;
+use secret::SecretBigUint;
+use sha2::Sha256;
+use std::fmt;
+use zeroize::Zeroizing;
 
+pub use error::CryptoError;
+pub use groups::DhGroup;
+pub use identity::{ServerIdentity, ServerPublicKey};
+pub use noise::{NoiseHandshake, NoiseStaticKey, NoiseXX, NOISE_KEY_LEN, NOISE_PARAMS};
+pub use psk::PreSharedKey;
+pub use python_compat::PythonCompatDiffieHellman;
+pub use ratchet::RatchetSession;
+pub use rng::SecureRng;
+pub use srp::{SrpClient, SrpServer, SrpVerifier, SRP_DEFAULT_ITERATIONS, SRP_SALT_LEN};
+pub use stream::{StreamDecryptor, StreamEncryptor, STREAM_CHUNK_LEN, STREAM_SALT_LEN};
+pub use x25519::X25519DiffieHellman;
+pub use x3dh::{first_message_ephemeral, PrekeyBundle, UserIdentity, UserPublicKey};
+
+// Session key handed to the cipher, derived from the DH shared secret
 type KeyBytes = [u8; 16];
 
+// ECB ciphertexts are always a whole number of these
+const AES_BLOCK_LEN: usize = 16;
+
+// HKDF context string, so this key can't be confused with one derived for another purpose
+const SESSION_KEY_INFO: &[u8] = b"copilot-chat session key v1";
+
+// HKDF context string for stepping a session key forward on rekey()
+const KEY_UPDATE_INFO: &[u8] = b"copilot-chat key update v1";
+
+// HKDF context string for folding a pre-shared key into the session key
+const PSK_INFO: &[u8] = b"copilot-chat psk v1";
+
+// HKDF context string for the per-stream keys of StreamEncryptor and StreamDecryptor
+const STREAM_INFO: &[u8] = b"copilot-chat stream v1";
+
 pub trait Crypto {
-    fn init_keys(&mut self) -> KeyBytes;
-    fn handshake(&mut self, other_pub_key: &KeyBytes);
-    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>;
-    fn decrypt(&self, ciphertext: &[u8]) -> Vec<u8>;
-    fn serialize(&self, pub_key: &BigUint) -> KeyBytes;
-    fn deserialize(&self, pub_key: &KeyBytes) -> BigUint;
+    // In-memory form of a public key; what goes over the wire is serialize()'s output
+    type PublicKey;
+
+    fn init_keys(&mut self) -> Vec<u8>;
+    fn handshake(&mut self, other_pub_key: &[u8]) -> Result<(), CryptoError>;
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError>;
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError>;
+    // Replace the session key with one derived from it; both ends must rekey at the same point
+    fn rekey(&mut self) -> Result<(), CryptoError>;
+    // Fold a pre-shared key into the session key agreed by handshake()
+    fn mix_psk(&mut self, psk: &PreSharedKey) -> Result<(), CryptoError>;
+    // Key for one StreamEncryptor/StreamDecryptor, derived one-way from the session key
+    fn stream_key(&self, salt: &[u8], context: &[u8]) -> Result<Zeroizing<KeyBytes>, CryptoError>;
+    // Fill buf from the session's RNG, such as a stream's salt, so seeded sessions replay exactly
+    fn fill_random(&self, buf: &mut [u8]);
+    fn serialize(&self, pub_key: &Self::PublicKey) -> Vec<u8>;
+    fn deserialize(&self, pub_key: &[u8]) -> Result<Self::PublicKey, CryptoError>;
+}
+
+// Run a raw shared secret through HKDF-SHA256 to get a uniformly random AES key
+fn derive_key(shared_secret: &[u8]) -> Zeroizing<KeyBytes> {
+    let mut key = Zeroizing::new([0_u8; 16]);
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(SESSION_KEY_INFO, key.as_mut())
+        .expect("16 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+// One-way step from the current session key to the next, so old traffic stays safe if a later key leaks
+fn next_key<const N: usize>(key: &[u8; N]) -> Zeroizing<[u8; N]> {
+    let mut next = Zeroizing::new([0_u8; N]);
+    Hkdf::<Sha256>::new(None, key)
+        .expand(KEY_UPDATE_INFO, next.as_mut())
+        .expect("session keys are a valid HKDF-SHA256 output length");
+    next
+}
+
+// The DH secret is the input key material and the PSK the salt, so the result is only
+// predictable to someone who knows both
+fn psk_key<const N: usize>(key: &[u8; N], psk: &PreSharedKey) -> Zeroizing<[u8; N]> {
+    let mut mixed = Zeroizing::new([0_u8; N]);
+    Hkdf::<Sha256>::new(Some(psk.as_bytes()), key)
+        .expand(PSK_INFO, mixed.as_mut())
+        .expect("session keys are a valid HKDF-SHA256 output length");
+    mixed
+}
+
+// Each stream's salt and context pick a fresh key, so chunk nonces can simply count from zero
+fn stream_key(key: &[u8], salt: &[u8], context: &[u8]) -> Zeroizing<KeyBytes> {
+    let mut stream_key = Zeroizing::new([0_u8; 16]);
+    Hkdf::<Sha256>::new(Some(salt), key)
+        .expand_multi_info(&[STREAM_INFO, context], stream_key.as_mut())
+        .expect("16 bytes is a valid HKDF-SHA256 output length");
+    stream_key
 }
 
 impl Crypto for PrimeDiffieHellman {
+    type PublicKey = BigUint;
+
     /** Facilitate a cryptographic handshake between two parties.
         This will generate the private key and the public key.
         The private key is initialized inside the class and
         you are given the public key, in a format you can
         readily send to the other party.
     */
-    fn init_keys(&mut self) -> KeyBytes {
-        self.priv_key = self.gen_priv_key();
+    fn init_keys(&mut self) -> Vec<u8> {
+        self.priv_key = SecretBigUint::new(self.gen_priv_key());
         let pub_key = self.gen_pub_key(&self.priv_key);
         self.serialize(&pub_key)
     }
 
     // You must call handshake() with the public key the other party gives you.
-    fn handshake(&mut self, other_pub_key: &KeyBytes) {
-        let deserialized_key = self.deserialize(other_pub_key);
-        let shared_secret = self.compute_shared_secret(&self.priv_key, &deserialized_key);
-        let shared_key = self.pad_be(&shared_secret);
-        self.key = shared_key;
-    }
-
-    // Encrypts plaintext using the shared secret
-    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
-        let mut encryptvec: Vec<u8> = plaintext.to_vec();
-        encryptvec.push(encryptvec.len() as u8); // add data length
-        let mut ciphertext = vec![0; plaintext.len() + self.cipher.block_size()];
-        let mut crypter = Crypter::new(self.cipher, Mode::Encrypt, &self.key, None).unwrap();
-        crypter.pad(true);
-        let datalen = encryptvec.pop();
-        let count = crypter.update(&encryptvec, &mut ciphertext).unwrap();
-        let rest = crypter.finalize(&mut ciphertext[count..]).unwrap();
-
-        ciphertext.truncate(count + rest);
-        ciphertext.push(datalen.unwrap());
-        ciphertext
-    }
-
-    // Decrypt a message using the shared secret
-    fn decrypt(&self, data: &[u8]) -> Vec<u8> {
-        let mut decrypted = Crypter::new(self.cipher, Mode::Decrypt, &self.key, None).unwrap();
-        let mut output = vec![0_u8; data.len() + self.cipher.block_size()];
-        let datalen = data.to_vec().pop();
-        decrypted.update(data, &mut output).unwrap();
-        output.truncate(datalen.unwrap() as usize);
-        output
+    // Keys that would force a predictable shared secret are rejected.
+    fn handshake(&mut self, other_pub_key: &[u8]) -> Result<(), CryptoError> {
+        let deserialized_key = self.deserialize(other_pub_key)?;
+        self.validate_pub_key(&deserialized_key)?;
+        let shared_secret =
+            SecretBigUint::new(self.compute_shared_secret(&self.priv_key, &deserialized_key));
+        self.key = derive_key(&Zeroizing::new(self.pad_be(&shared_secret)));
+        Ok(())
+    }
+
+    // Encrypts plaintext using the shared secret.
+    // The cipher applies PKCS#7 padding, so messages of any length round-trip.
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        backend::ecb_encrypt(self.key.as_slice(), plaintext)
+    }
+
+    // Decrypt a message using the shared secret, stripping the PKCS#7 padding.
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if data.is_empty() || !data.len().is_multiple_of(AES_BLOCK_LEN) {
+            return Err(CryptoError::Truncated);
+        }
+        backend::ecb_decrypt(self.key.as_slice(), data)
+    }
+
+    fn rekey(&mut self) -> Result<(), CryptoError> {
+        self.key = next_key(&self.key);
+        Ok(())
+    }
+
+    fn mix_psk(&mut self, psk: &PreSharedKey) -> Result<(), CryptoError> {
+        self.key = psk_key(&self.key, psk);
+        Ok(())
+    }
+
+    // Streams are AES-GCM even on this ECB suite: every chunk needs its own tag
+    fn stream_key(&self, salt: &[u8], context: &[u8]) -> Result<Zeroizing<KeyBytes>, CryptoError> {
+        Ok(stream_key(self.key.as_slice(), salt, context))
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        self.rng.with(|rng| rng.fill_bytes(buf))
     }
 
     // Input: a public key to be sent to the other party
-    // Output: a string representing the public key, in bytes
-    fn serialize(&self, pub_key: &BigUint) -> KeyBytes {
+    // Output: the key as big-endian bytes, padded to the width of the group's prime
+    fn serialize(&self, pub_key: &BigUint) -> Vec<u8> {
         self.pad_be(pub_key)
     }
 
-    // Input: bytes representing a public key as a string.
+    // Input: big-endian bytes representing a public key, exactly as wide as the prime.
     // Output: a public key int
-    fn deserialize(&self, pub_key: &KeyBytes) -> BigUint {
-        BigUint::from_bytes_be(pub_key)
+    fn deserialize(&self, pub_key: &[u8]) -> Result<BigUint, CryptoError> {
+        if pub_key.len() != self.group.byte_len() {
+            return Err(CryptoError::BadKey);
+        }
+        Ok(BigUint::from_bytes_be(pub_key))
     }
 }
 
+// Secrets are wiped on drop, including in every clone, and never printed
 #[derive(Clone)]
 pub struct PrimeDiffieHellman {
-    p: usize,
-    g: usize,
-    cipher: Cipher,
-    key: KeyBytes,
-    priv_key: BigUint,
+    group: DhGroup,
+    p: BigUint,
+    g: BigUint,
+    key: Zeroizing<KeyBytes>,
+    priv_key: SecretBigUint,
+    rng: SessionRng,
 }
 
 impl PrimeDiffieHellman {
     pub fn new() -> PrimeDiffieHellman {
+        Self::with_group(DhGroup::default())
+    }
+
+    pub fn with_group(group: DhGroup) -> PrimeDiffieHellman {
+        Self::with_session_rng(group, SessionRng::default())
+    }
+
+    // Draw private keys from rng instead of the thread-local generator, e.g. to replay a handshake
+    pub fn with_rng<R: SecureRng + Send + 'static>(group: DhGroup, rng: R) -> PrimeDiffieHellman {
+        Self::with_session_rng(group, SessionRng::new(rng))
+    }
+
+    fn with_session_rng(group: DhGroup, rng: SessionRng) -> PrimeDiffieHellman {
         PrimeDiffieHellman {
-            cipher: Cipher::aes_128_ecb(),
-            key: [0_u8; 16],
-            p: 997,
-            g: 2,
-            priv_key: BigUint::from(0u8),
+            group,
+            key: Zeroizing::new([0_u8; 16]),
+            p: group.prime(),
+            g: group.generator(),
+            priv_key: SecretBigUint::default(),
+            rng,
         }
     }
 
+    pub fn group(&self) -> DhGroup {
+        self.group
+    }
+
     fn gen_priv_key(&self) -> BigUint {
-        let mut rng = rand::thread_rng();
-        let priv_key = rng.gen_range(1..(self.p - 1));
-        BigUint::from(priv_key)
+        self.rng.with(|rng| loop {
+            let priv_key = rng.gen_biguint(self.group.exponent_bits());
+            if priv_key > BigUint::from(1u8) {
+                return priv_key;
+            }
+        })
     }
 
     fn gen_pub_key(&self, priv_key: &BigUint) -> BigUint {
-        BigUint::from(self.g).modpow(priv_key, &self.p.into())
+        self.g.modpow(priv_key, &self.p)
     }
 
     fn compute_shared_secret(&self, priv_key: &BigUint, other_pub_key: &BigUint) -> BigUint {
-        other_pub_key.modpow(priv_key, &BigUint::from(self.p))
+        other_pub_key.modpow(priv_key, &self.p)
     }
 
-    fn pad_be(&self, key: &BigUint) -> KeyBytes {
-        let mut buffer = [0u8; 16];
-        let key_bytes = key.to_bytes_be();
-        buffer[16 - key_bytes.len()..].copy_from_slice(&key_bytes);
+    /** Reject public keys outside [2, p - 2] (0, 1 and p - 1 give a fixed shared secret)
+        and keys outside the order-q subgroup generated by g. All our groups use safe primes
+        p = 2q + 1, so y is in that subgroup exactly when y^q = 1 mod p.
+    */
+    fn validate_pub_key(&self, pub_key: &BigUint) -> Result<(), CryptoError> {
+        let one = BigUint::from(1u8);
+        let p_minus_one = &self.p - 1u8;
+        if *pub_key <= one || *pub_key >= p_minus_one {
+            return Err(CryptoError::InvalidPublicKey);
+        }
+        let q = p_minus_one >> 1;
+        if pub_key.modpow(&q, &self.p) != one {
+            return Err(CryptoError::InvalidPublicKey);
+        }
+        Ok(())
+    }
+
+    fn pad_be(&self, key: &BigUint) -> Vec<u8> {
+        let width = self.group.byte_len();
+        let mut buffer = vec![0u8; width];
+        let key_bytes = Zeroizing::new(key.to_bytes_be());
+        buffer[width - key_bytes.len()..].copy_from_slice(&key_bytes);
         buffer
     }
 }
@@ -120,3 +284,617 @@ impl Default for PrimeDiffieHellman {
         Self::new()
     }
 }
+
+impl fmt::Debug for PrimeDiffieHellman {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrimeDiffieHellman")
+            .field("group", &self.group)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Crypto for AeadDiffieHellman {
+    type PublicKey = BigUint;
+
+    fn init_keys(&mut self) -> Vec<u8> {
+        self.dh.init_keys()
+    }
+
+    fn handshake(&mut self, other_pub_key: &[u8]) -> Result<(), CryptoError> {
+        self.dh.handshake(other_pub_key)
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        aead::seal(&self.dh.key, &self.dh.rng, plaintext)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        aead::open(&self.dh.key, data)
+    }
+
+    fn rekey(&mut self) -> Result<(), CryptoError> {
+        self.dh.rekey()
+    }
+
+    fn mix_psk(&mut self, psk: &PreSharedKey) -> Result<(), CryptoError> {
+        self.dh.mix_psk(psk)
+    }
+
+    fn stream_key(&self, salt: &[u8], context: &[u8]) -> Result<Zeroizing<KeyBytes>, CryptoError> {
+        self.dh.stream_key(salt, context)
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        self.dh.fill_random(buf)
+    }
+
+    fn serialize(&self, pub_key: &BigUint) -> Vec<u8> {
+        self.dh.serialize(pub_key)
+    }
+
+    fn deserialize(&self, pub_key: &[u8]) -> Result<BigUint, CryptoError> {
+        self.dh.deserialize(pub_key)
+    }
+}
+
+/** Diffie-Hellman key agreement feeding AES-128-GCM.
+    Every message gets its own nonce and an authentication tag,
+    so repeated plaintexts look different on the wire and
+    tampered ciphertexts are rejected rather than decrypted.
+*/
+#[derive(Clone)]
+pub struct AeadDiffieHellman {
+    dh: PrimeDiffieHellman,
+}
+
+impl AeadDiffieHellman {
+    pub fn new() -> AeadDiffieHellman {
+        Self::with_group(DhGroup::default())
+    }
+
+    pub fn with_group(group: DhGroup) -> AeadDiffieHellman {
+        AeadDiffieHellman {
+            dh: PrimeDiffieHellman::with_group(group),
+        }
+    }
+
+    // Private keys and nonces both come from rng
+    pub fn with_rng<R: SecureRng + Send + 'static>(group: DhGroup, rng: R) -> AeadDiffieHellman {
+        AeadDiffieHellman {
+            dh: PrimeDiffieHellman::with_rng(group, rng),
+        }
+    }
+
+    pub fn group(&self) -> DhGroup {
+        self.dh.group
+    }
+}
+
+impl Default for AeadDiffieHellman {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for AeadDiffieHellman {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AeadDiffieHellman")
+            .field("group", &self.dh.group)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{RngCore, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+    use serde_json::Value;
+    use sha2::Digest;
+    use zeroize::Zeroize;
+
+    fn session_pair<C: Crypto + Default>() -> (C, C) {
+        let mut alice = C::default();
+        let mut bob = C::default();
+        let alice_pub = alice.init_keys();
+        let bob_pub = bob.init_keys();
+        alice.handshake(&bob_pub).unwrap();
+        bob.handshake(&alice_pub).unwrap();
+        (alice, bob)
+    }
+
+    #[test]
+    fn round_trips_messages_of_any_length() {
+        let (alice, bob) = session_pair::<PrimeDiffieHellman>();
+        for len in [0, 15, 16, 255, 256, 1024 * 1024] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let ciphertext = alice.encrypt(&plaintext).unwrap();
+            assert_eq!(ciphertext.len() % 16, 0, "length {}", len);
+            assert!(ciphertext.len() > plaintext.len(), "length {}", len);
+            assert_eq!(bob.decrypt(&ciphertext), Ok(plaintext), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn malformed_ecb_ciphertexts_are_errors() {
+        let (alice, bob) = session_pair::<PrimeDiffieHellman>();
+        let ciphertext = alice.encrypt(b"hello").unwrap();
+        assert_eq!(bob.decrypt(&[]), Err(CryptoError::Truncated));
+        assert_eq!(bob.decrypt(&ciphertext[..15]), Err(CryptoError::Truncated));
+
+        // a final block that decrypts to garbage almost never carries valid padding
+        let mut forged = ciphertext.clone();
+        forged[15] ^= 0xff;
+        assert_ne!(bob.decrypt(&forged), Ok(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn every_group_agrees_on_a_key() {
+        for group in [
+            DhGroup::Modp2048,
+            DhGroup::Modp3072,
+            DhGroup::Modp4096,
+            DhGroup::Ffdhe2048,
+            DhGroup::Ffdhe3072,
+            DhGroup::Ffdhe4096,
+        ] {
+            assert_eq!(group.prime().bits() as usize, group.byte_len() * 8);
+            let mut alice = PrimeDiffieHellman::with_group(group);
+            let mut bob = PrimeDiffieHellman::with_group(group);
+            let alice_pub = alice.init_keys();
+            let bob_pub = bob.init_keys();
+            assert_eq!(alice_pub.len(), group.byte_len());
+            alice.handshake(&bob_pub).unwrap();
+            bob.handshake(&alice_pub).unwrap();
+            assert_eq!(alice.key, bob.key, "{:?}", group);
+            assert_ne!(*alice.key, [0_u8; 16], "{:?}", group);
+        }
+    }
+
+    #[test]
+    fn degenerate_dh_public_keys_are_rejected() {
+        let mut alice = PrimeDiffieHellman::new();
+        alice.init_keys();
+        let p = alice.group().prime();
+        let bad_keys = [
+            BigUint::from(0u8),
+            BigUint::from(1u8),
+            &p - 1u8,
+            p.clone(),
+            &p + 1u8,
+            // a quadratic non-residue, outside the prime-order subgroup
+            &p - 2u8,
+        ];
+        for bad_key in bad_keys {
+            assert_eq!(
+                alice.handshake(&alice.serialize(&bad_key)),
+                Err(CryptoError::InvalidPublicKey),
+                "{:x}",
+                bad_key
+            );
+        }
+    }
+
+    #[test]
+    fn aead_round_trips_and_uses_fresh_nonces() {
+        let (alice, bob) = session_pair::<AeadDiffieHellman>();
+        for len in [0, 15, 16, 255, 256, 1024 * 1024] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            assert_eq!(
+                bob.decrypt(&alice.encrypt(&plaintext).unwrap()),
+                Ok(plaintext)
+            );
+        }
+        assert_ne!(alice.encrypt(b"hello"), alice.encrypt(b"hello"));
+    }
+
+    #[test]
+    fn rekeying_both_ends_keeps_them_in_step() {
+        let (mut alice, mut bob) = session_pair::<AeadDiffieHellman>();
+        let before = alice.encrypt(b"before").unwrap();
+        alice.rekey().unwrap();
+        assert_eq!(
+            bob.decrypt(&alice.encrypt(b"early").unwrap()),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        bob.rekey().unwrap();
+        assert_eq!(
+            bob.decrypt(&alice.encrypt(b"after").unwrap()),
+            Ok(b"after".to_vec())
+        );
+        assert_eq!(bob.decrypt(&before), Err(CryptoError::AuthenticationFailed));
+
+        let (mut alice, mut bob) = session_pair::<X25519DiffieHellman>();
+        alice.rekey().unwrap();
+        bob.rekey().unwrap();
+        assert_eq!(
+            bob.decrypt(&alice.encrypt(b"after").unwrap()),
+            Ok(b"after".to_vec())
+        );
+    }
+
+    #[test]
+    fn x25519_agrees_on_a_key_with_fixed_size_public_keys() {
+        let mut alice = X25519DiffieHellman::new();
+        let mut bob = X25519DiffieHellman::new();
+        let alice_pub = alice.init_keys();
+        let bob_pub = bob.init_keys();
+        assert_eq!(alice_pub.len(), 32);
+        assert_eq!(bob_pub.len(), 32);
+        alice.handshake(&bob_pub).unwrap();
+        bob.handshake(&alice_pub).unwrap();
+        for len in [0, 15, 16, 255, 256, 1024 * 1024] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            assert_eq!(
+                bob.decrypt(&alice.encrypt(&plaintext).unwrap()),
+                Ok(plaintext)
+            );
+        }
+        assert_eq!(
+            bob.handshake(&alice_pub[..31]),
+            Err(CryptoError::BadKey),
+            "short public key"
+        );
+        assert_eq!(
+            bob.handshake(&[0_u8; 32]),
+            Err(CryptoError::InvalidPublicKey),
+            "low-order point"
+        );
+    }
+
+    #[test]
+    fn noise_xx_gives_each_direction_its_own_key() {
+        let client_key = NoiseStaticKey::generate();
+        let server_key = ServerIdentity::generate().noise_static_key();
+        let mut client = NoiseHandshake::initiator(&client_key).unwrap();
+        let mut server = NoiseHandshake::responder(&server_key).unwrap();
+        let msg1 = client.write_message(&[]).unwrap();
+        server.read_message(&msg1).unwrap();
+        let msg2 = server.write_message(b"identity").unwrap();
+        assert_eq!(client.read_message(&msg2), Ok(b"identity".to_vec()));
+        assert_eq!(client.remote_static(), Some(server.static_public_key()));
+        let msg3 = client.write_message(&[]).unwrap();
+        server.read_message(&msg3).unwrap();
+        assert_eq!(server.remote_static(), Some(client.static_public_key()));
+
+        let (client_send, client_recv) = client.finish().unwrap();
+        let (server_send, server_recv) = server.finish().unwrap();
+        // each side can now check the other's long-term key
+        assert_eq!(
+            client_recv.remote_static_key(),
+            Some(server_key.public_key())
+        );
+        assert_eq!(
+            server_send.remote_static_key(),
+            Some(client_key.public_key())
+        );
+        let to_server = client_send.encrypt(b"hello").unwrap();
+        assert_eq!(server_recv.decrypt(&to_server), Ok(b"hello".to_vec()));
+        let to_client = server_send.encrypt(b"hi").unwrap();
+        assert_eq!(client_recv.decrypt(&to_client), Ok(b"hi".to_vec()));
+        // a record can't be reflected back at its sender
+        assert_eq!(
+            client_recv.decrypt(&to_server),
+            Err(CryptoError::AuthenticationFailed)
+        );
+
+        // tampering with the handshake is caught before any keys exist
+        let mut client = NoiseHandshake::initiator(&client_key).unwrap();
+        let mut server = NoiseHandshake::responder(&server_key).unwrap();
+        server
+            .read_message(&client.write_message(&[]).unwrap())
+            .unwrap();
+        let mut msg2 = server.write_message(&[]).unwrap();
+        let last = msg2.len() - 1;
+        msg2[last] ^= 1;
+        assert_eq!(
+            client.read_message(&msg2),
+            Err(CryptoError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn noise_xx_draws_keys_and_nonces_from_the_session_rng() {
+        let server_key = ServerIdentity::generate().noise_static_key();
+        let mut client = NoiseXX::with_rng(ChaCha20Rng::seed_from_u64(5))
+            .initiator()
+            .unwrap();
+        let mut server = NoiseXX::default().responder(&server_key).unwrap();
+        let first = client.write_message(&[]).unwrap();
+        server.read_message(&first).unwrap();
+        client
+            .read_message(&server.write_message(&[]).unwrap())
+            .unwrap();
+        server
+            .read_message(&client.write_message(&[]).unwrap())
+            .unwrap();
+
+        // with no static key of its own, the client's comes first out of the stream
+        let mut expected = ChaCha20Rng::seed_from_u64(5);
+        let static_key = NoiseStaticKey::generate_with_rng(&mut expected);
+        assert_eq!(client.static_public_key(), static_key.public_key());
+        // then the ephemeral key, which is all the first handshake message holds
+        let ephemeral = x25519_dalek::StaticSecret::random_from_rng(&mut expected);
+        assert_eq!(first, x25519_dalek::PublicKey::from(&ephemeral).as_bytes());
+        let (client_send, _) = client.finish().unwrap();
+        let mut nonce = [0_u8; 12];
+        expected.fill_bytes(&mut nonce);
+        assert_eq!(client_send.encrypt(b"hello").unwrap()[..12], nonce);
+        let (mut salt, mut expected_salt) = ([0_u8; 4], [0_u8; 4]);
+        client_send.fill_random(&mut salt);
+        expected.fill_bytes(&mut expected_salt);
+        assert_eq!(salt, expected_salt);
+    }
+
+    #[test]
+    fn noise_static_keys_outlive_the_process() {
+        let path = std::env::temp_dir().join(format!("noise_key_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let key = NoiseStaticKey::load_or_generate(&path).unwrap();
+        let reloaded = NoiseStaticKey::load_or_generate(&path).unwrap();
+        assert_eq!(reloaded.public_key(), key.public_key());
+        std::fs::remove_file(&path).unwrap();
+
+        // the server's is tied to its identity seed
+        let identity =
+            |seed| ServerIdentity::generate_with_rng(&mut ChaCha20Rng::seed_from_u64(seed));
+        let server_key = identity(1).noise_static_key();
+        assert_eq!(
+            identity(1).noise_static_key().public_key(),
+            server_key.public_key()
+        );
+        assert_ne!(
+            identity(2).noise_static_key().public_key(),
+            server_key.public_key()
+        );
+    }
+
+    #[test]
+    fn secrets_are_wiped_and_never_printed() {
+        let (alice, _bob) = session_pair::<AeadDiffieHellman>();
+        let key_hex = hex::encode(alice.dh.key.as_slice());
+        let priv_hex = alice.dh.priv_key.to_str_radix(16);
+        let printed = format!("{:?}", alice);
+        assert!(!printed.contains(&key_hex) && !printed.contains(&priv_hex));
+        assert_eq!(
+            format!("{:?}", PreSharedKey::new(b"team secret")),
+            "PreSharedKey(<redacted>)"
+        );
+        let identity = ServerIdentity::generate();
+        assert!(format!("{:?}", identity).contains(&identity.public_key().to_hex()));
+
+        let mut priv_key = alice.dh.priv_key.clone();
+        priv_key.zeroize();
+        assert_eq!(*priv_key, BigUint::from(0u8));
+    }
+
+    #[test]
+    fn python_compat_matches_crypto_py() {
+        let (alice, bob) = session_pair::<PythonCompatDiffieHellman>();
+        // Padding.pad(b"hi\n", 16) under AES-256-ECB is exactly one block
+        let ciphertext = alice.encrypt(b"hi\n").unwrap();
+        assert_eq!(ciphertext.len(), 16);
+        assert_eq!(bob.decrypt(&ciphertext), Ok(b"hi\n".to_vec()));
+
+        let compat = PythonCompatDiffieHellman::new();
+        assert_eq!(compat.serialize(&BigUint::from(512u32)), b"512".to_vec());
+        assert_eq!(compat.deserialize(b"512"), Ok(BigUint::from(512u32)));
+        assert_eq!(compat.deserialize(b"5x2"), Err(CryptoError::BadKey));
+    }
+
+    #[test]
+    fn handshake_signatures_only_verify_under_the_signing_key() {
+        let server = ServerIdentity::generate();
+        let pinned = ServerPublicKey::from_hex(&server.public_key().to_hex()).unwrap();
+        let dh_pub = PrimeDiffieHellman::new().init_keys();
+        let signature = server.sign_handshake(&dh_pub);
+        assert_eq!(pinned.verify_handshake(&dh_pub, &signature), Ok(()));
+
+        let mut other_dh_pub = dh_pub.clone();
+        other_dh_pub[0] ^= 1;
+        assert_eq!(
+            pinned.verify_handshake(&other_dh_pub, &signature),
+            Err(CryptoError::BadSignature)
+        );
+        let impostor = ServerIdentity::generate().public_key();
+        assert_eq!(
+            impostor.verify_handshake(&dh_pub, &signature),
+            Err(CryptoError::BadSignature)
+        );
+        assert_eq!(
+            pinned.verify_handshake(&dh_pub, &signature[..10]),
+            Err(CryptoError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn aead_rejects_tampered_and_truncated_messages() {
+        let (alice, bob) = session_pair::<AeadDiffieHellman>();
+        let ciphertext = alice.encrypt(b"/quit").unwrap();
+        for i in 0..ciphertext.len() {
+            let mut forged = ciphertext.clone();
+            forged[i] ^= 0x01;
+            assert_eq!(
+                bob.decrypt(&forged),
+                Err(CryptoError::AuthenticationFailed),
+                "flipped byte {}",
+                i
+            );
+        }
+        assert_eq!(
+            bob.decrypt(&ciphertext[..ciphertext.len() - 1]),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        assert_eq!(bob.decrypt(&[]), Err(CryptoError::Truncated));
+    }
+
+    // Both parties seeded, so every output below is fixed; the hex is what this code produced
+    fn seeded_pair<C: Crypto>(new: impl Fn(ChaCha20Rng) -> C) -> (C, C, Vec<u8>, Vec<u8>) {
+        let mut alice = new(ChaCha20Rng::seed_from_u64(1));
+        let mut bob = new(ChaCha20Rng::seed_from_u64(2));
+        let alice_pub = alice.init_keys();
+        let bob_pub = bob.init_keys();
+        alice.handshake(&bob_pub).unwrap();
+        bob.handshake(&alice_pub).unwrap();
+        (alice, bob, alice_pub, bob_pub)
+    }
+
+    #[test]
+    fn prime_dh_known_answers() {
+        let (alice, bob, alice_pub, bob_pub) =
+            seeded_pair(|rng| PrimeDiffieHellman::with_rng(DhGroup::Modp2048, rng));
+        assert_eq!(
+            hex::encode(Sha256::digest(&alice_pub)),
+            "649f15a33d737f4f42fadec108e214167ff3f433d76c245a788e094b4e2adc94"
+        );
+        assert_eq!(
+            hex::encode(Sha256::digest(&bob_pub)),
+            "1ac70b68efe7934926be5f2deff5d63f9044b492e505ba848230939e76e2b46a"
+        );
+        assert_eq!(hex::encode(*alice.key), "bcb8aafcd4601813239a1f52ec0d49e8");
+        let ciphertext = alice.encrypt(b"hello, world").unwrap();
+        assert_eq!(hex::encode(&ciphertext), "80a90d10e3f5966db1a1480038baa5b6");
+        assert_eq!(bob.decrypt(&ciphertext), Ok(b"hello, world".to_vec()));
+    }
+
+    #[test]
+    fn aead_dh_known_answers() {
+        let (alice, bob, _, _) =
+            seeded_pair(|rng| AeadDiffieHellman::with_rng(DhGroup::Modp2048, rng));
+        // the nonce comes from the same seeded stream, so sealing is reproducible too
+        let ciphertext = alice.encrypt(b"hello, world").unwrap();
+        assert_eq!(
+            hex::encode(&ciphertext),
+            "1157912e0e171f60de9e53416448ad1b6ca5d1fc9695b3e2a46786e04dbd2c743973f7f8d2c2c206"
+        );
+        assert_eq!(bob.decrypt(&ciphertext), Ok(b"hello, world".to_vec()));
+    }
+
+    #[test]
+    fn x25519_known_answers() {
+        let (alice, bob, alice_pub, bob_pub) = seeded_pair(X25519DiffieHellman::with_rng);
+        assert_eq!(
+            hex::encode(&alice_pub),
+            "c9561fe32c63944f32911110e14dc210d15c4c3402f82a05f1c5c8334172216b"
+        );
+        assert_eq!(
+            hex::encode(&bob_pub),
+            "52cca6438038819fef7230a934ca175235da9a44c20590cc9c2f025194a64328"
+        );
+        let ciphertext = alice.encrypt(b"hello, world").unwrap();
+        assert_eq!(
+            hex::encode(&ciphertext),
+            "000efec87c5749ec1157912e23bae23c7affd35ad3941a4cb1c6fbf851b6d1e3ba775a537b56808d"
+        );
+        assert_eq!(bob.decrypt(&ciphertext), Ok(b"hello, world".to_vec()));
+    }
+
+    #[test]
+    fn python_compat_known_answers() {
+        let (alice, bob, alice_pub, bob_pub) = seeded_pair(PythonCompatDiffieHellman::with_rng);
+        assert_eq!(alice_pub, b"517");
+        assert_eq!(bob_pub, b"8");
+        let ciphertext = alice.encrypt(b"hello, world").unwrap();
+        assert_eq!(hex::encode(&ciphertext), "f7cf177783db2592497ee8e9200e2dca");
+        assert_eq!(bob.decrypt(&ciphertext), Ok(b"hello, world".to_vec()));
+    }
+
+    #[test]
+    fn seeded_identities_are_reproducible() {
+        let identity = ServerIdentity::generate_with_rng(&mut ChaCha20Rng::seed_from_u64(3));
+        assert_eq!(
+            identity.public_key().to_hex(),
+            "ec8924090e507c2d8371d2fb0bf965d553e6e5756aeec6c274df3801cf2b49b9"
+        );
+    }
+
+    // Shared with chat/py/solution/crypto.py, which checks the python_compat half
+    const TEST_VECTORS: &str = include_str!("../../../../test_vectors.json");
+
+    fn vectors(scheme: &str) -> Vec<Value> {
+        let all: Value = serde_json::from_str(TEST_VECTORS).unwrap();
+        all[scheme].as_array().unwrap().clone()
+    }
+
+    fn hex_field(vector: &Value, field: &str) -> Vec<u8> {
+        hex::decode(vector[field].as_str().unwrap()).unwrap()
+    }
+
+    // Every message must encrypt to exactly the recorded ciphertext and decrypt back
+    fn check_messages<C: Crypto>(sender: &C, receiver: &C, vector: &Value) {
+        for message in vector["messages"].as_array().unwrap() {
+            let plaintext = hex_field(message, "plaintext");
+            let ciphertext = hex_field(message, "ciphertext");
+            assert_eq!(sender.encrypt(&plaintext), Ok(ciphertext.clone()));
+            assert_eq!(receiver.decrypt(&ciphertext), Ok(plaintext));
+        }
+    }
+
+    #[test]
+    fn prime_dh_matches_test_vectors() {
+        for vector in vectors("prime_dh") {
+            let name = vector["group"].as_str().unwrap();
+            let group = [
+                DhGroup::Modp2048,
+                DhGroup::Modp3072,
+                DhGroup::Modp4096,
+                DhGroup::Ffdhe2048,
+                DhGroup::Ffdhe3072,
+                DhGroup::Ffdhe4096,
+            ]
+            .into_iter()
+            .find(|group| format!("{:?}", group) == name)
+            .unwrap();
+            let priv_key = |field| {
+                let digits = vector[field].as_str().unwrap().as_bytes();
+                SecretBigUint::new(BigUint::parse_bytes(digits, 16).unwrap())
+            };
+
+            let mut alice = PrimeDiffieHellman::with_group(group);
+            let mut bob = PrimeDiffieHellman::with_group(group);
+            alice.priv_key = priv_key("a_priv");
+            bob.priv_key = priv_key("b_priv");
+            let alice_pub = alice.serialize(&alice.gen_pub_key(&alice.priv_key));
+            let bob_pub = bob.serialize(&bob.gen_pub_key(&bob.priv_key));
+            assert_eq!(alice_pub, hex_field(&vector, "a_pub"), "{}", name);
+            assert_eq!(bob_pub, hex_field(&vector, "b_pub"), "{}", name);
+
+            let secret =
+                alice.compute_shared_secret(&alice.priv_key, &BigUint::from_bytes_be(&bob_pub));
+            assert_eq!(
+                alice.pad_be(&secret),
+                hex_field(&vector, "shared_secret"),
+                "{}",
+                name
+            );
+            alice.handshake(&bob_pub).unwrap();
+            bob.handshake(&alice_pub).unwrap();
+            assert_eq!(alice.key.to_vec(), hex_field(&vector, "key"), "{}", name);
+            assert_eq!(bob.key, alice.key, "{}", name);
+            check_messages(&alice, &bob, &vector);
+        }
+    }
+
+    #[test]
+    fn python_compat_matches_test_vectors() {
+        for vector in vectors("python_compat") {
+            assert_eq!(vector["p"], 997);
+            assert_eq!(vector["g"], 2);
+            let priv_key = |field: &str| vector[field].as_u64().unwrap() as u32;
+            let (mut alice, alice_pub) =
+                PythonCompatDiffieHellman::with_private_key(priv_key("a_priv"));
+            let (mut bob, bob_pub) =
+                PythonCompatDiffieHellman::with_private_key(priv_key("b_priv"));
+            assert_eq!(alice_pub, vector["a_pub"].as_str().unwrap().as_bytes());
+            assert_eq!(bob_pub, vector["b_pub"].as_str().unwrap().as_bytes());
+
+            alice.handshake(&bob_pub).unwrap();
+            bob.handshake(&alice_pub).unwrap();
+            // crypto.py uses the padded shared secret itself as the key
+            assert_eq!(alice.key(), hex_field(&vector, "shared_secret"));
+            assert_eq!(alice.key(), hex_field(&vector, "key"));
+            assert_eq!(bob.key(), alice.key());
+            check_messages(&alice, &bob, &vector);
+        }
+    }
+}
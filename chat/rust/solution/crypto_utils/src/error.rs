@@ -0,0 +1,51 @@
+use std::{fmt, io};
+
+/** Everything that can go wrong turning bytes from the network into keys or plaintext.
+    None of these are bugs on our side: they all mean the peer sent something malformed
+    or forged, so callers should drop the record or the connection rather than panic.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    // A public or session key has the wrong size, or was used before the handshake set it
+    BadKey,
+    // The peer's public key is well-formed but degenerate: out of range, or in a small
+    // subgroup, so the shared secret would be predictable
+    InvalidPublicKey,
+    // Decryption succeeded but the PKCS#7 padding is malformed
+    BadPadding,
+    // An AEAD tag didn't verify: the ciphertext was tampered with or encrypted under another key
+    AuthenticationFailed,
+    // The server's signature over its handshake doesn't verify against the key we expected
+    BadSignature,
+    // The input is too short (or not a whole number of blocks) to be a ciphertext
+    Truncated,
+    // The peer's key confirmation didn't match ours, so the two sides used different pre-shared keys
+    KeyConfirmationFailed,
+    // A ratchet message claims to be so far ahead that we'd have to derive an unreasonable
+    // number of keys for the messages in between
+    TooFarAhead,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            CryptoError::BadKey => "invalid or missing key",
+            CryptoError::InvalidPublicKey => "peer public key is degenerate or out of range",
+            CryptoError::BadPadding => "bad padding",
+            CryptoError::AuthenticationFailed => "authentication failed",
+            CryptoError::BadSignature => "server identity signature did not verify",
+            CryptoError::Truncated => "truncated input",
+            CryptoError::KeyConfirmationFailed => "key confirmation failed (wrong pre-shared key?)",
+            CryptoError::TooFarAhead => "message is too far ahead of the conversation",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+impl From<CryptoError> for io::Error {
+    fn from(e: CryptoError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{CryptoError, NoiseStaticKey, SecureRng};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+// Domain separation, so a handshake signature can never be passed off as anything else
+const HANDSHAKE_SIG_CONTEXT: &[u8] = b"copilot-chat server handshake v1";
+
+// HKDF context string for the Noise static key the identity seed stands behind
+const NOISE_STATIC_INFO: &[u8] = b"copilot-chat noise static key v1";
+
+// HKDF context string for the key behind SRP decoy challenges
+const SRP_DECOY_INFO: &[u8] = b"copilot-chat srp decoy key v1";
+
+/** The server's long-term Ed25519 key.
+    It signs every ephemeral DH public key the server sends, so a client that
+    knows the matching ServerPublicKey can tell the real server from a man in the middle.
+    SigningKey wipes itself on drop; Debug only shows the public half.
+*/
+pub struct ServerIdentity {
+    signing_key: SigningKey,
+}
+
+impl ServerIdentity {
+    pub fn generate() -> ServerIdentity {
+        Self::generate_with_rng(&mut rand::thread_rng())
+    }
+
+    pub fn generate_with_rng<R: SecureRng + ?Sized>(rng: &mut R) -> ServerIdentity {
+        let mut seed = Zeroizing::new([0_u8; 32]);
+        rng.fill_bytes(seed.as_mut());
+        ServerIdentity {
+            signing_key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    // The key file holds the 32-byte secret seed as a single line of hex
+    pub fn load(path: &Path) -> io::Result<ServerIdentity> {
+        let seed = load_secret::<32>(path)?;
+        Ok(ServerIdentity {
+            signing_key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    // Refuses to overwrite an existing file; on unix the file is only readable by its owner
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        save_secret(path, self.signing_key.as_bytes())
+    }
+
+    pub fn load_or_generate(path: &Path) -> io::Result<ServerIdentity> {
+        if path.exists() {
+            return Self::load(path);
+        }
+        let identity = Self::generate();
+        identity.save(path)?;
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> ServerPublicKey {
+        ServerPublicKey(self.signing_key.verifying_key())
+    }
+
+    // The server's Noise static key, derived from the identity so it lasts exactly as long
+    pub fn noise_static_key(&self) -> NoiseStaticKey {
+        let mut secret = Zeroizing::new([0_u8; 32]);
+        Hkdf::<Sha256>::new(None, self.signing_key.as_bytes())
+            .expand(NOISE_STATIC_INFO, secret.as_mut())
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        NoiseStaticKey::from_secret(secret)
+    }
+
+    // Also derived from the identity, so decoys stay the same across restarts like real accounts
+    pub(crate) fn srp_decoy_key(&self) -> Zeroizing<[u8; 32]> {
+        let mut key = Zeroizing::new([0_u8; 32]);
+        Hkdf::<Sha256>::new(None, self.signing_key.as_bytes())
+            .expand(SRP_DECOY_INFO, key.as_mut())
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+
+    pub fn sign_handshake(&self, dh_pub_key: &[u8]) -> Vec<u8> {
+        let signature = self.signing_key.sign(&handshake_message(dh_pub_key));
+        signature.to_bytes().to_vec()
+    }
+}
+
+impl fmt::Debug for ServerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerIdentity")
+            .field("public_key", &self.public_key().to_hex())
+            .finish_non_exhaustive()
+    }
+}
+
+// What a client pins to recognise its server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServerPublicKey(VerifyingKey);
+
+impl ServerPublicKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<ServerPublicKey, CryptoError> {
+        let bytes: &[u8; 32] = bytes.try_into().map_err(|_| CryptoError::BadKey)?;
+        VerifyingKey::from_bytes(bytes)
+            .map(ServerPublicKey)
+            .map_err(|_| CryptoError::BadKey)
+    }
+
+    pub fn from_hex(hex_key: &str) -> Result<ServerPublicKey, CryptoError> {
+        let bytes = hex::decode(hex_key.trim()).map_err(|_| CryptoError::BadKey)?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.as_bytes())
+    }
+
+    // Short, stable form for showing to humans, in the style of ssh-keygen -l
+    pub fn fingerprint(&self) -> String {
+        format!("SHA256:{}", hex::encode(Sha256::digest(self.as_bytes())))
+    }
+
+    // Check that the server really did sign the DH public key it just sent us
+    pub fn verify_handshake(&self, dh_pub_key: &[u8], signature: &[u8]) -> Result<(), CryptoError> {
+        let signature = Signature::from_slice(signature).map_err(|_| CryptoError::BadSignature)?;
+        self.0
+            .verify(&handshake_message(dh_pub_key), &signature)
+            .map_err(|_| CryptoError::BadSignature)
+    }
+}
+
+// Read a key file holding an N-byte secret as a single line of hex
+pub(crate) fn load_secret<const N: usize>(path: &Path) -> io::Result<Zeroizing<[u8; N]>> {
+    let contents = Zeroizing::new(fs::read_to_string(path)?);
+    let bytes = Zeroizing::new(hex::decode(contents.trim()).unwrap_or_default());
+    if bytes.len() != N {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a hex-encoded {}-byte key", path.display(), N),
+        ));
+    }
+    let mut secret = Zeroizing::new([0_u8; N]);
+    secret.copy_from_slice(&bytes);
+    Ok(secret)
+}
+
+// Write a key file for load_secret, refusing to overwrite one and keeping it private on unix
+pub(crate) fn save_secret(path: &Path, secret: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    let hex_secret = Zeroizing::new(hex::encode(secret));
+    writeln!(file, "{}", hex_secret.as_str())
+}
+
+fn handshake_message(dh_pub_key: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HANDSHAKE_SIG_CONTEXT.len() + dh_pub_key.len());
+    message.extend_from_slice(HANDSHAKE_SIG_CONTEXT);
+    message.extend_from_slice(dh_pub_key);
+    message
+}
@@ -0,0 +1,394 @@
+use crate::identity::{load_secret, save_secret};
+use crate::rng::SessionRng;
+use crate::{
+    aead, derive_key, next_key, psk_key, stream_key, Crypto, CryptoError, KeyBytes, PreSharedKey,
+    SecureRng,
+};
+use rand::{CryptoRng, RngCore};
+use snow::params::{CipherChoice, DHChoice, HashChoice};
+use snow::resolvers::{CryptoResolver, DefaultResolver, FallbackResolver};
+use snow::types::{Cipher, Dh, Hash, Random};
+use snow::{Builder, HandshakeState};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/** The handshake pattern and primitives; both ends must agree on every part of this string.
+    The handshake encrypts with AES-GCM so it names the same cipher family as the records,
+    which use AES-128-GCM under keys derived from the Noise split (see NoiseHandshake::finish).
+*/
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_AESGCM_SHA256";
+
+// Noise caps every handshake and transport message at this size
+const NOISE_MAX_MESSAGE_LEN: usize = 65535;
+
+// X25519 static keys
+pub const NOISE_KEY_LEN: usize = 32;
+
+/** A long-term X25519 key for Noise handshakes, which is what the peer authenticates.
+    The server's comes from its identity (see ServerIdentity::noise_static_key); a client keeps
+    its own in a key file, so the server can tell it's the same client as last time.
+    The secret wipes itself on drop; Debug only shows the public half.
+*/
+#[derive(Clone)]
+pub struct NoiseStaticKey {
+    secret: Zeroizing<[u8; NOISE_KEY_LEN]>,
+    public: [u8; NOISE_KEY_LEN],
+}
+
+impl NoiseStaticKey {
+    pub fn generate() -> NoiseStaticKey {
+        Self::generate_with_rng(&mut rand::thread_rng())
+    }
+
+    pub fn generate_with_rng<R: SecureRng + ?Sized>(rng: &mut R) -> NoiseStaticKey {
+        let mut secret = Zeroizing::new([0_u8; NOISE_KEY_LEN]);
+        rng.fill_bytes(secret.as_mut());
+        Self::from_secret(secret)
+    }
+
+    pub(crate) fn from_secret(secret: Zeroizing<[u8; NOISE_KEY_LEN]>) -> NoiseStaticKey {
+        let public = PublicKey::from(&StaticSecret::from(*secret)).to_bytes();
+        NoiseStaticKey { secret, public }
+    }
+
+    // The key file holds the 32-byte secret as a single line of hex, like a ServerIdentity's
+    pub fn load(path: &Path) -> io::Result<NoiseStaticKey> {
+        Ok(Self::from_secret(load_secret(path)?))
+    }
+
+    // Refuses to overwrite an existing file; on unix the file is only readable by its owner
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        save_secret(path, self.secret.as_slice())
+    }
+
+    pub fn load_or_generate(path: &Path) -> io::Result<NoiseStaticKey> {
+        if path.exists() {
+            return Self::load(path);
+        }
+        let key = Self::generate();
+        key.save(path)?;
+        Ok(key)
+    }
+
+    pub fn public_key(&self) -> &[u8; NOISE_KEY_LEN] {
+        &self.public
+    }
+}
+
+impl fmt::Debug for NoiseStaticKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseStaticKey")
+            .field("public_key", &hex::encode(self.public))
+            .finish_non_exhaustive()
+    }
+}
+
+/** One side of a Noise_XX handshake:
+    -> e
+    <- e, ee, s, es
+    -> s, se
+    The initiator (client) writes first. Each side proves it holds its static key, and
+    finish() hands on the peer's, so the caller can check it against one it knows.
+*/
+pub struct NoiseHandshake {
+    state: HandshakeState,
+    static_public: [u8; NOISE_KEY_LEN],
+    // What the finished ciphers will draw their nonces from
+    rng: SessionRng,
+}
+
+impl NoiseHandshake {
+    pub fn initiator(static_key: &NoiseStaticKey) -> Result<NoiseHandshake, CryptoError> {
+        Self::build(true, static_key, SessionRng::default())
+    }
+
+    pub fn responder(static_key: &NoiseStaticKey) -> Result<NoiseHandshake, CryptoError> {
+        Self::build(false, static_key, SessionRng::default())
+    }
+
+    fn build(
+        initiator: bool,
+        static_key: &NoiseStaticKey,
+        rng: SessionRng,
+    ) -> Result<NoiseHandshake, CryptoError> {
+        let params = NOISE_PARAMS.parse().map_err(|_| CryptoError::BadKey)?;
+        let resolver = FallbackResolver::new(
+            Box::new(SessionRngResolver(rng.clone())),
+            Box::new(DefaultResolver),
+        );
+        let builder = Builder::with_resolver(params, Box::new(resolver))
+            .local_private_key(static_key.secret.as_slice());
+        let state = if initiator {
+            builder.build_initiator()
+        } else {
+            builder.build_responder()
+        }
+        .map_err(noise_error)?;
+        Ok(NoiseHandshake {
+            state,
+            static_public: static_key.public,
+            rng,
+        })
+    }
+
+    // Our static public key, which the peer will learn during the handshake
+    pub fn static_public_key(&self) -> &[u8] {
+        &self.static_public
+    }
+
+    // The peer's static public key, once its handshake message carrying it has been read
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.state.get_remote_static()
+    }
+
+    // Produce our next handshake message, carrying payload (encrypted once keys exist)
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut message = vec![0_u8; NOISE_MAX_MESSAGE_LEN];
+        let len = self
+            .state
+            .write_message(payload, &mut message)
+            .map_err(noise_error)?;
+        message.truncate(len);
+        Ok(message)
+    }
+
+    // Consume the peer's next handshake message and return its payload
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut payload = vec![0_u8; NOISE_MAX_MESSAGE_LEN];
+        let len = self
+            .state
+            .read_message(message, &mut payload)
+            .map_err(noise_error)?;
+        payload.truncate(len);
+        Ok(payload)
+    }
+
+    /** Turn the finished handshake into (sending, receiving) ciphers.
+        Each direction gets its own AES-128-GCM key, derived from its half of the Noise split;
+        both remember the peer's static key (see NoiseXX::remote_static_key).
+    */
+    pub fn finish(mut self) -> Result<(NoiseXX, NoiseXX), CryptoError> {
+        if !self.state.is_handshake_finished() {
+            return Err(CryptoError::BadKey);
+        }
+        let remote_static: [u8; NOISE_KEY_LEN] = self
+            .state
+            .get_remote_static()
+            .and_then(|key| key.try_into().ok())
+            .ok_or(CryptoError::BadKey)?;
+        let (initiator_to_responder, responder_to_initiator) =
+            self.state.dangerously_get_raw_split();
+        let initiator_to_responder = Zeroizing::new(initiator_to_responder);
+        let responder_to_initiator = Zeroizing::new(responder_to_initiator);
+        let outgoing = NoiseXX::established(
+            derive_key(initiator_to_responder.as_slice()),
+            remote_static,
+            self.rng.clone(),
+        );
+        let incoming = NoiseXX::established(
+            derive_key(responder_to_initiator.as_slice()),
+            remote_static,
+            self.rng.clone(),
+        );
+        if self.state.is_initiator() {
+            Ok((outgoing, incoming))
+        } else {
+            Ok((incoming, outgoing))
+        }
+    }
+}
+
+fn noise_error(e: snow::Error) -> CryptoError {
+    match e {
+        snow::Error::Decrypt => CryptoError::AuthenticationFailed,
+        snow::Error::Dh => CryptoError::InvalidPublicKey,
+        snow::Error::Input => CryptoError::Truncated,
+        _ => CryptoError::BadKey,
+    }
+}
+
+impl Crypto for NoiseXX {
+    type PublicKey = Vec<u8>;
+
+    // Noise_XX needs three messages, which doesn't fit init_keys/handshake;
+    // keys only come from NoiseHandshake::finish.
+    fn init_keys(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn handshake(&mut self, _other_pub_key: &[u8]) -> Result<(), CryptoError> {
+        Err(CryptoError::BadKey)
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        aead::seal(&self.key, &self.rng, plaintext)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        aead::open(&self.key, data)
+    }
+
+    fn rekey(&mut self) -> Result<(), CryptoError> {
+        self.key = next_key(&self.key);
+        Ok(())
+    }
+
+    fn mix_psk(&mut self, psk: &PreSharedKey) -> Result<(), CryptoError> {
+        self.key = psk_key(&self.key, psk);
+        Ok(())
+    }
+
+    fn stream_key(&self, salt: &[u8], context: &[u8]) -> Result<Zeroizing<KeyBytes>, CryptoError> {
+        Ok(stream_key(self.key.as_slice(), salt, context))
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        self.rng.with(|rng| rng.fill_bytes(buf))
+    }
+
+    fn serialize(&self, pub_key: &Vec<u8>) -> Vec<u8> {
+        pub_key.clone()
+    }
+
+    fn deserialize(&self, pub_key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if pub_key.len() != NOISE_KEY_LEN {
+            return Err(CryptoError::BadKey);
+        }
+        Ok(pub_key.to_vec())
+    }
+}
+
+// Hands snow the session's RNG, for its ephemeral keys; everything else is snow's own
+struct SessionRngResolver(SessionRng);
+
+impl CryptoResolver for SessionRngResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        Some(Box::new(SnowRng(self.0.clone())))
+    }
+
+    fn resolve_dh(&self, _choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        None
+    }
+
+    fn resolve_hash(&self, _choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        None
+    }
+
+    fn resolve_cipher(&self, _choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        None
+    }
+}
+
+struct SnowRng(SessionRng);
+
+impl RngCore for SnowRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.with(|rng| rng.next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.with(|rng| rng.next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.with(|rng| rng.fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0.with(|rng| rng.try_fill_bytes(dest))
+    }
+}
+
+impl CryptoRng for SnowRng {}
+impl Random for SnowRng {}
+
+/** Record cipher for one direction of a Noise_XX session.
+    The handshake gives forward secrecy and a hash of the whole transcript mixed into the keys,
+    and authenticates both static keys: it's up to each side to check the other's, against a
+    pinned or signed key. Records then use AES-128-GCM like the other suites.
+    Before the handshake, an instance only carries our static key, if we have a long-term one.
+*/
+#[derive(Clone)]
+pub struct NoiseXX {
+    key: Zeroizing<KeyBytes>,
+    static_key: Option<NoiseStaticKey>,
+    remote_static: Option<[u8; NOISE_KEY_LEN]>,
+    rng: SessionRng,
+}
+
+impl NoiseXX {
+    // Ephemeral keys, nonces, and our static key if we have no long-term one, come from rng
+    pub fn with_rng<R: SecureRng + Send + 'static>(rng: R) -> NoiseXX {
+        NoiseXX {
+            rng: SessionRng::new(rng),
+            ..NoiseXX::default()
+        }
+    }
+
+    // This instance, but handshaking with static_key instead of a fresh key
+    pub fn with_static_key(self, static_key: NoiseStaticKey) -> NoiseXX {
+        NoiseXX {
+            static_key: Some(static_key),
+            ..self
+        }
+    }
+
+    fn established(
+        key: Zeroizing<KeyBytes>,
+        remote_static: [u8; NOISE_KEY_LEN],
+        rng: SessionRng,
+    ) -> NoiseXX {
+        NoiseXX {
+            key,
+            static_key: None,
+            remote_static: Some(remote_static),
+            rng,
+        }
+    }
+
+    /** Start the initiator's side of a handshake with our static key, or with a fresh one
+        from the session's RNG if we have none. The ciphers it finishes with share that RNG.
+    */
+    pub fn initiator(&self) -> Result<NoiseHandshake, CryptoError> {
+        let static_key = match &self.static_key {
+            Some(static_key) => static_key.clone(),
+            None => self.rng.with(|rng| NoiseStaticKey::generate_with_rng(rng)),
+        };
+        NoiseHandshake::build(true, &static_key, self.rng.clone())
+    }
+
+    // The responder's side, which authenticates with static_key (the server's identity's)
+    pub fn responder(&self, static_key: &NoiseStaticKey) -> Result<NoiseHandshake, CryptoError> {
+        NoiseHandshake::build(false, static_key, self.rng.clone())
+    }
+
+    // The static key to handshake with, if this instance was given one
+    pub fn static_key(&self) -> Option<&NoiseStaticKey> {
+        self.static_key.as_ref()
+    }
+
+    // The peer's static key, as authenticated by the handshake these ciphers came from
+    pub fn remote_static_key(&self) -> Option<&[u8; NOISE_KEY_LEN]> {
+        self.remote_static.as_ref()
+    }
+}
+
+impl Default for NoiseXX {
+    fn default() -> Self {
+        NoiseXX {
+            key: Zeroizing::new([0_u8; 16]),
+            static_key: None,
+            remote_static: None,
+            rng: SessionRng::default(),
+        }
+    }
+}
+
+impl fmt::Debug for NoiseXX {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseXX").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,86 @@
+use crate::aead::TAG_LEN;
+use crate::{CryptoError, KeyBytes};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher, Crypter, Mode};
+
+// Our ECB keys are either 16-byte session keys or chat/py's 32-byte shared secrets
+fn ecb_cipher(key: &[u8]) -> Result<Cipher, CryptoError> {
+    match key.len() {
+        16 => Ok(Cipher::aes_128_ecb()),
+        32 => Ok(Cipher::aes_256_ecb()),
+        _ => Err(CryptoError::BadKey),
+    }
+}
+
+// AES-ECB with PKCS#7 padding, so messages of any length round-trip
+pub(crate) fn ecb_encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let cipher = ecb_cipher(key)?;
+    let mut ciphertext = vec![0; plaintext.len() + cipher.block_size()];
+    let mut crypter =
+        Crypter::new(cipher, Mode::Encrypt, key, None).map_err(|_| CryptoError::BadKey)?;
+    crypter.pad(true);
+    let count = crypter
+        .update(plaintext, &mut ciphertext)
+        .map_err(|_| CryptoError::BadKey)?;
+    let rest = crypter
+        .finalize(&mut ciphertext[count..])
+        .map_err(|_| CryptoError::BadKey)?;
+    ciphertext.truncate(count + rest);
+    Ok(ciphertext)
+}
+
+// Inverse of ecb_encrypt; data must already be a whole number of blocks
+pub(crate) fn ecb_decrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let cipher = ecb_cipher(key)?;
+    let mut crypter =
+        Crypter::new(cipher, Mode::Decrypt, key, None).map_err(|_| CryptoError::BadKey)?;
+    crypter.pad(true);
+    let mut output = vec![0_u8; data.len() + cipher.block_size()];
+    let count = crypter
+        .update(data, &mut output)
+        .map_err(|_| CryptoError::BadPadding)?;
+    let rest = crypter
+        .finalize(&mut output[count..])
+        .map_err(|_| CryptoError::BadPadding)?;
+    output.truncate(count + rest);
+    Ok(output)
+}
+
+// AES-128-GCM, authenticating aad alongside the plaintext; returns ciphertext || tag
+pub(crate) fn gcm_seal(
+    key: &KeyBytes,
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let mut tag = [0_u8; TAG_LEN];
+    let mut sealed = encrypt_aead(
+        Cipher::aes_128_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        plaintext,
+        &mut tag,
+    )
+    .map_err(|_| CryptoError::BadKey)?;
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+// Inverse of gcm_seal; sealed must be at least a tag long
+pub(crate) fn gcm_open(
+    key: &KeyBytes,
+    nonce: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_128_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| CryptoError::AuthenticationFailed)
+}
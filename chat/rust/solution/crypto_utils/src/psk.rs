@@ -0,0 +1,41 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/** A secret shared out of band by everyone allowed on a private server.
+    It is mixed into the session key after the DH exchange (see Crypto::mix_psk),
+    so a peer without it ends up with a different key and fails key confirmation.
+*/
+#[derive(Clone)]
+pub struct PreSharedKey(Zeroizing<Vec<u8>>);
+
+impl PreSharedKey {
+    pub fn new(secret: &[u8]) -> PreSharedKey {
+        PreSharedKey(Zeroizing::new(secret.to_vec()))
+    }
+
+    // The key file holds the secret as text; surrounding whitespace is ignored
+    pub fn load(path: &Path) -> io::Result<PreSharedKey> {
+        let contents = Zeroizing::new(fs::read_to_string(path)?);
+        let secret = contents.trim();
+        if secret.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} does not contain a pre-shared key", path.display()),
+            ));
+        }
+        Ok(Self::new(secret.as_bytes()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PreSharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PreSharedKey(<redacted>)")
+    }
+}
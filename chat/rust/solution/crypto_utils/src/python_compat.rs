@@ -0,0 +1,149 @@
+use crate::rng::SessionRng;
+use crate::secret::SecretBigUint;
+use crate::{
+    backend, next_key, psk_key, stream_key, Crypto, CryptoError, KeyBytes, PreSharedKey, SecureRng,
+    AES_BLOCK_LEN,
+};
+use num::bigint::RandBigInt;
+use num::BigUint;
+use std::fmt;
+use zeroize::Zeroizing;
+
+// The parameters and key size hardcoded in chat/py/solution/crypto.py
+const PYTHON_P: u32 = 997;
+const PYTHON_G: u32 = 2;
+const PYTHON_KEY_LEN: usize = 32;
+
+impl Crypto for PythonCompatDiffieHellman {
+    type PublicKey = BigUint;
+
+    fn init_keys(&mut self) -> Vec<u8> {
+        // random.randint(1, p - 1) is inclusive on both ends
+        let priv_key = self
+            .rng
+            .with(|rng| rng.gen_biguint_range(&BigUint::from(1u8), &self.p));
+        self.priv_key = SecretBigUint::new(priv_key);
+        let pub_key = self.g.modpow(&self.priv_key, &self.p);
+        self.serialize(&pub_key)
+    }
+
+    // Only keys outside [1, p - 1] are rejected. crypto.py will happily send 1 or p - 1
+    // now and then, and refusing those would just make interop fail at random.
+    fn handshake(&mut self, other_pub_key: &[u8]) -> Result<(), CryptoError> {
+        let other_pub_key = self.deserialize(other_pub_key)?;
+        if other_pub_key == BigUint::from(0u8) || other_pub_key >= self.p {
+            return Err(CryptoError::InvalidPublicKey);
+        }
+        let shared_secret = SecretBigUint::new(other_pub_key.modpow(&self.priv_key, &self.p));
+
+        // shared_secret.to_bytes(32, byteorder="big"), used directly as the AES-256 key
+        let secret_bytes = Zeroizing::new(shared_secret.to_bytes_be());
+        self.key = Zeroizing::new([0_u8; PYTHON_KEY_LEN]);
+        self.key[PYTHON_KEY_LEN - secret_bytes.len()..].copy_from_slice(&secret_bytes);
+        Ok(())
+    }
+
+    // AES-256-ECB with PKCS#7 padding, like Padding.pad(message, AES.block_size)
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        backend::ecb_encrypt(self.key.as_slice(), plaintext)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if data.is_empty() || !data.len().is_multiple_of(AES_BLOCK_LEN) {
+            return Err(CryptoError::Truncated);
+        }
+        backend::ecb_decrypt(self.key.as_slice(), data)
+    }
+
+    // crypto.py never rekeys, so this only matters if both ends are Rust
+    fn rekey(&mut self) -> Result<(), CryptoError> {
+        self.key = next_key(&self.key);
+        Ok(())
+    }
+
+    fn mix_psk(&mut self, psk: &PreSharedKey) -> Result<(), CryptoError> {
+        self.key = psk_key(&self.key, psk);
+        Ok(())
+    }
+
+    fn stream_key(&self, salt: &[u8], context: &[u8]) -> Result<Zeroizing<KeyBytes>, CryptoError> {
+        Ok(stream_key(self.key.as_slice(), salt, context))
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        self.rng.with(|rng| rng.fill_bytes(buf))
+    }
+
+    // Python sends str(pub_key).encode(): the key in decimal ASCII
+    fn serialize(&self, pub_key: &BigUint) -> Vec<u8> {
+        pub_key.to_str_radix(10).into_bytes()
+    }
+
+    fn deserialize(&self, pub_key: &[u8]) -> Result<BigUint, CryptoError> {
+        let digits = std::str::from_utf8(pub_key).map_err(|_| CryptoError::BadKey)?;
+        BigUint::parse_bytes(digits.trim().as_bytes(), 10).ok_or(CryptoError::BadKey)
+    }
+}
+
+/** Bit-for-bit the scheme in chat/py/solution/crypto.py, so Rust peers can talk to Python ones.
+    That means p = 997, unauthenticated AES-256-ECB and decimal public keys:
+    there is no security here, only interoperability. Don't use it for anything else.
+*/
+#[derive(Clone)]
+pub struct PythonCompatDiffieHellman {
+    p: BigUint,
+    g: BigUint,
+    key: Zeroizing<[u8; PYTHON_KEY_LEN]>,
+    priv_key: SecretBigUint,
+    rng: SessionRng,
+}
+
+impl PythonCompatDiffieHellman {
+    pub fn new() -> PythonCompatDiffieHellman {
+        Self::with_session_rng(SessionRng::default())
+    }
+
+    // Draw the private key from rng instead of the thread-local generator, like the other suites
+    pub fn with_rng<R: SecureRng + Send + 'static>(rng: R) -> PythonCompatDiffieHellman {
+        Self::with_session_rng(SessionRng::new(rng))
+    }
+
+    fn with_session_rng(rng: SessionRng) -> PythonCompatDiffieHellman {
+        PythonCompatDiffieHellman {
+            p: BigUint::from(PYTHON_P),
+            g: BigUint::from(PYTHON_G),
+            key: Zeroizing::new([0_u8; PYTHON_KEY_LEN]),
+            priv_key: SecretBigUint::default(),
+            rng,
+        }
+    }
+}
+
+#[cfg(test)]
+impl PythonCompatDiffieHellman {
+    // As if random.randint had returned priv_key; also hands back what init_keys would send
+    pub(crate) fn with_private_key(priv_key: u32) -> (PythonCompatDiffieHellman, Vec<u8>) {
+        let mut compat = Self::new();
+        compat.priv_key = SecretBigUint::new(BigUint::from(priv_key));
+        let pub_key = compat.serialize(&compat.g.modpow(&compat.priv_key, &compat.p));
+        (compat, pub_key)
+    }
+
+    pub(crate) fn key(&self) -> &[u8] {
+        self.key.as_slice()
+    }
+}
+
+impl Default for PythonCompatDiffieHellman {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for PythonCompatDiffieHellman {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PythonCompatDiffieHellman")
+            .field("p", &self.p)
+            .finish_non_exhaustive()
+    }
+}
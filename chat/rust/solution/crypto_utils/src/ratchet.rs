@@ -0,0 +1,425 @@
+/** The Double Ratchet behind end-to-end direct messages, after Signal's (without header encryption).
+    Every message gets its own key from a symmetric chain, and every change of speaker runs a
+    fresh X25519 exchange into the root key, so a leaked key exposes neither earlier messages
+    nor, once the peer has replied, later ones.
+
+    A ratchet message is a 40-byte header, [ratchet public key][previous chain length: u32]
+    [index in chain: u32], followed by AES-128-GCM ciphertext || tag. Both users' identities and
+    the header are the associated data, so none of it can be altered or replayed elsewhere.
+*/
+use crate::aead::{NONCE_LEN, TAG_LEN};
+use crate::rng::SessionRng;
+use crate::x25519::X25519_KEY_LEN;
+use crate::x3dh::{UserPublicKey, PRELUDE_LEN};
+use crate::{backend, CryptoError, KeyBytes};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use zeroize::Zeroizing;
+
+// HKDF context strings for the root chain, the sending/receiving chains and each message's cipher
+const ROOT_INFO: &[u8] = b"copilot-chat ratchet root v1";
+const CHAIN_KEY_INFO: &[u8] = b"copilot-chat ratchet chain key v1";
+const MESSAGE_KEY_INFO: &[u8] = b"copilot-chat ratchet message key v1";
+const MESSAGE_CIPHER_INFO: &[u8] = b"copilot-chat ratchet cipher v1";
+
+const HEADER_LEN: usize = X25519_KEY_LEN + 8;
+
+// How far ahead of the conversation a message may be before we refuse to derive the keys
+// for everything in between; this also caps how many skipped keys one chain can leave behind
+const MAX_SKIP: u32 = 1000;
+
+// However many chains they come from, we keep no more skipped keys than this, dropping the oldest
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+type SkippedKey = ([u8; X25519_KEY_LEN], u32);
+
+// The first byte of every message says whether an X3DH prelude comes next
+pub(crate) const RATCHET_MESSAGE: u8 = 0;
+pub(crate) const PREKEY_MESSAGE: u8 = 1;
+
+type ChainKey = Zeroizing<[u8; 32]>;
+
+// X25519, refusing low-order points that would make the output predictable
+pub(crate) fn dh(secret: &StaticSecret, public: &PublicKey) -> Result<SharedSecret, CryptoError> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(CryptoError::InvalidPublicKey);
+    }
+    Ok(shared)
+}
+
+// Mix a new DH output into the root key, giving the next root key and a fresh chain key
+fn kdf_root(root_key: &[u8; 32], dh_out: &SharedSecret) -> (ChainKey, ChainKey) {
+    let mut okm = Zeroizing::new([0_u8; 64]);
+    Hkdf::<Sha256>::new(Some(root_key), dh_out.as_bytes())
+        .expand(ROOT_INFO, okm.as_mut())
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+    let (mut root, mut chain) = (Zeroizing::new([0_u8; 32]), Zeroizing::new([0_u8; 32]));
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    (root, chain)
+}
+
+// One step along a chain: the next chain key, and the key for the message at this step
+fn kdf_chain(chain_key: &[u8; 32]) -> (ChainKey, ChainKey) {
+    let hkdf = Hkdf::<Sha256>::from_prk(chain_key).expect("chain keys are full-length PRKs");
+    let (mut next, mut message_key) = (Zeroizing::new([0_u8; 32]), Zeroizing::new([0_u8; 32]));
+    hkdf.expand(CHAIN_KEY_INFO, next.as_mut())
+        .and_then(|_| hkdf.expand(MESSAGE_KEY_INFO, message_key.as_mut()))
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    (next, message_key)
+}
+
+// Each message key is used once, so the nonce can come from it along with the AES key
+fn message_cipher(message_key: &[u8; 32]) -> (Zeroizing<KeyBytes>, [u8; NONCE_LEN]) {
+    let mut okm = Zeroizing::new([0_u8; 16 + NONCE_LEN]);
+    Hkdf::<Sha256>::new(None, message_key)
+        .expand(MESSAGE_CIPHER_INFO, okm.as_mut())
+        .expect("28 bytes is a valid HKDF-SHA256 output length");
+    let mut key = Zeroizing::new([0_u8; 16]);
+    key.copy_from_slice(&okm[..16]);
+    let mut nonce = [0_u8; NONCE_LEN];
+    nonce.copy_from_slice(&okm[16..]);
+    (key, nonce)
+}
+
+#[derive(Clone)]
+pub(crate) struct DoubleRatchet {
+    root_key: ChainKey,
+    // StaticSecret wipes itself on drop
+    dh_self: StaticSecret,
+    dh_remote: Option<PublicKey>,
+    send_chain: Option<ChainKey>,
+    recv_chain: Option<ChainKey>,
+    sent: u32,
+    received: u32,
+    previous: u32,
+    // Keys for messages that were overtaken by later ones, by ratchet key and index
+    skipped: HashMap<SkippedKey, ChainKey>,
+    // The same keys' ratchet keys and indices, oldest first
+    skipped_order: VecDeque<SkippedKey>,
+    rng: SessionRng,
+}
+
+impl DoubleRatchet {
+    // The side that sends first, starting from the X3DH secret and the peer's signed prekey
+    pub(crate) fn initiator(
+        shared_secret: ChainKey,
+        remote: PublicKey,
+        rng: SessionRng,
+    ) -> Result<DoubleRatchet, CryptoError> {
+        let dh_self = rng.with(|rng| StaticSecret::random_from_rng(rng));
+        let (root_key, send_chain) = kdf_root(&shared_secret, &dh(&dh_self, &remote)?);
+        Ok(DoubleRatchet {
+            root_key,
+            dh_self,
+            dh_remote: Some(remote),
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+            rng,
+        })
+    }
+
+    // The side that receives first; its signed prekey is its first ratchet key
+    pub(crate) fn responder(
+        shared_secret: ChainKey,
+        signed_prekey: StaticSecret,
+        rng: SessionRng,
+    ) -> DoubleRatchet {
+        DoubleRatchet {
+            root_key: shared_secret,
+            dh_self: signed_prekey,
+            dh_remote: None,
+            send_chain: None,
+            recv_chain: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+            rng,
+        }
+    }
+
+    pub(crate) fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        // the responder can't speak until the initiator's first message has arrived
+        let send_chain = self.send_chain.as_ref().ok_or(CryptoError::BadKey)?;
+        let (next, message_key) = kdf_chain(send_chain);
+
+        let mut message = Vec::with_capacity(HEADER_LEN + plaintext.len() + TAG_LEN);
+        message.extend_from_slice(PublicKey::from(&self.dh_self).as_bytes());
+        message.extend_from_slice(&self.previous.to_be_bytes());
+        message.extend_from_slice(&self.sent.to_be_bytes());
+        let (key, nonce) = message_cipher(&message_key);
+        let sealed = backend::gcm_seal(&key, &nonce, &[ad, &message].concat(), plaintext)?;
+        message.extend_from_slice(&sealed);
+
+        self.send_chain = Some(next);
+        self.sent += 1;
+        Ok(message)
+    }
+
+    // Works on a copy, so a forged or replayed message leaves the session as it was
+    pub(crate) fn decrypt(&mut self, ad: &[u8], message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if message.len() < HEADER_LEN + TAG_LEN {
+            return Err(CryptoError::Truncated);
+        }
+        let (header, sealed) = message.split_at(HEADER_LEN);
+        let mut remote = [0_u8; X25519_KEY_LEN];
+        remote.copy_from_slice(&header[..X25519_KEY_LEN]);
+        let previous = u32::from_be_bytes(header[32..36].try_into().unwrap());
+        let index = u32::from_be_bytes(header[36..40].try_into().unwrap());
+
+        let mut next = self.clone();
+        let message_key = next.message_key(remote, previous, index)?;
+        let (key, nonce) = message_cipher(&message_key);
+        let plaintext = backend::gcm_open(&key, &nonce, &[ad, header].concat(), sealed)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn message_key(
+        &mut self,
+        remote: [u8; X25519_KEY_LEN],
+        previous: u32,
+        index: u32,
+    ) -> Result<ChainKey, CryptoError> {
+        if let Some(message_key) = self.skipped.remove(&(remote, index)) {
+            self.skipped_order
+                .retain(|skipped| skipped != &(remote, index));
+            return Ok(message_key);
+        }
+        let remote = PublicKey::from(remote);
+        if self.dh_remote != Some(remote) {
+            // the peer has started a new chain; keep the keys for what's still due on the old one
+            self.skip_to(previous)?;
+            self.dh_step(remote)?;
+        }
+        self.skip_to(index)?;
+        let recv_chain = self.recv_chain.as_ref().ok_or(CryptoError::BadKey)?;
+        let (next, message_key) = kdf_chain(recv_chain);
+        self.recv_chain = Some(next);
+        self.received += 1;
+        Ok(message_key)
+    }
+
+    fn skip_to(&mut self, until: u32) -> Result<(), CryptoError> {
+        let (Some(remote), Some(mut chain)) = (self.dh_remote, self.recv_chain.clone()) else {
+            return Ok(());
+        };
+        if until > self.received.saturating_add(MAX_SKIP) {
+            return Err(CryptoError::TooFarAhead);
+        }
+        while self.received < until {
+            let (next, message_key) = kdf_chain(&chain);
+            let skipped = (*remote.as_bytes(), self.received);
+            self.skipped.insert(skipped, message_key);
+            self.skipped_order.push_back(skipped);
+            if self.skipped_order.len() > MAX_SKIPPED_KEYS {
+                let oldest = self.skipped_order.pop_front().unwrap();
+                self.skipped.remove(&oldest);
+            }
+            chain = next;
+            self.received += 1;
+        }
+        self.recv_chain = Some(chain);
+        Ok(())
+    }
+
+    fn dh_step(&mut self, remote: PublicKey) -> Result<(), CryptoError> {
+        self.previous = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.dh_remote = Some(remote);
+        let (root_key, recv_chain) = kdf_root(&self.root_key, &dh(&self.dh_self, &remote)?);
+        self.dh_self = self.rng.with(|rng| StaticSecret::random_from_rng(rng));
+        let (root_key, send_chain) = kdf_root(&root_key, &dh(&self.dh_self, &remote)?);
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+        self.send_chain = Some(send_chain);
+        Ok(())
+    }
+}
+
+/** An end-to-end encrypted conversation with one other user.
+    Start one with UserIdentity::initiate or UserIdentity::accept. Until the peer has replied,
+    our messages carry the X3DH prelude they need to set up their side, so any of them can be
+    the first to arrive.
+*/
+pub struct RatchetSession {
+    peer: UserPublicKey,
+    // initiator's identity || responder's identity
+    associated_data: Vec<u8>,
+    ratchet: DoubleRatchet,
+    prelude: Option<Vec<u8>>,
+}
+
+impl RatchetSession {
+    pub(crate) fn new(
+        peer: UserPublicKey,
+        associated_data: Vec<u8>,
+        ratchet: DoubleRatchet,
+        prelude: Option<Vec<u8>>,
+    ) -> RatchetSession {
+        RatchetSession {
+            peer,
+            associated_data,
+            ratchet,
+            prelude,
+        }
+    }
+
+    // Who is at the other end; compare its fingerprint with theirs to rule out a middleman
+    pub fn peer(&self) -> &UserPublicKey {
+        &self.peer
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let body = self.ratchet.encrypt(&self.associated_data, plaintext)?;
+        let mut message = Vec::with_capacity(1 + PRELUDE_LEN + body.len());
+        match &self.prelude {
+            Some(prelude) => {
+                message.push(PREKEY_MESSAGE);
+                message.extend_from_slice(prelude);
+            }
+            None => message.push(RATCHET_MESSAGE),
+        }
+        message.extend_from_slice(&body);
+        Ok(message)
+    }
+
+    pub fn decrypt(&mut self, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let body = match message.split_first() {
+            Some((&RATCHET_MESSAGE, body)) => body,
+            Some((&PREKEY_MESSAGE, rest)) if rest.len() >= PRELUDE_LEN => &rest[PRELUDE_LEN..],
+            _ => return Err(CryptoError::Truncated),
+        };
+        let plaintext = self.ratchet.decrypt(&self.associated_data, body)?;
+        // anything from the peer means they've set up their side
+        self.prelude = None;
+        Ok(plaintext)
+    }
+}
+
+// Keys stay out of logs; only say who the session is with
+impl fmt::Debug for RatchetSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RatchetSession")
+            .field("peer", &self.peer.fingerprint())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UserIdentity;
+
+    fn sessions() -> (UserIdentity, RatchetSession, RatchetSession) {
+        let alice = UserIdentity::generate();
+        let mut bob = UserIdentity::generate();
+        let mut to_bob = alice.initiate(&bob.prekey_bundle(1).take_one()).unwrap();
+        let first = to_bob.encrypt(b"hi bob").unwrap();
+        let (to_alice, plaintext) = bob.accept(&first).unwrap();
+        assert_eq!(plaintext, b"hi bob");
+        (alice, to_bob, to_alice)
+    }
+
+    #[test]
+    fn conversations_ratchet_in_both_directions() {
+        let (alice, mut to_bob, mut to_alice) = sessions();
+        assert_eq!(to_alice.peer(), &alice.public_key());
+
+        for round in 0..3 {
+            let reply = to_alice
+                .encrypt(format!("reply {}", round).as_bytes())
+                .unwrap();
+            assert_eq!(
+                to_bob.decrypt(&reply).unwrap(),
+                format!("reply {}", round).as_bytes()
+            );
+            let next = to_bob.encrypt(b"and again").unwrap();
+            // once bob has answered, alice stops sending the X3DH prelude
+            assert_eq!(next[0], RATCHET_MESSAGE);
+            assert_eq!(to_alice.decrypt(&next).unwrap(), b"and again");
+        }
+    }
+
+    #[test]
+    fn late_messages_still_decrypt_but_replays_do_not() {
+        let (_, mut to_bob, mut to_alice) = sessions();
+        let first = to_alice.encrypt(b"one").unwrap();
+        let second = to_alice.encrypt(b"two").unwrap();
+        let new_chain = {
+            assert_eq!(to_bob.decrypt(&second).unwrap(), b"two");
+            to_bob.encrypt(b"ack").unwrap()
+        };
+        to_alice.decrypt(&new_chain).unwrap();
+        let third = to_alice.encrypt(b"three").unwrap();
+
+        assert_eq!(to_bob.decrypt(&third).unwrap(), b"three");
+        assert_eq!(to_bob.decrypt(&first).unwrap(), b"one");
+        assert_eq!(
+            to_bob.decrypt(&first).unwrap_err(),
+            CryptoError::AuthenticationFailed
+        );
+        assert!(to_bob.decrypt(&third).is_err());
+    }
+
+    #[test]
+    fn tampered_messages_leave_the_session_intact() {
+        let (_, mut to_bob, mut to_alice) = sessions();
+        let message = to_alice.encrypt(b"untouched").unwrap();
+
+        // flipping the index in the header, or a ciphertext bit, fails authentication
+        for offset in [1 + HEADER_LEN - 1, message.len() - 1] {
+            let mut forged = message.clone();
+            forged[offset] ^= 1;
+            assert!(to_bob.decrypt(&forged).is_err());
+        }
+        let mut far_ahead = message.clone();
+        far_ahead[1 + 36..1 + 40].copy_from_slice(&(MAX_SKIP + 1).to_be_bytes());
+        assert_eq!(
+            to_bob.decrypt(&far_ahead).unwrap_err(),
+            CryptoError::TooFarAhead
+        );
+        assert_eq!(to_bob.decrypt(&message).unwrap(), b"untouched");
+    }
+
+    #[test]
+    fn skipped_keys_are_capped_across_chains() {
+        let (_, mut to_bob, mut to_alice) = sessions();
+        // each round alice starts a new chain, and only its last message gets through
+        let mut missed = Vec::new();
+        for _ in 0..3 {
+            let mut chain: Vec<_> = (0..MAX_SKIP)
+                .map(|i| to_alice.encrypt(&i.to_be_bytes()).unwrap())
+                .collect();
+            let last = chain.pop().unwrap();
+            assert_eq!(to_bob.decrypt(&last).unwrap(), (MAX_SKIP - 1).to_be_bytes());
+            missed.push(chain);
+            to_alice.decrypt(&to_bob.encrypt(b"ack").unwrap()).unwrap();
+        }
+        let ratchet = &to_bob.ratchet;
+        assert_eq!(ratchet.skipped.len(), MAX_SKIPPED_KEYS);
+        assert_eq!(ratchet.skipped_order.len(), MAX_SKIPPED_KEYS);
+
+        // the first chain's keys made way for the later ones
+        assert!(to_bob.decrypt(&missed[0][0]).is_err());
+        let newest = missed[2].pop().unwrap();
+        assert_eq!(
+            to_bob.decrypt(&newest).unwrap(),
+            (MAX_SKIP - 2).to_be_bytes()
+        );
+        assert_eq!(to_bob.ratchet.skipped.len(), MAX_SKIPPED_KEYS - 1);
+        assert_eq!(to_bob.ratchet.skipped_order.len(), MAX_SKIPPED_KEYS - 1);
+    }
+}
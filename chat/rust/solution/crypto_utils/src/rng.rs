@@ -0,0 +1,28 @@
+use rand::{CryptoRng, RngCore};
+use std::sync::{Arc, Mutex, PoisonError};
+
+// Anything the with_rng constructors accept: a cryptographically secure generator
+pub trait SecureRng: RngCore + CryptoRng {}
+impl<R: RngCore + CryptoRng + ?Sized> SecureRng for R {}
+
+/** Where a session draws its private keys and nonces from.
+    By default that's the thread-local generator; an injected RNG (say, a seeded ChaCha20Rng
+    in tests) is shared between clones, so a session and its clones draw from one stream
+    and a whole conversation can be reproduced byte for byte.
+*/
+#[derive(Clone, Default)]
+pub(crate) struct SessionRng(Option<Arc<Mutex<dyn SecureRng + Send>>>);
+
+impl SessionRng {
+    pub(crate) fn new<R: SecureRng + Send + 'static>(rng: R) -> SessionRng {
+        SessionRng(Some(Arc::new(Mutex::new(rng))))
+    }
+
+    pub(crate) fn with<T>(&self, f: impl FnOnce(&mut dyn SecureRng) -> T) -> T {
+        match &self.0 {
+            // a panic elsewhere can't leave an RNG in a state that matters to us
+            Some(rng) => f(&mut *rng.lock().unwrap_or_else(PoisonError::into_inner)),
+            None => f(&mut rand::thread_rng()),
+        }
+    }
+}
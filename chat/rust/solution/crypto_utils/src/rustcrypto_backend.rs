@@ -0,0 +1,62 @@
+use crate::{CryptoError, KeyBytes};
+use aes::{Aes128, Aes256};
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes128Gcm, Nonce};
+use ecb::cipher::block_padding::Pkcs7;
+use ecb::cipher::{BlockDecryptMut, BlockEncryptMut, KeyInit};
+
+// AES-ECB with PKCS#7 padding; AES-128 for session keys, AES-256 for chat/py's shared secrets
+pub(crate) fn ecb_encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    match key.len() {
+        16 => Ok(ecb::Encryptor::<Aes128>::new_from_slice(key)
+            .map_err(|_| CryptoError::BadKey)?
+            .encrypt_padded_vec_mut::<Pkcs7>(plaintext)),
+        32 => Ok(ecb::Encryptor::<Aes256>::new_from_slice(key)
+            .map_err(|_| CryptoError::BadKey)?
+            .encrypt_padded_vec_mut::<Pkcs7>(plaintext)),
+        _ => Err(CryptoError::BadKey),
+    }
+}
+
+// Inverse of ecb_encrypt; data must already be a whole number of blocks
+pub(crate) fn ecb_decrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let plaintext = match key.len() {
+        16 => ecb::Decryptor::<Aes128>::new_from_slice(key)
+            .map_err(|_| CryptoError::BadKey)?
+            .decrypt_padded_vec_mut::<Pkcs7>(data),
+        32 => ecb::Decryptor::<Aes256>::new_from_slice(key)
+            .map_err(|_| CryptoError::BadKey)?
+            .decrypt_padded_vec_mut::<Pkcs7>(data),
+        _ => return Err(CryptoError::BadKey),
+    };
+    plaintext.map_err(|_| CryptoError::BadPadding)
+}
+
+// AES-128-GCM, authenticating aad alongside the plaintext; returns ciphertext || tag
+pub(crate) fn gcm_seal(
+    key: &KeyBytes,
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let payload = Payload {
+        msg: plaintext,
+        aad,
+    };
+    Aes128Gcm::new(key.into())
+        .encrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| CryptoError::BadKey)
+}
+
+// Inverse of gcm_seal; sealed must be at least a tag long
+pub(crate) fn gcm_open(
+    key: &KeyBytes,
+    nonce: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let payload = Payload { msg: sealed, aad };
+    Aes128Gcm::new(key.into())
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| CryptoError::AuthenticationFailed)
+}
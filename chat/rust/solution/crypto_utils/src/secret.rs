@@ -0,0 +1,46 @@
+use num::BigUint;
+use std::fmt;
+use std::ops::Deref;
+use zeroize::Zeroize;
+
+/** A BigUint private exponent that is wiped when dropped.
+    num has no zeroize support, so we clear it bit by bit, which overwrites its digits in place.
+    Temporaries inside num's own arithmetic (modpow and friends) are out of our reach.
+*/
+#[derive(Clone, Default)]
+pub(crate) struct SecretBigUint(BigUint);
+
+impl SecretBigUint {
+    pub(crate) fn new(value: BigUint) -> SecretBigUint {
+        SecretBigUint(value)
+    }
+}
+
+impl Deref for SecretBigUint {
+    type Target = BigUint;
+
+    fn deref(&self) -> &BigUint {
+        &self.0
+    }
+}
+
+impl Zeroize for SecretBigUint {
+    // Lowest bits first, so the digits are only truncated once they are all zero
+    fn zeroize(&mut self) {
+        for bit in 0..self.0.bits() {
+            self.0.set_bit(bit, false);
+        }
+    }
+}
+
+impl Drop for SecretBigUint {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretBigUint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBigUint(<redacted>)")
+    }
+}
@@ -0,0 +1,421 @@
+/** SRP-6a (RFC 5054's 2048-bit group, over SHA-256) for logging registered users in by
+    password, with the srp crate doing the protocol's arithmetic.
+    The server only ever stores a verifier, g^x mod N where x is hashed from the password after
+    stretching it with PBKDF2, and the password never crosses the wire in any form. Both sides
+    end up with the same session key only if the client knew the password; someone watching,
+    or even pretending to be either side, learns nothing that lets them test password guesses
+    offline, and a stolen verifier still costs a full PBKDF2 run per guess.
+*/
+use crate::{Crypto, CryptoError, PreSharedKey, SecureRng, ServerIdentity};
+use hmac::{Hmac, Mac};
+use num::{BigUint, Zero};
+use sha2::{Digest, Sha256};
+use srp::client::SrpClient as Client;
+use srp::groups::G_2048;
+use srp::server::SrpServer as Server;
+use std::fmt;
+use zeroize::Zeroizing;
+
+pub const SRP_SALT_LEN: usize = 16;
+
+// PBKDF2-HMAC-SHA256 rounds for new verifiers; the count is stored with each one
+pub const SRP_DEFAULT_ITERATIONS: u32 = 100_000;
+
+// More than this and a hostile server could keep a client busy for minutes
+const MAX_ITERATIONS: u32 = 10_000_000;
+
+// The private exponents a and b, well beyond the 112 bits or so the group is worth
+const PRIVATE_LEN: usize = 64;
+
+// Binds the session key to this protocol and to everything both sides saw
+const SESSION_KEY_CONTEXT: &[u8] = b"copilot-chat srp v1";
+
+// Public values and verifiers travel as wide as N
+fn group_len() -> usize {
+    G_2048.n.bits().div_ceil(8) as usize
+}
+
+// The password, stretched with PBKDF2-HMAC-SHA256 before srp hashes it into x
+fn stretch(password: &str, salt: &[u8], iterations: u32) -> Zeroizing<[u8; 32]> {
+    let mut stretched = Zeroizing::new([0_u8; 32]);
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, stretched.as_mut());
+    stretched
+}
+
+// Big-endian, left-padded to the width of N, as RFC 5054's PAD()
+fn pad(bytes: &[u8]) -> Vec<u8> {
+    let mut padded = vec![0_u8; group_len().saturating_sub(bytes.len())];
+    padded.extend_from_slice(bytes);
+    padded
+}
+
+// A peer's public value must be as wide as N and nonzero mod N, or the secret is predictable
+fn check_public(bytes: &[u8]) -> Result<(), CryptoError> {
+    if bytes.len() != group_len() {
+        return Err(CryptoError::BadKey);
+    }
+    if (BigUint::from_bytes_be(bytes) % &G_2048.n).is_zero() {
+        return Err(CryptoError::InvalidPublicKey);
+    }
+    Ok(())
+}
+
+// Drawn from the session's RNG, so a seeded session reproduces its logins too
+fn private_exponent<C: Crypto + ?Sized>(crypto: &C) -> Zeroizing<[u8; PRIVATE_LEN]> {
+    let mut exponent = Zeroizing::new([0_u8; PRIVATE_LEN]);
+    crypto.fill_random(exponent.as_mut());
+    exponent
+}
+
+fn session_key(
+    username: &str,
+    salt: &[u8],
+    client_public: &[u8],
+    server_public: &[u8],
+    premaster: &[u8],
+) -> PreSharedKey {
+    let premaster = Zeroizing::new(pad(premaster));
+    let key = Sha256::new()
+        .chain_update(SESSION_KEY_CONTEXT)
+        .chain_update((username.len() as u64).to_be_bytes())
+        .chain_update(username)
+        .chain_update(salt)
+        .chain_update(client_public)
+        .chain_update(server_public)
+        .chain_update(premaster.as_slice())
+        .finalize();
+    PreSharedKey::new(&key)
+}
+
+/** What the server stores for a registered user: a salt, the PBKDF2 work factor and g^x.
+    It can't be turned back into the password, but it does let someone test guesses at
+    PBKDF2 speed, so keep it as private as a password hash. Debug leaves it out.
+*/
+#[derive(Clone, PartialEq, Eq)]
+pub struct SrpVerifier {
+    salt: [u8; SRP_SALT_LEN],
+    iterations: u32,
+    verifier: Vec<u8>,
+}
+
+impl SrpVerifier {
+    pub fn new(username: &str, password: &str) -> SrpVerifier {
+        Self::with_iterations(username, password, SRP_DEFAULT_ITERATIONS)
+    }
+
+    pub fn with_iterations(username: &str, password: &str, iterations: u32) -> SrpVerifier {
+        Self::generate_with_rng(username, password, iterations, &mut rand::thread_rng())
+    }
+
+    // The salt comes from rng, so a seeded one makes the same verifier every time
+    pub fn generate_with_rng<R: SecureRng + ?Sized>(
+        username: &str,
+        password: &str,
+        iterations: u32,
+        rng: &mut R,
+    ) -> SrpVerifier {
+        let mut salt = [0_u8; SRP_SALT_LEN];
+        rng.fill_bytes(&mut salt);
+        let stretched = stretch(password, &salt, iterations);
+        let verifier = Client::<Sha256>::new(&G_2048).compute_verifier(
+            username.as_bytes(),
+            stretched.as_ref(),
+            &salt,
+        );
+        SrpVerifier {
+            salt,
+            iterations,
+            verifier: pad(&verifier),
+        }
+    }
+
+    /** A stand-in for a username nobody has registered, so a failed login looks the same
+        whether the name exists or not. It's keyed by the server's identity, so its salt
+        stays put between attempts and restarts, just as a real account's would.
+    */
+    pub fn decoy(identity: &ServerIdentity, username: &str) -> SrpVerifier {
+        let digest = Hmac::<Sha256>::new_from_slice(identity.srp_decoy_key().as_ref())
+            .expect("HMAC takes keys of any length")
+            .chain_update(username)
+            .finalize()
+            .into_bytes();
+        let mut salt = [0_u8; SRP_SALT_LEN];
+        salt.copy_from_slice(&digest[..SRP_SALT_LEN]);
+        let verifier = Client::<Sha256>::new(&G_2048).compute_v(&BigUint::from_bytes_be(&digest));
+        SrpVerifier {
+            salt,
+            iterations: SRP_DEFAULT_ITERATIONS,
+            verifier: pad(&verifier.to_bytes_be()),
+        }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub fn iterations(&self) -> u32 {
+        self.iterations
+    }
+
+    // [salt][iterations: u32][verifier, as wide as N]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.salt.to_vec();
+        bytes.extend_from_slice(&self.iterations.to_be_bytes());
+        bytes.extend_from_slice(&self.verifier);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SrpVerifier, CryptoError> {
+        if bytes.len() != SRP_SALT_LEN + 4 + group_len() {
+            return Err(CryptoError::BadKey);
+        }
+        let (salt, rest) = bytes.split_at(SRP_SALT_LEN);
+        let (iterations, verifier) = rest.split_at(4);
+        let iterations = u32::from_be_bytes(iterations.try_into().unwrap());
+        if iterations == 0 || iterations > MAX_ITERATIONS {
+            return Err(CryptoError::BadKey);
+        }
+        check_public(verifier)?;
+        Ok(SrpVerifier {
+            salt: salt.try_into().unwrap(),
+            iterations,
+            verifier: verifier.to_vec(),
+        })
+    }
+}
+
+impl fmt::Debug for SrpVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SrpVerifier")
+            .field("iterations", &self.iterations)
+            .finish_non_exhaustive()
+    }
+}
+
+// The client's half of one login: it sends public_key(), and gets back the salt and B
+pub struct SrpClient {
+    username: String,
+    password: Zeroizing<String>,
+    a: Zeroizing<[u8; PRIVATE_LEN]>,
+    public_key: Vec<u8>,
+}
+
+impl SrpClient {
+    pub fn new<C: Crypto + ?Sized>(crypto: &C, username: &str, password: &str) -> SrpClient {
+        let a = private_exponent(crypto);
+        let public_key = Client::<Sha256>::new(&G_2048).compute_public_ephemeral(a.as_ref());
+        SrpClient {
+            username: username.to_string(),
+            password: Zeroizing::new(password.to_string()),
+            a,
+            public_key: pad(&public_key),
+        }
+    }
+
+    // A = g^a mod N
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.clone()
+    }
+
+    // S = (B - k * g^x)^(a + u * x) mod N, hashed with the transcript
+    pub fn session_key(
+        &self,
+        salt: &[u8],
+        iterations: u32,
+        server_public: &[u8],
+    ) -> Result<PreSharedKey, CryptoError> {
+        if iterations == 0 || iterations > MAX_ITERATIONS {
+            return Err(CryptoError::BadKey);
+        }
+        check_public(server_public)?;
+        let stretched = stretch(&self.password, salt, iterations);
+        let premaster = Client::<Sha256>::new(&G_2048)
+            .process_reply(
+                self.a.as_ref(),
+                self.username.as_bytes(),
+                stretched.as_ref(),
+                salt,
+                server_public,
+            )
+            .map_err(|_| CryptoError::InvalidPublicKey)?;
+        Ok(session_key(
+            &self.username,
+            salt,
+            &self.public_key,
+            server_public,
+            premaster.key(),
+        ))
+    }
+}
+
+impl fmt::Debug for SrpClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SrpClient")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+// The server's half of one login, for the account whose verifier it was given
+pub struct SrpServer {
+    username: String,
+    verifier: SrpVerifier,
+    b: Zeroizing<[u8; PRIVATE_LEN]>,
+    public_key: Vec<u8>,
+}
+
+impl SrpServer {
+    pub fn new<C: Crypto + ?Sized>(
+        crypto: &C,
+        username: &str,
+        verifier: &SrpVerifier,
+    ) -> SrpServer {
+        let b = private_exponent(crypto);
+        let public_key =
+            Server::<Sha256>::new(&G_2048).compute_public_ephemeral(b.as_ref(), &verifier.verifier);
+        SrpServer {
+            username: username.to_string(),
+            verifier: verifier.clone(),
+            b,
+            public_key: pad(&public_key),
+        }
+    }
+
+    // B = k * v + g^b mod N
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.clone()
+    }
+
+    // S = (A * v^u)^b mod N, hashed with the transcript
+    pub fn session_key(&self, client_public: &[u8]) -> Result<PreSharedKey, CryptoError> {
+        check_public(client_public)?;
+        let premaster = Server::<Sha256>::new(&G_2048)
+            .process_reply(self.b.as_ref(), &self.verifier.verifier, client_public)
+            .map_err(|_| CryptoError::InvalidPublicKey)?;
+        Ok(session_key(
+            &self.username,
+            &self.verifier.salt,
+            client_public,
+            &self.public_key,
+            premaster.key(),
+        ))
+    }
+}
+
+impl fmt::Debug for SrpServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SrpServer")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::X25519DiffieHellman;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    // Full-strength PBKDF2 is the point in production and just slow here
+    const TEST_ITERATIONS: u32 = 10;
+
+    fn login(
+        verifier: &SrpVerifier,
+        username: &str,
+        password: &str,
+    ) -> (PreSharedKey, Result<PreSharedKey, CryptoError>) {
+        let crypto = X25519DiffieHellman::new();
+        let client = SrpClient::new(&crypto, username, password);
+        let server = SrpServer::new(&crypto, username, verifier);
+        let server_key = server.session_key(&client.public_key()).unwrap();
+        let client_key =
+            client.session_key(verifier.salt(), verifier.iterations(), &server.public_key());
+        (server_key, client_key)
+    }
+
+    #[test]
+    fn only_the_right_password_agrees_on_a_key() {
+        let verifier = SrpVerifier::with_iterations("alice", "hunter2", TEST_ITERATIONS);
+        let (server_key, client_key) = login(&verifier, "alice", "hunter2");
+        assert_eq!(server_key.as_bytes(), client_key.unwrap().as_bytes());
+
+        let (server_key, client_key) = login(&verifier, "alice", "hunter3");
+        assert_ne!(server_key.as_bytes(), client_key.unwrap().as_bytes());
+
+        // the private values come from the session's RNG, so seeded ones repeat
+        let seeded = || X25519DiffieHellman::with_rng(ChaCha20Rng::seed_from_u64(9));
+        assert_eq!(
+            SrpServer::new(&seeded(), "alice", &verifier).public_key(),
+            SrpServer::new(&seeded(), "alice", &verifier).public_key()
+        );
+    }
+
+    #[test]
+    fn degenerate_public_keys_are_refused() {
+        let verifier = SrpVerifier::with_iterations("alice", "hunter2", TEST_ITERATIONS);
+        let crypto = X25519DiffieHellman::new();
+        let server = SrpServer::new(&crypto, "alice", &verifier);
+        let client = SrpClient::new(&crypto, "alice", "hunter2");
+        // A = 0 or N would make the server's secret 0 without any password at all
+        for degenerate in [BigUint::zero(), G_2048.n.clone()] {
+            assert_eq!(
+                server
+                    .session_key(&pad(&degenerate.to_bytes_be()))
+                    .unwrap_err(),
+                CryptoError::InvalidPublicKey
+            );
+            assert_eq!(
+                client
+                    .session_key(
+                        verifier.salt(),
+                        TEST_ITERATIONS,
+                        &pad(&degenerate.to_bytes_be())
+                    )
+                    .unwrap_err(),
+                CryptoError::InvalidPublicKey
+            );
+        }
+        assert_eq!(
+            server.session_key(&[1, 2, 3]).unwrap_err(),
+            CryptoError::BadKey
+        );
+    }
+
+    #[test]
+    fn verifiers_round_trip_and_decoys_are_stable() {
+        let verifier = SrpVerifier::with_iterations("alice", "hunter2", TEST_ITERATIONS);
+        let parsed = SrpVerifier::from_bytes(&verifier.to_bytes()).unwrap();
+        assert_eq!(parsed, verifier);
+        assert_eq!(
+            SrpVerifier::from_bytes(&verifier.to_bytes()[1..]).unwrap_err(),
+            CryptoError::BadKey
+        );
+        assert!(!format!("{:?}", verifier).contains("verifier:"));
+
+        let seeded = |seed| {
+            SrpVerifier::generate_with_rng(
+                "alice",
+                "hunter2",
+                TEST_ITERATIONS,
+                &mut ChaCha20Rng::seed_from_u64(seed),
+            )
+        };
+        assert_eq!(seeded(5), seeded(5));
+        assert_ne!(seeded(5), seeded(6));
+
+        // the same identity, as a restarted server would load it, gives the same decoys
+        let identity =
+            |seed| ServerIdentity::generate_with_rng(&mut ChaCha20Rng::seed_from_u64(seed));
+        let decoy = SrpVerifier::decoy(&identity(7), "mallory");
+        assert_eq!(SrpVerifier::decoy(&identity(7), "mallory"), decoy);
+        assert_ne!(
+            SrpVerifier::decoy(&identity(7), "trudy").salt(),
+            decoy.salt()
+        );
+        assert_ne!(
+            SrpVerifier::decoy(&identity(8), "mallory").salt(),
+            decoy.salt()
+        );
+    }
+}
@@ -0,0 +1,300 @@
+/** Incremental encryption for payloads too big to hold in memory, such as files or logs.
+    Crypto::encrypt seals a whole message at once; these wrap a Write or Read instead and seal
+    the data in STREAM_CHUNK_LEN pieces, each with its own authentication tag.
+
+    The stream starts with a random salt from Crypto::fill_random, then one frame per chunk:
+        [last: u8][length: u32 big-endian][AES-128-GCM ciphertext || tag]
+    Every stream gets a fresh key from Crypto::stream_key(salt, context), and each chunk's nonce
+    is its index plus the last flag, so chunks that are reordered, dropped, replayed from another
+    stream or relabelled as the end all fail to authenticate. A stream cut short before its last
+    chunk is an UnexpectedEof error rather than a quietly shorter payload.
+*/
+use crate::aead::{NONCE_LEN, TAG_LEN};
+use crate::{backend, Crypto, KeyBytes};
+use std::io::{self, ErrorKind, Read, Write};
+use zeroize::Zeroizing;
+
+// Plaintext bytes per chunk, and so roughly the memory either end needs per stream
+pub const STREAM_CHUNK_LEN: usize = 64 * 1024;
+
+// Random per-stream salt that leads the stream, so no two streams share a key
+pub const STREAM_SALT_LEN: usize = 32;
+
+// last flag + length
+const FRAME_HEADER_LEN: usize = 5;
+
+// The chunk index in the first 11 bytes, then whether it's the last chunk
+fn chunk_nonce(index: u64, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0_u8; NONCE_LEN];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/** Encrypts everything written to it onto the inner writer.
+    Call finish() once the payload is written: it seals the last chunk, and without it the
+    reader sees a truncated stream.
+*/
+pub struct StreamEncryptor<W: Write> {
+    writer: W,
+    key: Zeroizing<KeyBytes>,
+    index: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> StreamEncryptor<W> {
+    // context must match the decryptor's; use it to tie the stream to where it's being sent
+    pub fn new<C: Crypto + ?Sized>(crypto: &C, context: &[u8], mut writer: W) -> io::Result<Self> {
+        let mut salt = [0_u8; STREAM_SALT_LEN];
+        crypto.fill_random(&mut salt);
+        let key = crypto.stream_key(&salt, context)?;
+        writer.write_all(&salt)?;
+        Ok(StreamEncryptor {
+            writer,
+            key,
+            index: 0,
+            buffer: Vec::with_capacity(STREAM_CHUNK_LEN),
+        })
+    }
+
+    // Seal whatever is buffered as the last chunk, and hand back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal_chunk(true)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        let sealed =
+            backend::gcm_seal(&self.key, &chunk_nonce(self.index, last), &[], &self.buffer)?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + sealed.len());
+        frame.push(last as u8);
+        frame.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&sealed);
+        self.writer.write_all(&frame)?;
+        self.index += 1;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(STREAM_CHUNK_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == STREAM_CHUNK_LEN {
+            self.seal_chunk(false)?;
+        }
+        Ok(n)
+    }
+
+    // Only flushes the inner writer: a partly filled chunk waits for more data or finish()
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/** Decrypts a stream written by StreamEncryptor, one chunk at a time.
+    Reads return 0 once the last chunk has been consumed, and the inner reader is left just
+    past the end of the stream. Data is only returned once its chunk has authenticated, but
+    an error part way through means the payload read so far is incomplete.
+*/
+pub struct StreamDecryptor<R: Read> {
+    reader: R,
+    key: Zeroizing<KeyBytes>,
+    index: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> StreamDecryptor<R> {
+    // Reads the salt straight away, so a missing stream is noticed here
+    pub fn new<C: Crypto + ?Sized>(crypto: &C, context: &[u8], mut reader: R) -> io::Result<Self> {
+        let mut salt = [0_u8; STREAM_SALT_LEN];
+        reader.read_exact(&mut salt)?;
+        let key = crypto.stream_key(&salt, context)?;
+        Ok(StreamDecryptor {
+            reader,
+            key,
+            index: 0,
+            chunk: Vec::new(),
+            pos: 0,
+            finished: false,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn open_chunk(&mut self) -> io::Result<()> {
+        let mut header = [0_u8; FRAME_HEADER_LEN];
+        self.reader.read_exact(&mut header).map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "stream ended before its last chunk",
+                )
+            } else {
+                e
+            }
+        })?;
+        let last = match header[0] {
+            0 => false,
+            1 => true,
+            flag => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("bad stream chunk flag {}", flag),
+                ))
+            }
+        };
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if !(TAG_LEN..=STREAM_CHUNK_LEN + TAG_LEN).contains(&len) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("stream chunk of {} bytes is out of range", len),
+            ));
+        }
+        let mut sealed = vec![0_u8; len];
+        self.reader.read_exact(&mut sealed)?;
+        self.chunk = backend::gcm_open(&self.key, &chunk_nonce(self.index, last), &[], &sealed)?;
+        self.pos = 0;
+        self.index += 1;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // loop, because a sender may legitimately emit an empty last chunk
+        while self.pos == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::X25519DiffieHellman;
+    use rand_chacha::rand_core::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn session() -> X25519DiffieHellman {
+        session_with(X25519DiffieHellman::new(), X25519DiffieHellman::new())
+    }
+
+    fn session_with(
+        mut alice: X25519DiffieHellman,
+        mut bob: X25519DiffieHellman,
+    ) -> X25519DiffieHellman {
+        let alice_pub = alice.init_keys();
+        let bob_pub = bob.init_keys();
+        alice.handshake(&bob_pub).unwrap();
+        bob.handshake(&alice_pub).unwrap();
+        alice
+    }
+
+    fn encrypt(crypto: &X25519DiffieHellman, payload: &[u8]) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::new(crypto, b"test", Vec::new()).unwrap();
+        encryptor.write_all(payload).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(crypto: &X25519DiffieHellman, context: &[u8], stream: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decryptor = StreamDecryptor::new(crypto, context, stream)?;
+        let mut payload = Vec::new();
+        decryptor.read_to_end(&mut payload)?;
+        Ok(payload)
+    }
+
+    // Offset of chunk n's frame in a stream of full chunks
+    fn frame_offset(n: usize) -> usize {
+        STREAM_SALT_LEN + n * (FRAME_HEADER_LEN + STREAM_CHUNK_LEN + TAG_LEN)
+    }
+
+    #[test]
+    fn payloads_round_trip_across_chunk_boundaries() {
+        let crypto = session();
+        for len in [
+            0,
+            1,
+            STREAM_CHUNK_LEN - 1,
+            STREAM_CHUNK_LEN,
+            3 * STREAM_CHUNK_LEN + 5,
+        ] {
+            let payload: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let stream = encrypt(&crypto, &payload);
+            assert_eq!(decrypt(&crypto, b"test", &stream).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn salts_come_from_the_session_rng() {
+        let seeded = || {
+            let rng = |seed| X25519DiffieHellman::with_rng(ChaCha20Rng::seed_from_u64(seed));
+            session_with(rng(1), rng(2))
+        };
+        let crypto = seeded();
+        let first = encrypt(&crypto, b"payload");
+        let second = encrypt(&crypto, b"payload");
+        assert_ne!(first[..STREAM_SALT_LEN], second[..STREAM_SALT_LEN]);
+
+        let replayed = seeded();
+        assert_eq!(encrypt(&replayed, b"payload"), first);
+        assert_eq!(encrypt(&replayed, b"payload"), second);
+    }
+
+    #[test]
+    fn decryptor_stops_at_the_end_of_the_stream() {
+        let crypto = session();
+        let mut stream = encrypt(&crypto, b"first");
+        stream.extend_from_slice(b"trailing");
+        let mut reader = &stream[..];
+        let mut decryptor = StreamDecryptor::new(&crypto, b"test", &mut reader).unwrap();
+        let mut payload = Vec::new();
+        decryptor.read_to_end(&mut payload).unwrap();
+        assert_eq!(payload, b"first");
+        assert_eq!(reader, b"trailing");
+    }
+
+    #[test]
+    fn tampered_truncated_and_reordered_streams_are_rejected() {
+        let crypto = session();
+        let payload = vec![7_u8; 2 * STREAM_CHUNK_LEN + 10];
+        let stream = encrypt(&crypto, &payload);
+
+        let mut flipped = stream.clone();
+        flipped[frame_offset(1) + FRAME_HEADER_LEN] ^= 1;
+        let err = decrypt(&crypto, b"test", &flipped).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // dropping the last chunk
+        let err = decrypt(&crypto, b"test", &stream[..frame_offset(2)]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        // marking a middle chunk as the last one
+        let mut relabelled = stream[..frame_offset(1)].to_vec();
+        relabelled[frame_offset(0)] = 1;
+        assert!(decrypt(&crypto, b"test", &relabelled).is_err());
+
+        let mut swapped = stream[..STREAM_SALT_LEN].to_vec();
+        swapped.extend_from_slice(&stream[frame_offset(1)..frame_offset(2)]);
+        swapped.extend_from_slice(&stream[frame_offset(0)..frame_offset(1)]);
+        swapped.extend_from_slice(&stream[frame_offset(2)..]);
+        assert!(decrypt(&crypto, b"test", &swapped).is_err());
+
+        // a stream sent for one context can't be accepted for another
+        assert!(decrypt(&crypto, b"elsewhere", &stream).is_err());
+    }
+}
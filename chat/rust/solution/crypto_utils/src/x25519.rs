@@ -0,0 +1,114 @@
+use crate::rng::SessionRng;
+use crate::{
+    aead, derive_key, next_key, psk_key, stream_key, Crypto, CryptoError, KeyBytes, PreSharedKey,
+    SecureRng,
+};
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+// X25519 public keys and shared secrets are always exactly this long
+pub(crate) const X25519_KEY_LEN: usize = 32;
+
+impl Crypto for X25519DiffieHellman {
+    type PublicKey = PublicKey;
+
+    fn init_keys(&mut self) -> Vec<u8> {
+        let priv_key = self.rng.with(|rng| StaticSecret::random_from_rng(rng));
+        let pub_key = PublicKey::from(&priv_key);
+        self.priv_key = Some(priv_key);
+        self.serialize(&pub_key)
+    }
+
+    // You must call init_keys() before handshake(). Low-order peer keys are rejected.
+    fn handshake(&mut self, other_pub_key: &[u8]) -> Result<(), CryptoError> {
+        let other_pub_key = self.deserialize(other_pub_key)?;
+        let priv_key = self.priv_key.as_ref().ok_or(CryptoError::BadKey)?;
+        let shared_secret = priv_key.diffie_hellman(&other_pub_key);
+        // a low-order peer point yields the all-zero secret no matter what our key is
+        if !shared_secret.was_contributory() {
+            return Err(CryptoError::InvalidPublicKey);
+        }
+        self.key = derive_key(shared_secret.as_bytes());
+        Ok(())
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        aead::seal(&self.key, &self.rng, plaintext)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        aead::open(&self.key, data)
+    }
+
+    fn rekey(&mut self) -> Result<(), CryptoError> {
+        self.key = next_key(&self.key);
+        Ok(())
+    }
+
+    fn mix_psk(&mut self, psk: &PreSharedKey) -> Result<(), CryptoError> {
+        self.key = psk_key(&self.key, psk);
+        Ok(())
+    }
+
+    fn stream_key(&self, salt: &[u8], context: &[u8]) -> Result<Zeroizing<KeyBytes>, CryptoError> {
+        Ok(stream_key(self.key.as_slice(), salt, context))
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        self.rng.with(|rng| rng.fill_bytes(buf))
+    }
+
+    fn serialize(&self, pub_key: &PublicKey) -> Vec<u8> {
+        pub_key.as_bytes().to_vec()
+    }
+
+    fn deserialize(&self, pub_key: &[u8]) -> Result<PublicKey, CryptoError> {
+        let bytes: [u8; X25519_KEY_LEN] = pub_key.try_into().map_err(|_| CryptoError::BadKey)?;
+        Ok(PublicKey::from(bytes))
+    }
+}
+
+/** Elliptic-curve Diffie-Hellman over Curve25519, feeding AES-128-GCM.
+    Public keys are a fixed 32 bytes and the handshake is much cheaper
+    than a 2048-bit modular exponentiation.
+*/
+#[derive(Clone)]
+pub struct X25519DiffieHellman {
+    key: Zeroizing<KeyBytes>,
+    priv_key: Option<StaticSecret>,
+    rng: SessionRng,
+}
+
+impl X25519DiffieHellman {
+    pub fn new() -> X25519DiffieHellman {
+        Self::with_session_rng(SessionRng::default())
+    }
+
+    // Private keys and nonces both come from rng
+    pub fn with_rng<R: SecureRng + Send + 'static>(rng: R) -> X25519DiffieHellman {
+        Self::with_session_rng(SessionRng::new(rng))
+    }
+
+    fn with_session_rng(rng: SessionRng) -> X25519DiffieHellman {
+        X25519DiffieHellman {
+            key: Zeroizing::new([0_u8; 16]),
+            // StaticSecret wipes itself on drop
+            priv_key: None,
+            rng,
+        }
+    }
+}
+
+impl Default for X25519DiffieHellman {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for X25519DiffieHellman {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("X25519DiffieHellman")
+            .finish_non_exhaustive()
+    }
+}
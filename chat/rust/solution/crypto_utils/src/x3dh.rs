@@ -0,0 +1,504 @@
+/** X3DH-style key agreement for end-to-end direct messages between chat users.
+    Each user publishes a PrekeyBundle through the server: their identity, a signed prekey and a
+    batch of one-time prekeys. Anyone who wants to write to them takes a bundle (using up one of
+    the one-time prekeys) and can start a RatchetSession straight away, without the recipient
+    having to be online; the recipient finishes the agreement with accept() when the first
+    message arrives. The server relays bundles and messages but can read neither. It could hand
+    out a bundle of its own instead, though, so users should compare fingerprints out of band.
+*/
+use crate::identity::{load_secret, save_secret};
+use crate::ratchet::{dh, DoubleRatchet, RatchetSession, PREKEY_MESSAGE};
+use crate::rng::SessionRng;
+use crate::x25519::X25519_KEY_LEN;
+use crate::{CryptoError, SecureRng};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+// HKDF context string for the secret X3DH hands to the ratchet
+const X3DH_INFO: &[u8] = b"copilot-chat x3dh v1";
+
+// Domain separation, so a prekey signature can't be passed off as a handshake signature
+const PREKEY_SIG_CONTEXT: &[u8] = b"copilot-chat signed prekey v1";
+
+const SIGNATURE_LEN: usize = 64;
+
+// Ed25519 signing key || X25519 identity key
+const USER_KEY_LEN: usize = 64;
+
+// What a key file holds: Ed25519 seed || X25519 identity key || signed prekey
+const IDENTITY_FILE_LEN: usize = 96;
+
+// What the initiator's messages carry until the peer answers:
+// [initiator's identity][ephemeral key][one-time prekey used: u8][its id: u32]
+pub(crate) const PRELUDE_LEN: usize = USER_KEY_LEN + X25519_KEY_LEN + 5;
+
+/** One user's long-term keys for end-to-end messages, and the private halves of their prekeys.
+    The Ed25519 key signs the prekeys; the X25519 key takes part in the agreement itself.
+*/
+pub struct UserIdentity {
+    signing_key: SigningKey,
+    // StaticSecret wipes itself on drop
+    dh_key: StaticSecret,
+    signed_prekey: StaticSecret,
+    prekey_signature: Signature,
+    one_time_prekeys: HashMap<u32, StaticSecret>,
+    next_prekey_id: u32,
+    rng: SessionRng,
+}
+
+impl UserIdentity {
+    pub fn generate() -> UserIdentity {
+        Self::with_session_rng(SessionRng::default())
+    }
+
+    // Identity, prekeys and every ratchet key of every session come from rng
+    pub fn generate_with_rng<R: SecureRng + Send + 'static>(rng: R) -> UserIdentity {
+        Self::with_session_rng(SessionRng::new(rng))
+    }
+
+    fn with_session_rng(rng: SessionRng) -> UserIdentity {
+        let mut seed = Zeroizing::new([0_u8; 32]);
+        rng.with(|rng| rng.fill_bytes(seed.as_mut()));
+        let dh_key = rng.with(|rng| StaticSecret::random_from_rng(rng));
+        let signed_prekey = rng.with(|rng| StaticSecret::random_from_rng(rng));
+        Self::from_keys(&seed, dh_key, signed_prekey, rng)
+    }
+
+    fn from_keys(
+        seed: &[u8; 32],
+        dh_key: StaticSecret,
+        signed_prekey: StaticSecret,
+        rng: SessionRng,
+    ) -> UserIdentity {
+        let signing_key = SigningKey::from_bytes(seed);
+        let prekey_signature = signing_key.sign(&prekey_message(
+            &PublicKey::from(&dh_key),
+            &PublicKey::from(&signed_prekey),
+        ));
+        UserIdentity {
+            signing_key,
+            dh_key,
+            signed_prekey,
+            prekey_signature,
+            one_time_prekeys: HashMap::new(),
+            next_prekey_id: 0,
+            rng,
+        }
+    }
+
+    /** The key file holds the long-term keys as a single line of hex, so the fingerprint
+        peers have checked survives a restart. One-time prekeys aren't kept: each run publishes
+        a fresh batch.
+    */
+    pub fn load(path: &Path) -> io::Result<UserIdentity> {
+        let secrets = load_secret::<IDENTITY_FILE_LEN>(path)?;
+        let key = |range: std::ops::Range<usize>| {
+            let mut key = Zeroizing::new([0_u8; 32]);
+            key.copy_from_slice(&secrets[range]);
+            key
+        };
+        Ok(Self::from_keys(
+            &key(0..32),
+            StaticSecret::from(*key(32..64)),
+            StaticSecret::from(*key(64..96)),
+            SessionRng::default(),
+        ))
+    }
+
+    // Refuses to overwrite an existing file; on unix the file is only readable by its owner
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut secrets = Zeroizing::new([0_u8; IDENTITY_FILE_LEN]);
+        secrets[..32].copy_from_slice(self.signing_key.as_bytes());
+        secrets[32..64].copy_from_slice(self.dh_key.as_bytes());
+        secrets[64..].copy_from_slice(self.signed_prekey.as_bytes());
+        save_secret(path, secrets.as_slice())
+    }
+
+    pub fn load_or_generate(path: &Path) -> io::Result<UserIdentity> {
+        if path.exists() {
+            return Self::load(path);
+        }
+        let identity = Self::generate();
+        identity.save(path)?;
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> UserPublicKey {
+        UserPublicKey {
+            signing: self.signing_key.verifying_key(),
+            dh: PublicKey::from(&self.dh_key),
+        }
+    }
+
+    // A bundle to publish with `count` fresh one-time prekeys; earlier ones stay valid until used
+    pub fn prekey_bundle(&mut self, count: usize) -> PrekeyBundle {
+        let mut one_time_prekeys = Vec::with_capacity(count);
+        for _ in 0..count {
+            let id = self.next_prekey_id;
+            self.next_prekey_id += 1;
+            let prekey = self.rng.with(|rng| StaticSecret::random_from_rng(rng));
+            one_time_prekeys.push((id, PublicKey::from(&prekey)));
+            self.one_time_prekeys.insert(id, prekey);
+        }
+        PrekeyBundle {
+            identity: self.public_key(),
+            signed_prekey: PublicKey::from(&self.signed_prekey),
+            signature: self.prekey_signature,
+            one_time_prekeys,
+        }
+    }
+
+    // Start a session with whoever published `bundle`, using its first one-time prekey if any
+    pub fn initiate(&self, bundle: &PrekeyBundle) -> Result<RatchetSession, CryptoError> {
+        let ephemeral = self.rng.with(|rng| StaticSecret::random_from_rng(rng));
+        let one_time = bundle.one_time_prekeys.first();
+
+        let mut secrets = x3dh_input();
+        secrets.extend_from_slice(dh(&self.dh_key, &bundle.signed_prekey)?.as_bytes());
+        secrets.extend_from_slice(dh(&ephemeral, &bundle.identity.dh)?.as_bytes());
+        secrets.extend_from_slice(dh(&ephemeral, &bundle.signed_prekey)?.as_bytes());
+        if let Some((_, prekey)) = one_time {
+            secrets.extend_from_slice(dh(&ephemeral, prekey)?.as_bytes());
+        }
+        let ratchet = DoubleRatchet::initiator(
+            x3dh_secret(&secrets),
+            bundle.signed_prekey,
+            self.rng.clone(),
+        )?;
+
+        let mut prelude = Vec::with_capacity(PRELUDE_LEN);
+        prelude.extend_from_slice(&self.public_key().to_bytes());
+        prelude.extend_from_slice(PublicKey::from(&ephemeral).as_bytes());
+        prelude.push(one_time.is_some() as u8);
+        prelude.extend_from_slice(&one_time.map_or(0, |(id, _)| *id).to_be_bytes());
+
+        let associated_data = [self.public_key().to_bytes(), bundle.identity.to_bytes()].concat();
+        Ok(RatchetSession::new(
+            bundle.identity,
+            associated_data,
+            ratchet,
+            Some(prelude),
+        ))
+    }
+
+    /** Set up our side of a session from the first message the initiator sent us,
+        returning it along with that message's plaintext.
+        The one-time prekey it used is only spent once the message has decrypted.
+    */
+    pub fn accept(&mut self, message: &[u8]) -> Result<(RatchetSession, Vec<u8>), CryptoError> {
+        let prelude = match message.split_first() {
+            Some((&PREKEY_MESSAGE, rest)) if rest.len() >= PRELUDE_LEN => &rest[..PRELUDE_LEN],
+            _ => return Err(CryptoError::Truncated),
+        };
+        let peer = UserPublicKey::from_bytes(&prelude[..USER_KEY_LEN])?;
+        let ephemeral = public_key(&prelude[USER_KEY_LEN..USER_KEY_LEN + X25519_KEY_LEN])?;
+        let one_time_id = match prelude[USER_KEY_LEN + X25519_KEY_LEN] {
+            0 => None,
+            1 => Some(u32::from_be_bytes(
+                prelude[PRELUDE_LEN - 4..].try_into().unwrap(),
+            )),
+            _ => return Err(CryptoError::Truncated),
+        };
+
+        let mut secrets = x3dh_input();
+        secrets.extend_from_slice(dh(&self.signed_prekey, &peer.dh)?.as_bytes());
+        secrets.extend_from_slice(dh(&self.dh_key, &ephemeral)?.as_bytes());
+        secrets.extend_from_slice(dh(&self.signed_prekey, &ephemeral)?.as_bytes());
+        if let Some(id) = one_time_id {
+            // already used, or never ours: a replayed first message ends up here
+            let prekey = self.one_time_prekeys.get(&id).ok_or(CryptoError::BadKey)?;
+            secrets.extend_from_slice(dh(prekey, &ephemeral)?.as_bytes());
+        }
+        let ratchet = DoubleRatchet::responder(
+            x3dh_secret(&secrets),
+            self.signed_prekey.clone(),
+            self.rng.clone(),
+        );
+
+        let associated_data = [peer.to_bytes(), self.public_key().to_bytes()].concat();
+        let mut session = RatchetSession::new(peer, associated_data, ratchet, None);
+        let plaintext = session.decrypt(message)?;
+        if let Some(id) = one_time_id {
+            self.one_time_prekeys.remove(&id);
+        }
+        Ok((session, plaintext))
+    }
+}
+
+impl fmt::Debug for UserIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserIdentity")
+            .field("public_key", &self.public_key().fingerprint())
+            .finish_non_exhaustive()
+    }
+}
+
+/** The initiator's ephemeral key, if `message` is one of the first messages of a session.
+    A fresh one comes with every session, so it tells a replayed first message from a new one.
+*/
+pub fn first_message_ephemeral(message: &[u8]) -> Option<[u8; X25519_KEY_LEN]> {
+    match message.split_first() {
+        Some((&PREKEY_MESSAGE, rest)) if rest.len() >= PRELUDE_LEN => rest
+            [USER_KEY_LEN..USER_KEY_LEN + X25519_KEY_LEN]
+            .try_into()
+            .ok(),
+        _ => None,
+    }
+}
+
+// Sized up front so growing it never leaves a stray copy of the secrets behind
+fn x3dh_input() -> Zeroizing<Vec<u8>> {
+    let mut secrets = Zeroizing::new(Vec::with_capacity(5 * X25519_KEY_LEN));
+    secrets.extend_from_slice(&[0xFF_u8; X25519_KEY_LEN]);
+    secrets
+}
+
+// Leading 0xFF bytes keep the input from ever looking like a single X25519 output, as in X3DH
+fn x3dh_secret(secrets: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut secret = Zeroizing::new([0_u8; 32]);
+    Hkdf::<Sha256>::new(Some(&[0_u8; 32]), secrets)
+        .expand(X3DH_INFO, secret.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    secret
+}
+
+fn public_key(bytes: &[u8]) -> Result<PublicKey, CryptoError> {
+    let bytes: [u8; X25519_KEY_LEN] = bytes.try_into().map_err(|_| CryptoError::BadKey)?;
+    Ok(PublicKey::from(bytes))
+}
+
+fn prekey_message(identity: &PublicKey, signed_prekey: &PublicKey) -> Vec<u8> {
+    [
+        PREKEY_SIG_CONTEXT,
+        identity.as_bytes(),
+        signed_prekey.as_bytes(),
+    ]
+    .concat()
+}
+
+// What other users know us by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UserPublicKey {
+    signing: VerifyingKey,
+    dh: PublicKey,
+}
+
+impl UserPublicKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<UserPublicKey, CryptoError> {
+        if bytes.len() != USER_KEY_LEN {
+            return Err(CryptoError::BadKey);
+        }
+        let signing: &[u8; 32] = bytes[..32].try_into().unwrap();
+        Ok(UserPublicKey {
+            signing: VerifyingKey::from_bytes(signing).map_err(|_| CryptoError::BadKey)?,
+            dh: public_key(&bytes[32..])?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; USER_KEY_LEN] {
+        let mut bytes = [0_u8; USER_KEY_LEN];
+        bytes[..32].copy_from_slice(self.signing.as_bytes());
+        bytes[32..].copy_from_slice(self.dh.as_bytes());
+        bytes
+    }
+
+    // Same form as ServerPublicKey::fingerprint, for reading out to the other person
+    pub fn fingerprint(&self) -> String {
+        format!("SHA256:{}", hex::encode(Sha256::digest(self.to_bytes())))
+    }
+}
+
+/** What a user publishes so others can start sessions with them while they're away.
+    Parsing a bundle checks its signature, so every PrekeyBundle value is one its
+    identity really signed; that says nothing about whether the identity is who it claims.
+*/
+#[derive(Clone, Debug)]
+pub struct PrekeyBundle {
+    identity: UserPublicKey,
+    signed_prekey: PublicKey,
+    signature: Signature,
+    one_time_prekeys: Vec<(u32, PublicKey)>,
+}
+
+impl PrekeyBundle {
+    pub fn identity(&self) -> &UserPublicKey {
+        &self.identity
+    }
+
+    pub fn one_time_prekeys(&self) -> usize {
+        self.one_time_prekeys.len()
+    }
+
+    /** The bundle to give one initiator, with at most one one-time prekey, which is removed from
+        this one so nobody else gets it. With none left, sessions still work, just without the
+        extra protection for their first messages.
+    */
+    pub fn take_one(&mut self) -> PrekeyBundle {
+        let one_time_prekeys = if self.one_time_prekeys.is_empty() {
+            Vec::new()
+        } else {
+            vec![self.one_time_prekeys.remove(0)]
+        };
+        PrekeyBundle {
+            one_time_prekeys,
+            ..self.clone()
+        }
+    }
+
+    // [identity][signed prekey][signature][count: u16]([id: u32][one-time prekey])*
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.identity.to_bytes());
+        bytes.extend_from_slice(self.signed_prekey.as_bytes());
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        bytes.extend_from_slice(&(self.one_time_prekeys.len() as u16).to_be_bytes());
+        for (id, prekey) in &self.one_time_prekeys {
+            bytes.extend_from_slice(&id.to_be_bytes());
+            bytes.extend_from_slice(prekey.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<PrekeyBundle, CryptoError> {
+        const FIXED_LEN: usize = USER_KEY_LEN + X25519_KEY_LEN + SIGNATURE_LEN + 2;
+        const ENTRY_LEN: usize = 4 + X25519_KEY_LEN;
+        if bytes.len() < FIXED_LEN {
+            return Err(CryptoError::Truncated);
+        }
+        let (fixed, entries) = bytes.split_at(FIXED_LEN);
+        let count = u16::from_be_bytes([fixed[FIXED_LEN - 2], fixed[FIXED_LEN - 1]]) as usize;
+        if entries.len() != count * ENTRY_LEN {
+            return Err(CryptoError::Truncated);
+        }
+
+        let identity = UserPublicKey::from_bytes(&fixed[..USER_KEY_LEN])?;
+        let signed_prekey = public_key(&fixed[USER_KEY_LEN..USER_KEY_LEN + X25519_KEY_LEN])?;
+        let signature = Signature::from_slice(&fixed[FIXED_LEN - 2 - SIGNATURE_LEN..FIXED_LEN - 2])
+            .map_err(|_| CryptoError::BadSignature)?;
+        identity
+            .signing
+            .verify(&prekey_message(&identity.dh, &signed_prekey), &signature)
+            .map_err(|_| CryptoError::BadSignature)?;
+
+        let one_time_prekeys = entries
+            .chunks(ENTRY_LEN)
+            .map(|entry| {
+                let id = u32::from_be_bytes(entry[..4].try_into().unwrap());
+                Ok((id, public_key(&entry[4..])?))
+            })
+            .collect::<Result<_, CryptoError>>()?;
+        Ok(PrekeyBundle {
+            identity,
+            signed_prekey,
+            signature,
+            one_time_prekeys,
+        })
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.to_bytes())
+    }
+
+    pub fn from_hex(hex_bundle: &str) -> Result<PrekeyBundle, CryptoError> {
+        let bytes = hex::decode(hex_bundle.trim()).map_err(|_| CryptoError::Truncated)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_round_trip_and_forged_ones_are_refused() {
+        let mut bob = UserIdentity::generate();
+        let bundle = bob.prekey_bundle(3);
+        let parsed = PrekeyBundle::from_hex(&bundle.to_hex()).unwrap();
+        assert_eq!(parsed.identity(), &bob.public_key());
+        assert_eq!(parsed.one_time_prekeys(), 3);
+
+        // swapping in someone else's signed prekey breaks the signature
+        let mallory = UserIdentity::generate().prekey_bundle(0).to_bytes();
+        let mut forged = bundle.to_bytes();
+        forged[USER_KEY_LEN..USER_KEY_LEN + X25519_KEY_LEN]
+            .copy_from_slice(&mallory[USER_KEY_LEN..USER_KEY_LEN + X25519_KEY_LEN]);
+        assert_eq!(
+            PrekeyBundle::from_bytes(&forged).unwrap_err(),
+            CryptoError::BadSignature
+        );
+    }
+
+    #[test]
+    fn one_time_prekeys_are_handed_out_and_spent_once() {
+        let alice = UserIdentity::generate();
+        let mut bob = UserIdentity::generate();
+        let mut published = bob.prekey_bundle(1);
+
+        let first = published.take_one();
+        assert_eq!(first.one_time_prekeys(), 1);
+        assert_eq!(published.take_one().one_time_prekeys(), 0);
+
+        let message = alice.initiate(&first).unwrap().encrypt(b"hello").unwrap();
+        let ephemeral = first_message_ephemeral(&message).unwrap();
+        let (mut session, plaintext) = bob.accept(&message).unwrap();
+        assert_eq!(plaintext, b"hello");
+        let reply = session.encrypt(b"hi").unwrap();
+        assert_eq!(first_message_ephemeral(&reply), None);
+        assert_eq!(session.peer(), &alice.public_key());
+        // replaying the first message can't start a second session
+        assert_eq!(bob.accept(&message).unwrap_err(), CryptoError::BadKey);
+
+        // without a one-time prekey the agreement still works
+        let message = alice
+            .initiate(&published)
+            .unwrap()
+            .encrypt(b"again")
+            .unwrap();
+        assert_ne!(first_message_ephemeral(&message), Some(ephemeral));
+        assert_eq!(bob.accept(&message).unwrap().1, b"again");
+    }
+
+    #[test]
+    fn a_session_started_with_someone_elses_bundle_fails() {
+        let alice = UserIdentity::generate();
+        let mut bob = UserIdentity::generate();
+        let mut eve = UserIdentity::generate();
+        let message = alice
+            .initiate(&eve.prekey_bundle(0))
+            .unwrap()
+            .encrypt(b"for bob")
+            .unwrap();
+        assert!(bob.accept(&message).is_err());
+        assert!(eve.accept(&message).is_ok());
+    }
+
+    #[test]
+    fn identities_outlive_the_process() {
+        let path = std::env::temp_dir().join(format!("user_identity_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut bob = UserIdentity::load_or_generate(&path).unwrap();
+        let bundle = bob.prekey_bundle(0);
+        let reloaded = UserIdentity::load_or_generate(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            reloaded.public_key().fingerprint(),
+            bob.public_key().fingerprint()
+        );
+
+        // a bundle handed out before the restart still starts a session afterwards
+        let mut bob = reloaded;
+        let message = UserIdentity::generate()
+            .initiate(&bundle)
+            .unwrap()
+            .encrypt(b"still there?")
+            .unwrap();
+        assert_eq!(bob.accept(&message).unwrap().1, b"still there?");
+    }
+}
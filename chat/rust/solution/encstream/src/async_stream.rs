@@ -0,0 +1,506 @@
+use crate::records::RecordLayer;
+use crate::{
+    client_keys, for_suite, record_len, server_keys, write_record, AccountStore, CipherSuite,
+    Credentials, KeyExchange, RekeyPolicy, Role, CONTENT_DATA, RECORD_HEADER_LEN,
+};
+use crypto_utils::{
+    AeadDiffieHellman, Crypto, PreSharedKey, ServerIdentity, ServerPublicKey, NOISE_KEY_LEN,
+};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, ErrorKind, Read, Write};
+use std::panic;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+use tokio::time::{self, Instant};
+
+// How long a peer gets to finish the handshake before we give up on it and free its thread
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/** EncryptedStream for tokio, over a TcpStream or any other AsyncRead + AsyncWrite.
+    It runs the same handshake and speaks the same records, so either kind of peer can talk
+    to the other, but an idle connection costs a buffer rather than a thread.
+
+    The handshake is EncryptedStream's own, run on tokio's blocking pool with the socket lent
+    to it: key agreement and password logins are slow enough to stall every other connection
+    on a worker thread anyway. That's why the constructors take their arguments by value.
+    So each handshake in progress holds a blocking thread, and once the pool's are all taken
+    (512 by default) the rest wait for one. A peer that hasn't finished within ten seconds
+    gets a TimedOut error, which bounds how long a stalled or slow one can keep it.
+
+    recv() is cancel safe, so one task can wait in select! on it and on whatever it's asked to
+    send. Streams and the Python wire format are only available on EncryptedStream.
+*/
+pub struct AsyncEncryptedStream<C = AeadDiffieHellman, S = TcpStream> {
+    socket: S,
+    records: RecordLayer<C>,
+    // What we've read of the next record so far; kept here so a cancelled recv loses nothing
+    read_buf: Vec<u8>,
+    account: Option<String>,
+}
+
+// These mirror EncryptedStream's constructors of the same names
+impl<C, S> AsyncEncryptedStream<C, S>
+where
+    C: KeyExchange + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub async fn dh_handshake(socket: S, identity: Arc<ServerIdentity>) -> io::Result<Self> {
+        Self::server_handshake(
+            socket,
+            identity,
+            None,
+            None,
+            C::supported_suites(),
+            for_suite,
+        )
+        .await
+    }
+
+    pub async fn dh_handshake_psk(
+        socket: S,
+        identity: Arc<ServerIdentity>,
+        psk: PreSharedKey,
+    ) -> io::Result<Self> {
+        Self::server_handshake(
+            socket,
+            identity,
+            Some(psk),
+            None,
+            C::supported_suites(),
+            for_suite,
+        )
+        .await
+    }
+
+    pub async fn dh_handshake_accounts(
+        socket: S,
+        identity: Arc<ServerIdentity>,
+        psk: Option<PreSharedKey>,
+        accounts: Arc<dyn AccountStore + Send + Sync>,
+    ) -> io::Result<Self> {
+        Self::server_handshake(
+            socket,
+            identity,
+            psk,
+            Some(accounts),
+            C::supported_suites(),
+            for_suite,
+        )
+        .await
+    }
+
+    pub async fn dh_handshake_client<F>(socket: S, check_identity: F) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()> + Send + 'static,
+    {
+        Self::client_handshake(
+            socket,
+            None,
+            None,
+            C::supported_suites(),
+            for_suite,
+            check_identity,
+        )
+        .await
+    }
+
+    pub async fn dh_handshake_client_psk<F>(
+        socket: S,
+        psk: PreSharedKey,
+        check_identity: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()> + Send + 'static,
+    {
+        Self::client_handshake(
+            socket,
+            Some(psk),
+            None,
+            C::supported_suites(),
+            for_suite,
+            check_identity,
+        )
+        .await
+    }
+
+    pub async fn dh_handshake_client_login<F>(
+        socket: S,
+        psk: Option<PreSharedKey>,
+        credentials: Credentials,
+        check_identity: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()> + Send + 'static,
+    {
+        Self::client_handshake(
+            socket,
+            psk,
+            Some(credentials),
+            C::supported_suites(),
+            for_suite,
+            check_identity,
+        )
+        .await
+    }
+
+    pub async fn dh_handshake_with(
+        socket: S,
+        identity: Arc<ServerIdentity>,
+        psk: Option<PreSharedKey>,
+        crypto: C,
+    ) -> io::Result<Self> {
+        let offer = vec![crypto.suite()];
+        Self::server_handshake(socket, identity, psk, None, offer, |_| crypto).await
+    }
+
+    pub async fn dh_handshake_client_with<F>(
+        socket: S,
+        psk: Option<PreSharedKey>,
+        crypto: C,
+        check_identity: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()> + Send + 'static,
+    {
+        let offer = vec![crypto.suite()];
+        Self::client_handshake(socket, psk, None, offer, |_| crypto, check_identity).await
+    }
+
+    // The suite the hello exchange settled on
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.records.send_crypto.suite()
+    }
+
+    // The peer's long-term static key, if the suite authenticated one
+    pub fn peer_static_key(&self) -> Option<&[u8; NOISE_KEY_LEN]> {
+        self.records.recv_crypto.peer_static_key()
+    }
+
+    async fn server_handshake(
+        socket: S,
+        identity: Arc<ServerIdentity>,
+        psk: Option<PreSharedKey>,
+        accounts: Option<Arc<dyn AccountStore + Send + Sync>>,
+        offer: Vec<CipherSuite>,
+        new_crypto: impl FnOnce(CipherSuite) -> C + Send + 'static,
+    ) -> io::Result<Self> {
+        Self::handshake(socket, Role::Server, HANDSHAKE_TIMEOUT, move |socket| {
+            let accounts = accounts.as_deref().map(|a| a as &dyn AccountStore);
+            server_keys(
+                socket,
+                &identity,
+                psk.as_ref(),
+                accounts,
+                &offer,
+                new_crypto,
+            )
+        })
+        .await
+    }
+
+    async fn client_handshake<F>(
+        socket: S,
+        psk: Option<PreSharedKey>,
+        credentials: Option<Credentials>,
+        offer: Vec<CipherSuite>,
+        new_crypto: impl FnOnce(CipherSuite) -> C + Send + 'static,
+        check_identity: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()> + Send + 'static,
+    {
+        Self::handshake(socket, Role::Client, HANDSHAKE_TIMEOUT, move |socket| {
+            client_keys(
+                socket,
+                psk.as_ref(),
+                credentials.as_ref(),
+                &offer,
+                new_crypto,
+                check_identity,
+            )
+        })
+        .await
+    }
+
+    // Run one side of the blocking handshake on the blocking pool; a failed one drops the socket
+    async fn handshake<F>(socket: S, role: Role, timeout: Duration, keys: F) -> io::Result<Self>
+    where
+        F: FnOnce(&mut BlockingIo<S>) -> io::Result<((C, C), Option<String>)> + Send + 'static,
+    {
+        let runtime = Handle::current();
+        let deadline = Instant::now() + timeout;
+        let handshake = tokio::task::spawn_blocking(move || {
+            let mut socket = BlockingIo {
+                socket,
+                runtime,
+                deadline,
+            };
+            let keys = keys(&mut socket);
+            (socket.socket, keys)
+        });
+        let (socket, keys) = match handshake.await {
+            Ok(done) => done,
+            Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+            Err(e) => return Err(io::Error::new(ErrorKind::Interrupted, e)),
+        };
+        let ((send_crypto, recv_crypto), account) = keys?;
+        Ok(AsyncEncryptedStream {
+            socket,
+            records: RecordLayer::new(send_crypto, recv_crypto, role),
+            read_buf: Vec::new(),
+            account,
+        })
+    }
+}
+
+impl<C: Crypto + Clone + Default, S: AsyncRead + AsyncWrite + Unpin> AsyncEncryptedStream<C, S> {
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.records.rekey_policy = policy;
+    }
+
+    // The account the client logged in to; None for guests and on servers without accounts
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    // Records from the peer recv() has dropped; see EncryptedStream::dropped_records
+    pub fn dropped_records(&self) -> u64 {
+        self.records.dropped
+    }
+
+    // Switch our sending direction to a fresh key right now; see EncryptedStream::rekey
+    pub async fn rekey(&mut self) -> io::Result<()> {
+        let record = self.records.seal_key_update()?;
+        self.write_record(&record).await
+    }
+
+    // Shut down our sending direction; the peer's recv() returns None once it has read the rest
+    pub async fn close(&mut self) {
+        if let Err(e) = self.socket.shutdown().await {
+            eprintln!("Error shutting down socket: {:?}", e);
+        }
+    }
+
+    pub async fn send(&mut self, msg: &str) -> io::Result<()> {
+        if self.records.rekey_due() {
+            self.rekey().await?;
+        }
+        let record = self.records.seal(CONTENT_DATA, msg.trim().as_bytes())?;
+        self.write_record(&record).await
+    }
+
+    /** Receive the next message, dropping and applying records just like EncryptedStream::recv.
+        Cancel safe: if the future is dropped part way through a record, what was read of it
+        stays buffered and the next call picks up where this one left off.
+    */
+    pub async fn recv(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let Some(raw) = self.read_record().await? else {
+                return Ok(None);
+            };
+            match self.records.open(&raw)? {
+                Some((CONTENT_DATA, message)) => return Ok(String::from_utf8(message).ok()),
+                Some(_) => {
+                    return Err(io::Error::new(
+                        ErrorKind::Unsupported,
+                        "the peer sent a stream, which only EncryptedStream can receive",
+                    ))
+                }
+                None => {}
+            }
+        }
+    }
+
+    async fn write_record(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut record = Vec::new();
+        write_record(&mut record, payload)?;
+        self.socket.write_all(&record).await?;
+        self.socket.flush().await
+    }
+
+    // read_record for async sockets, reading into read_buf until a whole record is there
+    async fn read_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(header) = self.read_buf.first_chunk::<RECORD_HEADER_LEN>() {
+                let end = RECORD_HEADER_LEN + record_len(*header)?;
+                if self.read_buf.len() >= end {
+                    let record = self.read_buf[RECORD_HEADER_LEN..end].to_vec();
+                    self.read_buf.drain(..end);
+                    return Ok(Some(record));
+                }
+                // room for the rest of the record, so it arrives in as few reads as it can
+                self.read_buf.reserve(end - self.read_buf.len());
+            }
+            if self.socket.read_buf(&mut self.read_buf).await? == 0 {
+                if self.read_buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+// Keys stay out of logs, as for EncryptedStream
+impl<C, S> fmt::Debug for AsyncEncryptedStream<C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncEncryptedStream")
+            .field("role", &self.records.role)
+            .field("account", &self.account)
+            .field("send_seq", &self.records.send_seq)
+            .field("recv_seq", &self.records.recv_seq)
+            .finish_non_exhaustive()
+    }
+}
+
+/** Lends an async socket to the blocking handshake, from a thread of the blocking pool.
+    Every read and write fails with TimedOut once the deadline has passed, so a peer that
+    stops mid-handshake can't keep the thread past it.
+*/
+struct BlockingIo<S> {
+    socket: S,
+    runtime: Handle,
+    deadline: Instant,
+}
+
+impl<S: AsyncRead + Unpin> Read for BlockingIo<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        block_on_until(&self.runtime, self.deadline, self.socket.read(buf))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for BlockingIo<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        block_on_until(&self.runtime, self.deadline, self.socket.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        block_on_until(&self.runtime, self.deadline, self.socket.flush())
+    }
+}
+
+fn block_on_until<T>(
+    runtime: &Handle,
+    deadline: Instant,
+    io: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match runtime.block_on(time::timeout_at(deadline, io)) {
+        Ok(done) => done,
+        Err(_) => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "the peer didn't finish the handshake in time",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EncryptedStream;
+    use std::thread;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    // A server and client stream that have completed the signed handshake with each other
+    async fn connected_pair() -> (AsyncEncryptedStream, AsyncEncryptedStream) {
+        let identity = Arc::new(ServerIdentity::generate());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            AsyncEncryptedStream::dh_handshake(socket, identity).await
+        });
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let client = AsyncEncryptedStream::dh_handshake_client(socket, |_| Ok(()))
+            .await
+            .unwrap();
+        (server.await.unwrap().unwrap(), client)
+    }
+
+    #[tokio::test]
+    async fn messages_and_key_updates_flow_both_ways() {
+        let (mut server, mut client) = connected_pair().await;
+        client.set_rekey_policy(RekeyPolicy {
+            max_records: 2,
+            max_bytes: u64::MAX,
+        });
+        for i in 0..5 {
+            client.send(&format!("message {}", i)).await.unwrap();
+        }
+        for i in 0..5 {
+            assert_eq!(server.recv().await.unwrap(), Some(format!("message {}", i)));
+        }
+        server.rekey().await.unwrap();
+        server.send("Username granted!").await.unwrap();
+        assert_eq!(
+            client.recv().await.unwrap().as_deref(),
+            Some("Username granted!")
+        );
+
+        client.close().await;
+        assert_eq!(server.recv().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn blocking_clients_talk_to_async_servers() {
+        let identity = Arc::new(ServerIdentity::generate());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let socket = std::net::TcpStream::connect(addr).unwrap();
+            let mut client: EncryptedStream =
+                EncryptedStream::dh_handshake_client(socket, |_| Ok(())).unwrap();
+            client.send("alice").unwrap();
+            client.recv().unwrap()
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut server: AsyncEncryptedStream = AsyncEncryptedStream::dh_handshake(socket, identity)
+            .await
+            .unwrap();
+        assert_eq!(server.recv().await.unwrap().as_deref(), Some("alice"));
+        server.send("Username granted!").await.unwrap();
+        let reply = tokio::task::spawn_blocking(|| client.join().unwrap());
+        assert_eq!(reply.await.unwrap().as_deref(), Some("Username granted!"));
+    }
+
+    #[tokio::test]
+    async fn stalled_handshakes_time_out() {
+        let identity = Arc::new(ServerIdentity::generate());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // connects, then never says a word
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let offer = AeadDiffieHellman::supported_suites();
+        let server = AsyncEncryptedStream::<AeadDiffieHellman>::handshake(
+            socket,
+            Role::Server,
+            Duration::from_millis(100),
+            move |socket| server_keys(socket, &identity, None, None, &offer, for_suite),
+        );
+        let err = server.await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_recv_loses_nothing() {
+        let (mut server, mut client) = connected_pair().await;
+        let mut record = Vec::new();
+        let sealed = client.records.seal(CONTENT_DATA, b"hello").unwrap();
+        write_record(&mut record, &sealed).unwrap();
+
+        let (first, rest) = record.split_at(RECORD_HEADER_LEN + 2);
+        client.socket.write_all(first).await.unwrap();
+        let waited = tokio::time::timeout(Duration::from_millis(50), server.recv()).await;
+        assert!(waited.is_err());
+
+        client.socket.write_all(rest).await.unwrap();
+        assert_eq!(server.recv().await.unwrap().as_deref(), Some("hello"));
+    }
+}
@@ -1,78 +1,1515 @@
-use crypto_utils::{Crypto, PrimeDiffieHellman};
+#[cfg(feature = "tokio")]
+mod async_stream;
+mod key_exchange;
+mod login;
+mod negotiation;
+mod pipe;
+mod records;
+mod transport;
+
+use crypto_utils::{
+    AeadDiffieHellman, Crypto, CryptoError, NoiseStaticKey, PreSharedKey,
+    PythonCompatDiffieHellman, ServerIdentity, ServerPublicKey, StreamDecryptor, StreamEncryptor,
+    NOISE_KEY_LEN,
+};
+use records::RecordLayer;
+use std::fmt;
 use std::io::{self, *};
-use std::net::{Shutdown, TcpStream};
+use std::net::TcpStream;
+
+#[cfg(feature = "tokio")]
+pub use async_stream::AsyncEncryptedStream;
+pub use key_exchange::KeyExchange;
+pub use login::{valid_username, AccountStore, Credentials};
+pub use negotiation::{CipherSuite, Negotiated, PROTOCOL_VERSION};
+pub use pipe::Pipe;
+pub use transport::{Close, TryClone};
+
+// Every record on the wire is a 4-byte big-endian length followed by that many bytes of ciphertext.
+const RECORD_HEADER_LEN: usize = 4;
+
+// Upper bound on a single record, so a bogus header can't make us allocate gigabytes.
+pub const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+// Inside the encryption every record starts with a direction byte, a 64-bit sequence number
+// and a content type.
+const INNER_HEADER_LEN: usize = 10;
+const CLIENT_TO_SERVER: u8 = b'C';
+const SERVER_TO_CLIENT: u8 = b'S';
+const CONTENT_DATA: u8 = 0;
+// Empty record announcing that every later record in this direction uses the next key
+const CONTENT_KEY_UPDATE: u8 = 1;
+// Empty record announcing that a StreamEncryptor stream follows it, outside the record layer
+const CONTENT_STREAM: u8 = 2;
 
-pub struct EncryptedStream {
-    socket: TcpStream,
-    crypto: PrimeDiffieHellman,
+// What each side encrypts to prove it derived the same PSK-mixed keys as its peer
+const SERVER_CONFIRMATION: &[u8] = b"copilot-chat key confirmation: server";
+const CLIENT_CONFIRMATION: &[u8] = b"copilot-chat key confirmation: client";
+
+// chat/py reads each message with a single recv() of this size
+const PYTHON_MESSAGE_SIZE: usize = 2048;
+
+// How messages are delimited on the wire
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    // Length-prefixed, sequence-numbered records; see write_record/read_record
+    Records,
+    // No framing at all, one send() per message, exactly like chat/py
+    Python,
 }
 
-impl EncryptedStream {
-    // complete the Diffie-Hellman handshake before sending any data.
+/** When send() rekeys on its own; whichever limit is hit first wins.
+    The receiving end follows the key updates it sees, so only the sender needs a policy.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    // data records sent under one key
+    pub max_records: u64,
+    // plaintext bytes sent under one key
+    pub max_bytes: u64,
+}
 
-    pub fn dh_handshake(mut socket: TcpStream) -> io::Result<Self> {
-        let mut crypto = PrimeDiffieHellman::new();
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            max_records: 100_000,
+            max_bytes: 1 << 30,
+        }
+    }
+}
 
-        let pubkey = crypto.init_keys();
-        socket.write_all(&pubkey.to_vec())?;
+// Which end of the handshake we ran; decides the direction byte on our records
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Server,
+    Client,
+}
 
-        let pub_key_bytes = {
-            let mut data = [0_u8; 16]; // using 16 byte buffer
-            socket.read_exact(&mut data)?;
-            data
-        };
+/** An encrypted, record-framed connection.
+    The key agreement and cipher come from the Crypto implementation C;
+    pick one with e.g. `EncryptedStream::<X25519DiffieHellman>::dh_handshake(socket, &identity)`,
+    or use Negotiated to settle on the strongest suite both ends support.
+
+    Each direction numbers its records from zero, and recv only accepts the next number,
+    so captured records can't be replayed, reordered or reflected back at their sender.
+    Each direction also steps its key forward independently, see RekeyPolicy and rekey().
+    Clones share no counters or keys: drive each direction from a single clone.
+
+    The transport S is a TcpStream unless named otherwise, but anything Read + Write will do:
+    a UnixStream, stdio, a serial port, or a Pipe in tests. The handshakes and close() also
+    need it to implement Close, and try_clone() needs TryClone.
+*/
+pub struct EncryptedStream<C = AeadDiffieHellman, S = TcpStream> {
+    socket: S,
+    records: RecordLayer<C>,
+    wire: WireFormat,
+    // The registered account the client logged in to, if it didn't join as a guest
+    account: Option<String>,
+}
 
-        crypto.handshake(&pub_key_bytes);
-        println!("Handshake complete!");
+impl<C: KeyExchange, S: Read + Write + Close> EncryptedStream<C, S> {
+    // complete the handshake before sending any data. a hello exchange first picks the
+    // strongest cipher suite both ends offer; C decides which ones we offer (see KeyExchange).
+    // the server's long-term identity vouches for its keys, so clients that remember it
+    // can detect a man in the middle.
 
-        Ok(EncryptedStream { socket, crypto })
+    pub fn dh_handshake(socket: S, identity: &ServerIdentity) -> io::Result<Self> {
+        Self::server_handshake(
+            socket,
+            identity,
+            None,
+            None,
+            &C::supported_suites(),
+            for_suite,
+        )
+    }
+
+    /** dh_handshake for private servers: psk is mixed into the session keys and both sides
+        prove they ended up with the same ones, so a client without the team secret is
+        turned away before it sees a single chat message.
+    */
+    pub fn dh_handshake_psk(
+        socket: S,
+        identity: &ServerIdentity,
+        psk: &PreSharedKey,
+    ) -> io::Result<Self> {
+        Self::server_handshake(
+            socket,
+            identity,
+            Some(psk),
+            None,
+            &C::supported_suites(),
+            for_suite,
+        )
+    }
+
+    /** dh_handshake for servers with registered accounts: clients may log in to one of
+        them with a password, register a new one, or carry on as guests (see account()).
+        A client that gets the password wrong is turned away before it sees any chat.
+    */
+    pub fn dh_handshake_accounts(
+        socket: S,
+        identity: &ServerIdentity,
+        psk: Option<&PreSharedKey>,
+        accounts: &dyn AccountStore,
+    ) -> io::Result<Self> {
+        Self::server_handshake(
+            socket,
+            identity,
+            psk,
+            Some(accounts),
+            &C::supported_suites(),
+            for_suite,
+        )
+    }
+
+    /** Client side of dh_handshake.
+        check_identity decides whether the identity key the server announces is acceptable
+        (pinned, in known_hosts, ...); the server's keys must then carry a valid signature
+        from that identity, or we hang up before sending any data.
+    */
+    pub fn dh_handshake_client<F>(socket: S, check_identity: F) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        Self::client_handshake(
+            socket,
+            None,
+            None,
+            &C::supported_suites(),
+            for_suite,
+            check_identity,
+        )
+    }
+
+    // Client side of dh_handshake_psk
+    pub fn dh_handshake_client_psk<F>(
+        socket: S,
+        psk: &PreSharedKey,
+        check_identity: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        Self::client_handshake(
+            socket,
+            Some(psk),
+            None,
+            &C::supported_suites(),
+            for_suite,
+            check_identity,
+        )
+    }
+
+    // Client side of dh_handshake_accounts: log in to or register the account in credentials
+    pub fn dh_handshake_client_login<F>(
+        socket: S,
+        psk: Option<&PreSharedKey>,
+        credentials: &Credentials,
+        check_identity: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        Self::client_handshake(
+            socket,
+            psk,
+            Some(credentials),
+            &C::supported_suites(),
+            for_suite,
+            check_identity,
+        )
+    }
+
+    /** Client side of dh_handshake_accounts, or of the other server handshakes without
+        credentials, that authenticates us with a long-term static key wherever the suite can
+        (Noise_XX): the server then sees the same peer_static_key on every connection.
+        The other client handshakes use a fresh key per connection.
+    */
+    pub fn dh_handshake_client_static<F>(
+        socket: S,
+        static_key: &NoiseStaticKey,
+        psk: Option<&PreSharedKey>,
+        credentials: Option<&Credentials>,
+        check_identity: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        Self::client_handshake(
+            socket,
+            psk,
+            credentials,
+            &C::supported_suites(),
+            |suite| {
+                let mut crypto = for_suite::<C>(suite);
+                crypto.set_static_key(static_key);
+                crypto
+            },
+            check_identity,
+        )
+    }
+
+    /** The general form of dh_handshake: crypto is the fresh instance to handshake with,
+        so e.g. one built with_rng makes the whole session reproducible.
+        Only crypto's own suite is offered.
+    */
+    pub fn dh_handshake_with(
+        socket: S,
+        identity: &ServerIdentity,
+        psk: Option<&PreSharedKey>,
+        crypto: C,
+    ) -> io::Result<Self> {
+        Self::server_handshake(socket, identity, psk, None, &[crypto.suite()], |_| crypto)
+    }
+
+    // The general form of dh_handshake_client
+    pub fn dh_handshake_client_with<F>(
+        socket: S,
+        psk: Option<&PreSharedKey>,
+        crypto: C,
+        check_identity: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        Self::client_handshake(
+            socket,
+            psk,
+            None,
+            &[crypto.suite()],
+            |_| crypto,
+            check_identity,
+        )
+    }
+
+    // new_crypto builds the instance for whichever of the offered suites was chosen
+    fn server_handshake(
+        mut socket: S,
+        identity: &ServerIdentity,
+        psk: Option<&PreSharedKey>,
+        accounts: Option<&dyn AccountStore>,
+        offer: &[CipherSuite],
+        new_crypto: impl FnOnce(CipherSuite) -> C,
+    ) -> io::Result<Self> {
+        match server_keys(&mut socket, identity, psk, accounts, offer, new_crypto) {
+            Ok(((send_crypto, recv_crypto), account)) => {
+                let mut stream = Self::established(
+                    socket,
+                    send_crypto,
+                    recv_crypto,
+                    WireFormat::Records,
+                    Role::Server,
+                );
+                stream.account = account;
+                Ok(stream)
+            }
+            Err(e) => {
+                let _ = socket.close();
+                Err(e)
+            }
+        }
+    }
+
+    fn client_handshake<F>(
+        mut socket: S,
+        psk: Option<&PreSharedKey>,
+        credentials: Option<&Credentials>,
+        offer: &[CipherSuite],
+        new_crypto: impl FnOnce(CipherSuite) -> C,
+        check_identity: F,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        match client_keys(
+            &mut socket,
+            psk,
+            credentials,
+            offer,
+            new_crypto,
+            check_identity,
+        ) {
+            Ok(((send_crypto, recv_crypto), account)) => {
+                let mut stream = Self::established(
+                    socket,
+                    send_crypto,
+                    recv_crypto,
+                    WireFormat::Records,
+                    Role::Client,
+                );
+                stream.account = account;
+                Ok(stream)
+            }
+            Err(e) => {
+                let _ = socket.close();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<C: KeyExchange, S> EncryptedStream<C, S> {
+    // The suite the hello exchange settled on
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.records.send_crypto.suite()
+    }
+
+    // The peer's long-term static key, if the suite authenticated one (see KeyExchange)
+    pub fn peer_static_key(&self) -> Option<&[u8; NOISE_KEY_LEN]> {
+        self.records.recv_crypto.peer_static_key()
+    }
+}
+
+/** The server's half of the handshake, up to the keys it agreed and the account the client
+    logged in to: the hello picks a suite, which agrees on keys, then the PSK and the login
+    are mixed in and checked, and finally both ends confirm they saw the same hellos.
+*/
+fn server_keys<C: KeyExchange, S: Read + Write>(
+    socket: &mut S,
+    identity: &ServerIdentity,
+    psk: Option<&PreSharedKey>,
+    accounts: Option<&dyn AccountStore>,
+    offer: &[CipherSuite],
+    new_crypto: impl FnOnce(CipherSuite) -> C,
+) -> io::Result<((C, C), Option<String>)> {
+    let (suite, hellos) = negotiation::server_hello(socket, offer)?;
+    let keys = new_crypto(suite).server_handshake(socket, identity)?;
+    let keys = match psk {
+        Some(psk) => confirm_psk(socket, keys, psk, Role::Server, Role::Server)?,
+        None => keys,
+    };
+    let (keys, account) = login::server_login(socket, keys, identity, accounts)?;
+    let keys = negotiation::confirm_hellos(socket, keys, &hellos, Role::Server)?;
+    Ok((keys, account))
+}
+
+// The client's half; see server_keys
+fn client_keys<C, S, F>(
+    socket: &mut S,
+    psk: Option<&PreSharedKey>,
+    credentials: Option<&Credentials>,
+    offer: &[CipherSuite],
+    new_crypto: impl FnOnce(CipherSuite) -> C,
+    check_identity: F,
+) -> io::Result<((C, C), Option<String>)>
+where
+    C: KeyExchange,
+    S: Read + Write,
+    F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+{
+    let (suite, hellos) = negotiation::client_hello(socket, offer)?;
+    let keys = new_crypto(suite).client_handshake(socket, check_identity)?;
+    let keys = match psk {
+        Some(psk) => confirm_psk(socket, keys, psk, Role::Client, Role::Server)?,
+        None => keys,
+    };
+    let (keys, account) = login::client_login(socket, keys, credentials)?;
+    let keys = negotiation::confirm_hellos(socket, keys, &hellos, Role::Client)?;
+    Ok((keys, account))
+}
+
+// We only ever offer suites C supports, so the one chosen always has an instance
+fn for_suite<C: KeyExchange>(suite: CipherSuite) -> C {
+    C::for_suite(suite).expect("only supported suites are offered")
+}
+
+/** Mix the PSK into freshly agreed keys, then prove to each other that the results match.
+    Each side sends its label encrypted under its new sending key, first goes first. For a
+    team PSK that's the server, so a client with the wrong one finds out and hangs up without
+    revealing anything more; for a login it's the client, since the server's label would let
+    a client test password guesses offline.
+*/
+fn confirm_psk<C: Crypto, S: Read + Write>(
+    socket: &mut S,
+    (mut send_crypto, mut recv_crypto): (C, C),
+    psk: &PreSharedKey,
+    role: Role,
+    first: Role,
+) -> io::Result<(C, C)> {
+    send_crypto.mix_psk(psk)?;
+    recv_crypto.mix_psk(psk)?;
+    let (ours, theirs) = match role {
+        Role::Server => (SERVER_CONFIRMATION, CLIENT_CONFIRMATION),
+        Role::Client => (CLIENT_CONFIRMATION, SERVER_CONFIRMATION),
+    };
+
+    if role == first {
+        write_record(socket, &send_crypto.encrypt(ours)?)?;
+    }
+    let confirmation = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+    match recv_crypto.decrypt(&confirmation) {
+        Ok(label) if label == theirs => {}
+        _ => return Err(handshake_aborted(CryptoError::KeyConfirmationFailed)),
+    }
+    if role != first {
+        write_record(socket, &send_crypto.encrypt(ours)?)?;
+    }
+    Ok((send_crypto, recv_crypto))
+}
+
+impl<C: Crypto + Clone + Default, S: Read + Write> EncryptedStream<C, S> {
+    fn established(
+        socket: S,
+        send_crypto: C,
+        recv_crypto: C,
+        wire: WireFormat,
+        role: Role,
+    ) -> Self {
+        EncryptedStream {
+            socket,
+            records: RecordLayer::new(send_crypto, recv_crypto, role),
+            wire,
+            account: None,
+        }
+    }
+
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.records.rekey_policy = policy;
+    }
+
+    // The account the client logged in to; None for guests and on servers without accounts
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /** How many records from the peer recv() has dropped so far as forged, reflected or
+        replayed. Dropping them keeps the connection up; whether they're worth logging is up
+        to the caller.
+    */
+    pub fn dropped_records(&self) -> u64 {
+        self.records.dropped
+    }
+
+    /** Switch our sending direction to a fresh key right now.
+        A key-update record goes out under the old key, then everything after it uses the new one;
+        the peer steps its receiving key when it reads that record, so the connection stays up.
+    */
+    pub fn rekey(&mut self) -> io::Result<()> {
+        if self.wire == WireFormat::Python {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the Python wire format has no key updates",
+            ));
+        }
+        let record = self.records.seal_key_update()?;
+        write_record(&mut self.socket, &record)
     }
 
     // close connection with client
 
-    pub fn close(&mut self) {
-        if let Err(e) = self.socket.shutdown(Shutdown::Both) {
+    pub fn close(&mut self)
+    where
+        S: Close,
+    {
+        if let Err(e) = self.socket.close() {
             eprintln!("Error shutting down socket: {:?}", e);
         }
     }
 
-    // send an encrypted message to the connected client.
+    // send an encrypted message to the connected peer.
 
     pub fn send(&mut self, msg: &str) -> io::Result<()> {
-        let msg_bytes: Vec<u8> = msg.trim().as_bytes().to_vec();
-        let encrypted_msg = self.crypto.encrypt(&msg_bytes);
-        self.socket.write_all(&encrypted_msg)?;
-        println!("Sent: {}", &msg);
+        let msg = msg.trim().as_bytes();
+        match self.wire {
+            WireFormat::Records => {
+                self.rekey_if_due()?;
+                let record = self.records.seal(CONTENT_DATA, msg)?;
+                write_record(&mut self.socket, &record)?;
+            }
+            WireFormat::Python => {
+                // Python peers print what they receive verbatim, so each message carries its newline
+                let mut plaintext = msg.to_vec();
+                plaintext.push(b'\n');
+                let encrypted_msg = self.records.send_crypto.encrypt(&plaintext)?;
+                self.socket.write_all(&encrypted_msg)?;
+            }
+        }
+        Ok(())
+    }
+
+    /** Send a payload of any size, such as a file, and return how many bytes it had.
+        Only one chunk of it is in memory at a time; the peer reads it with recv_stream().
+        A stream record announces it and that record's sequence number goes into the stream's
+        key, so a stream can't be replayed or moved elsewhere in the conversation.
+    */
+    pub fn send_stream<R: Read>(&mut self, mut payload: R) -> io::Result<u64> {
+        if self.wire == WireFormat::Python {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the Python wire format has no streams",
+            ));
+        }
+        self.rekey_if_due()?;
+        let context = stream_context(self.records.send_direction(), self.records.send_seq);
+        let record = self.records.seal(CONTENT_STREAM, &[])?;
+        write_record(&mut self.socket, &record)?;
+
+        let mut encryptor =
+            StreamEncryptor::new(&self.records.send_crypto, &context, &mut self.socket)?;
+        let sent = io::copy(&mut payload, &mut encryptor)?;
+        encryptor.finish()?;
+        Ok(sent)
+    }
+
+    // Rekey before sending if the current key has carried as much as the policy allows
+    fn rekey_if_due(&mut self) -> io::Result<()> {
+        if self.records.rekey_due() {
+            self.rekey()?;
+        }
         Ok(())
     }
 
-    // clone the tcp stream, this function can be used to generate separate streams for each connected client
+    // clone the transport, this function can be used to generate separate streams for each connected client
 
-    pub fn try_clone(&self) -> io::Result<Self> {
+    pub fn try_clone(&self) -> io::Result<Self>
+    where
+        S: TryClone,
+    {
         let socket = self.socket.try_clone()?;
 
         Ok(EncryptedStream {
             socket,
-            crypto: self.crypto.clone(),
+            records: self.records.clone(),
+            wire: self.wire,
+            account: self.account.clone(),
         })
     }
 
-    // receive an encrypted message from the connected client and decrypt it
+    // receive an encrypted message from the connected peer and decrypt it.
+    // records that fail authentication or replay an earlier record are dropped and we
+    // wait for the next one; a record from the future means some were lost or reordered,
+    // which is fatal. key updates from the peer are applied here and never returned.
+    // any other crypto failure is surfaced as an InvalidData io::Error.
 
     pub fn recv(&mut self) -> io::Result<Option<String>> {
-        let raw = self.receive_raw()?;
-        if raw.is_empty() {
-            return Ok(None);
-        }
-        let message = self.crypto.decrypt(&raw);
+        let message = match self.recv_record()? {
+            Some((CONTENT_DATA, message)) => message,
+            Some(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "the peer sent a stream; read it with recv_stream()",
+                ))
+            }
+            None => return Ok(None),
+        };
         let txt = std::str::from_utf8(&message).ok().map(String::from);
-        println!("Received: {:?}", &txt);
+        let txt = match self.wire {
+            WireFormat::Records => txt,
+            // Python clients send whole lines; strip the newline so handlers see what a Rust client would send
+            WireFormat::Python => txt.map(|t| t.trim_end_matches(['\r', '\n']).to_string()),
+        };
         Ok(txt)
     }
 
-    fn receive_raw(&mut self) -> io::Result<Vec<u8>> {
-        let mut data = vec![0_u8; 1024];
-        let bytes_read = self.socket.read(&mut data)?;
-        Ok(data[..bytes_read].to_vec())
+    /** Receive a stream sent with send_stream(), writing the payload to `out` as it arrives,
+        and return its length, or None if the peer closed the connection instead.
+        If this fails part way through, `out` has only had a prefix of the payload.
+    */
+    pub fn recv_stream<W: Write>(&mut self, mut out: W) -> io::Result<Option<u64>> {
+        match self.recv_record()? {
+            Some((CONTENT_STREAM, _)) => {}
+            Some(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "expected a stream but the peer sent a message",
+                ))
+            }
+            None => return Ok(None),
+        }
+        // check_sequence has already moved past the announcing record
+        let context = stream_context(self.records.recv_direction(), self.records.recv_seq - 1);
+        let mut decryptor =
+            StreamDecryptor::new(&self.records.recv_crypto, &context, &mut self.socket)?;
+        let received = io::copy(&mut decryptor, &mut out)?;
+        Ok(Some(received))
+    }
+
+    // The next message or stream announcement and its content type, after dropping
+    // forged and replayed records and applying key updates along the way
+    fn recv_record(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        loop {
+            let raw = match self.read_message()? {
+                Some(raw) => raw,
+                None => return Ok(None),
+            };
+            let record = match self.wire {
+                WireFormat::Records => self.records.open(&raw)?,
+                WireFormat::Python => self
+                    .records
+                    .decrypt(&raw)?
+                    .map(|plaintext| (CONTENT_DATA, plaintext)),
+            };
+            if record.is_some() {
+                return Ok(record);
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.wire {
+            WireFormat::Records => read_record(&mut self.socket),
+            WireFormat::Python => {
+                let mut data = vec![0_u8; PYTHON_MESSAGE_SIZE];
+                let bytes_read = self.socket.read(&mut data)?;
+                if bytes_read == 0 {
+                    return Ok(None);
+                }
+                data.truncate(bytes_read);
+                Ok(Some(data))
+            }
+        }
+    }
+}
+
+impl<S: Read + Write + Close> EncryptedStream<PythonCompatDiffieHellman, S> {
+    /** Server side of the handshake in chat/py/solution/server.py:
+        send our public key as decimal ASCII, then read the client's with a single recv().
+        There's no server identity, framing or sequencing, so only use this to talk to Python clients.
+    */
+    pub fn python_compat_handshake(socket: S) -> io::Result<Self> {
+        Self::python_compat_handshake_with(socket, PythonCompatDiffieHellman::new())
+    }
+
+    // python_compat_handshake with a crypto of our choosing, e.g. PythonCompatDiffieHellman::with_rng
+    pub fn python_compat_handshake_with(
+        mut socket: S,
+        mut crypto: PythonCompatDiffieHellman,
+    ) -> io::Result<Self> {
+        let pubkey = crypto.init_keys();
+        socket.write_all(&pubkey)?;
+
+        let peer_key = python_compat_peer_key(&mut socket)?;
+        if let Err(e) = crypto.handshake(&peer_key) {
+            let _ = socket.close();
+            return Err(handshake_aborted(e));
+        }
+
+        Ok(Self::established(
+            socket,
+            crypto.clone(),
+            crypto,
+            WireFormat::Python,
+            Role::Server,
+        ))
+    }
+
+    /** Client side of the handshake in chat/py/solution/client.py:
+        read the server's public key with a single recv(), then send ours as decimal ASCII.
+        The server proves nothing about who it is, so only use this to talk to Python servers.
+    */
+    pub fn python_compat_handshake_client(socket: S) -> io::Result<Self> {
+        Self::python_compat_handshake_client_with(socket, PythonCompatDiffieHellman::new())
+    }
+
+    pub fn python_compat_handshake_client_with(
+        mut socket: S,
+        mut crypto: PythonCompatDiffieHellman,
+    ) -> io::Result<Self> {
+        let peer_key = python_compat_peer_key(&mut socket)?;
+        let pubkey = crypto.init_keys();
+        if let Err(e) = crypto.handshake(&peer_key) {
+            let _ = socket.close();
+            return Err(handshake_aborted(e));
+        }
+        socket.write_all(&pubkey)?;
+
+        Ok(Self::established(
+            socket,
+            crypto.clone(),
+            crypto,
+            WireFormat::Python,
+            Role::Client,
+        ))
+    }
+}
+
+// The peer's public key, which the Python code sends on its own and reads with a single recv()
+fn python_compat_peer_key<S: Read>(socket: &mut S) -> io::Result<Vec<u8>> {
+    let mut data = vec![0_u8; PYTHON_MESSAGE_SIZE];
+    let bytes_read = socket.read(&mut data)?;
+    if bytes_read == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    data.truncate(bytes_read);
+    Ok(data)
+}
+
+// Keys stay out of logs; the Crypto types redact themselves too, but we don't even ask them
+impl<C, S: fmt::Debug> fmt::Debug for EncryptedStream<C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedStream")
+            .field("socket", &self.socket)
+            .field("wire", &self.wire)
+            .field("role", &self.records.role)
+            .field("account", &self.account)
+            .field("send_seq", &self.records.send_seq)
+            .field("recv_seq", &self.records.recv_seq)
+            .finish_non_exhaustive()
+    }
+}
+
+// What a stream's key is bound to: the direction and sequence number of the record announcing it
+fn stream_context(direction: u8, seq: u64) -> [u8; 9] {
+    let mut context = [0_u8; 9];
+    context[0] = direction;
+    context[1..].copy_from_slice(&seq.to_be_bytes());
+    context
+}
+
+// The error dh_handshake returns when the peer's handshake message is rejected
+pub fn handshake_aborted(e: CryptoError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("handshake aborted: {}", e))
+}
+
+/** Write one record: a length header followed by the payload.
+    Header and payload go out in a single write so small records aren't split needlessly.
+*/
+pub fn write_record<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_RECORD_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "record of {} bytes exceeds the {} byte limit",
+                payload.len(),
+                MAX_RECORD_LEN
+            ),
+        ));
+    }
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(payload);
+    writer.write_all(&record)?;
+    writer.flush()
+}
+
+/** Read exactly one record, however the transport happens to fragment it.
+    Returns Ok(None) if the peer closed the connection cleanly between records;
+    a connection closed part way through a record is an UnexpectedEof error.
+*/
+pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0_u8; RECORD_HEADER_LEN];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut payload = vec![0_u8; record_len(header)?];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+// The payload length a record header announces, refusing anything over MAX_RECORD_LEN
+fn record_len(header: [u8; RECORD_HEADER_LEN]) -> io::Result<usize> {
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "peer sent a {} byte record, limit is {}",
+                len, MAX_RECORD_LEN
+            ),
+        ));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::Aes256;
+    use crypto_utils::{DhGroup, NoiseXX, PrimeDiffieHellman, SrpVerifier, X25519DiffieHellman};
+    use ecb::cipher::block_padding::Pkcs7;
+    use ecb::cipher::{BlockDecryptMut, BlockEncryptMut, KeyInit};
+    use num::BigUint;
+    use rand_chacha::rand_core::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use std::net::{Shutdown, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Hands out its bytes 1, 2, 3, 1, 2, 3... at a time, like a slow or fragmenting network
+    struct Trickle<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = (self.reads % 3 + 1).min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            self.reads += 1;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn records_come_out_whole_however_the_reads_split_them() {
+        let payloads: Vec<Vec<u8>> = [0, 2, 1500, 5000]
+            .into_iter()
+            .map(|len| (0..len).map(|i| (i % 251) as u8).collect())
+            .collect();
+        let mut wire = Vec::new();
+        for payload in &payloads {
+            write_record(&mut wire, payload).unwrap();
+        }
+
+        let mut reader = Trickle {
+            data: &wire,
+            reads: 0,
+        };
+        for payload in &payloads {
+            assert_eq!(read_record(&mut reader).unwrap().as_ref(), Some(payload));
+        }
+        assert_eq!(read_record(&mut reader).unwrap(), None);
+
+        // cut off inside the last payload
+        let mut reader = Trickle {
+            data: &wire[..wire.len() - 1],
+            reads: 0,
+        };
+        for payload in &payloads[..3] {
+            assert_eq!(read_record(&mut reader).unwrap().as_ref(), Some(payload));
+        }
+        let err = read_record(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        // and two records written back to back on a socket arrive as two
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut receiver, _) = listener.accept().unwrap();
+        let long = vec![b'x'; 2000];
+        write_record(&mut sender, &long).unwrap();
+        write_record(&mut sender, b"short").unwrap();
+        assert_eq!(read_record(&mut receiver).unwrap(), Some(long));
+        assert_eq!(read_record(&mut receiver).unwrap(), Some(b"short".to_vec()));
+    }
+
+    // How a server and a client handshaking over a Pipe each came out of it
+    type Handshake<S, C = S> = (
+        io::Result<EncryptedStream<S, Pipe>>,
+        io::Result<EncryptedStream<C, Pipe>>,
+    );
+
+    // A server and client stream that have completed the signed handshake with each other
+    pub(crate) fn connected_pair() -> (
+        EncryptedStream<X25519DiffieHellman, Pipe>,
+        EncryptedStream<X25519DiffieHellman, Pipe>,
+    ) {
+        connected_pair_with::<X25519DiffieHellman>()
+    }
+
+    fn connected_pair_with<C: KeyExchange + Send + 'static>(
+    ) -> (EncryptedStream<C, Pipe>, EncryptedStream<C, Pipe>) {
+        let identity = ServerIdentity::generate();
+        let pinned = identity.public_key();
+        let (socket, server_socket) = Pipe::pair();
+        let server =
+            thread::spawn(move || EncryptedStream::dh_handshake(server_socket, &identity).unwrap());
+        let client = EncryptedStream::dh_handshake_client(socket, |key| {
+            assert_eq!(key.as_bytes(), pinned.as_bytes());
+            Ok(())
+        })
+        .unwrap();
+        (server.join().unwrap(), client)
+    }
+
+    // What an attacker who captured (or forged the header of) a record would put on the wire
+    fn sealed_record<C: Crypto>(crypto: &C, direction: u8, seq: u64, msg: &str) -> Vec<u8> {
+        let mut plaintext = vec![direction];
+        plaintext.extend_from_slice(&seq.to_be_bytes());
+        plaintext.push(CONTENT_DATA);
+        plaintext.extend_from_slice(msg.as_bytes());
+        crypto.encrypt(&plaintext).unwrap()
+    }
+
+    #[test]
+    fn replayed_reflected_and_reordered_records_are_rejected() {
+        let (mut server, mut client) = connected_pair();
+
+        client.send("hello").unwrap();
+        let replay = sealed_record(&client.records.send_crypto, CLIENT_TO_SERVER, 0, "/quit");
+        write_record(&mut client.socket, &replay).unwrap();
+        write_record(&mut client.socket, &replay).unwrap();
+        let reflected = sealed_record(&client.records.send_crypto, SERVER_TO_CLIENT, 1, "/quit");
+        write_record(&mut client.socket, &reflected).unwrap();
+        write_record(&mut client.socket, &[0_u8; 40]).unwrap();
+        client.send("world").unwrap();
+        assert_eq!(server.recv().unwrap(), Some("hello".to_string()));
+        assert_eq!(server.dropped_records(), 0);
+        assert_eq!(server.recv().unwrap(), Some("world".to_string()));
+        assert_eq!(server.dropped_records(), 4);
+
+        // and the other direction is numbered independently
+        server.send("hi").unwrap();
+        assert_eq!(client.recv().unwrap(), Some("hi".to_string()));
+
+        let skipped = sealed_record(&client.records.send_crypto, CLIENT_TO_SERVER, 5, "/quit");
+        write_record(&mut client.socket, &skipped).unwrap();
+        assert_eq!(server.recv().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    // Plays chat/py/solution/client.py or server.py byte for byte, using nothing from crypto_utils
+    struct PythonPeer {
+        sock: TcpStream,
+        key: Vec<u8>,
+    }
+
+    impl PythonPeer {
+        fn connect(addr: std::net::SocketAddr) -> PythonPeer {
+            let mut sock = TcpStream::connect(addr).unwrap();
+            // ga_repr = self.sock.recv(MESSAGE_SIZE_BYTES)
+            let mut data = [0_u8; 2048];
+            let n = sock.read(&mut data).unwrap();
+            // self.sock.send(pubkey)
+            sock.write_all(&Self::pub_key(123)).unwrap();
+            let key = Self::shared_key(123, &data[..n]);
+            PythonPeer { sock, key }
+        }
+
+        fn accept(listener: &TcpListener) -> PythonPeer {
+            let (mut sock, _) = listener.accept().unwrap();
+            // self.conn.send(pubkey)
+            sock.write_all(&Self::pub_key(321)).unwrap();
+            // b_repr = self.conn.recv(ClientConnection.MESSAGE_SIZE_BYTES)
+            let mut data = [0_u8; 2048];
+            let n = sock.read(&mut data).unwrap();
+            let key = Self::shared_key(321, &data[..n]);
+            PythonPeer { sock, key }
+        }
+
+        // str(pub_key).encode()
+        fn pub_key(priv_key: u32) -> Vec<u8> {
+            let pub_key = BigUint::from(2u32).modpow(&BigUint::from(priv_key), &997u32.into());
+            pub_key.to_str_radix(10).into_bytes()
+        }
+
+        // shared_secret.to_bytes(32, byteorder="big")
+        fn shared_key(priv_key: u32, their_pub: &[u8]) -> Vec<u8> {
+            let their_pub =
+                BigUint::parse_bytes(std::str::from_utf8(their_pub).unwrap().as_bytes(), 10)
+                    .unwrap();
+            let secret = their_pub
+                .modpow(&BigUint::from(priv_key), &997u32.into())
+                .to_bytes_be();
+            let mut key = vec![0_u8; 32 - secret.len()];
+            key.extend_from_slice(&secret);
+            key
+        }
+
+        fn send_msg(&mut self, msg: &str) {
+            let ciphertext = ecb::Encryptor::<Aes256>::new_from_slice(&self.key)
+                .unwrap()
+                .encrypt_padded_vec_mut::<Pkcs7>(msg.as_bytes());
+            self.sock.write_all(&ciphertext).unwrap();
+        }
+
+        fn recv_msg(&mut self) -> String {
+            let mut data = [0_u8; 2048];
+            let n = self.sock.read(&mut data).unwrap();
+            let plaintext = ecb::Decryptor::<Aes256>::new_from_slice(&self.key)
+                .unwrap()
+                .decrypt_padded_vec_mut::<Pkcs7>(&data[..n])
+                .unwrap();
+            String::from_utf8(plaintext).unwrap()
+        }
+    }
+
+    #[test]
+    fn python_client_talks_to_rust_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = EncryptedStream::python_compat_handshake(socket).unwrap();
+            stream.send("Enter username: ").unwrap();
+            let username = stream.recv().unwrap();
+            stream.send("Username granted!").unwrap();
+            let eof = stream.recv().unwrap();
+            (username, eof)
+        });
+
+        let mut client = PythonPeer::connect(addr);
+        assert_eq!(client.recv_msg(), "Enter username:\n");
+        client.send_msg("alice\n");
+        assert_eq!(client.recv_msg(), "Username granted!\n");
+        drop(client);
+
+        let (username, eof) = server.join().unwrap();
+        assert_eq!(username, Some("alice".to_string()));
+        assert_eq!(eof, None);
+    }
+
+    #[test]
+    fn rust_client_talks_to_python_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let socket = TcpStream::connect(addr).unwrap();
+            let mut stream = EncryptedStream::python_compat_handshake_client(socket).unwrap();
+            let prompt = stream.recv().unwrap();
+            // what the user typed, newline and all
+            stream.send("alice\n").unwrap();
+            let welcome = stream.recv().unwrap();
+            let eof = stream.recv().unwrap();
+            (prompt, welcome, eof)
+        });
+
+        let mut server = PythonPeer::accept(&listener);
+        server.send_msg("Enter username: ");
+        assert_eq!(server.recv_msg(), "alice\n");
+        server.send_msg("Welcome alice!\n");
+        drop(server);
+
+        let (prompt, welcome, eof) = client.join().unwrap();
+        assert_eq!(prompt, Some("Enter username: ".to_string()));
+        assert_eq!(welcome, Some("Welcome alice!".to_string()));
+        assert_eq!(eof, None);
+    }
+
+    #[test]
+    fn key_updates_keep_both_ends_in_sync() {
+        let (mut server, mut client) = connected_pair();
+        client.set_rekey_policy(RekeyPolicy {
+            max_records: 3,
+            max_bytes: 10,
+        });
+
+        let messages = [
+            "one",
+            "two",
+            "three",
+            "four",
+            "five",
+            "sixteen bytes!!",
+            "seven",
+        ];
+        for msg in messages {
+            client.send(msg).unwrap();
+        }
+        for msg in messages {
+            assert_eq!(server.recv().unwrap(), Some(msg.to_string()));
+        }
+
+        // on request too, and records sealed under the retired key are no longer accepted
+        let stale = sealed_record(
+            &client.records.send_crypto,
+            CLIENT_TO_SERVER,
+            client.records.send_seq + 1,
+            "/quit",
+        );
+        client.rekey().unwrap();
+        write_record(&mut client.socket, &stale).unwrap();
+        server.send("still here").unwrap();
+        client.send("me too").unwrap();
+        assert_eq!(client.recv().unwrap(), Some("still here".to_string()));
+        assert_eq!(server.recv().unwrap(), Some("me too".to_string()));
+    }
+
+    #[test]
+    fn streams_carry_large_payloads_between_messages() {
+        let (mut server, mut client) = connected_pair();
+        client.set_rekey_policy(RekeyPolicy {
+            max_records: 2,
+            max_bytes: u64::MAX,
+        });
+        let payload: Vec<u8> = (0..(1 << 20) + 3).map(|i| (i % 251) as u8).collect();
+
+        // more than a socket's buffers would hold, so the sender gets its own thread
+        let sent = payload.clone();
+        let sender = thread::spawn(move || {
+            client.send("here it comes").unwrap();
+            assert_eq!(client.send_stream(&sent[..]).unwrap(), sent.len() as u64);
+            assert_eq!(client.send_stream(io::empty()).unwrap(), 0);
+            client.send("done").unwrap();
+            client.send_stream(&b"unexpected"[..]).unwrap();
+        });
+
+        assert_eq!(server.recv().unwrap(), Some("here it comes".to_string()));
+        let mut received = Vec::new();
+        assert_eq!(
+            server.recv_stream(&mut received).unwrap(),
+            Some(payload.len() as u64)
+        );
+        assert!(received == payload);
+        assert_eq!(server.recv_stream(io::sink()).unwrap(), Some(0));
+        assert_eq!(server.recv().unwrap(), Some("done".to_string()));
+        let err = server.recv().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn noise_xx_handshake_runs_behind_the_same_constructors() {
+        let (mut server, mut client) = connected_pair_with::<NoiseXX>();
+        client.send("hello").unwrap();
+        assert_eq!(server.recv().unwrap(), Some("hello".to_string()));
+        server.send("hi").unwrap();
+        client.rekey().unwrap();
+        client.send("again").unwrap();
+        assert_eq!(client.recv().unwrap(), Some("hi".to_string()));
+        assert_eq!(server.recv().unwrap(), Some("again".to_string()));
+
+        // a client pinned to some other identity hangs up
+        let identity = ServerIdentity::generate();
+        let (socket, server_socket) = Pipe::pair();
+        let server = thread::spawn(move || {
+            EncryptedStream::<NoiseXX, _>::dh_handshake(server_socket, &identity).is_err()
+        });
+        let pinned = ServerIdentity::generate().public_key();
+        let refused = EncryptedStream::<NoiseXX, _>::dh_handshake_client(socket, |key| {
+            if *key == pinned {
+                Ok(())
+            } else {
+                Err(ErrorKind::PermissionDenied.into())
+            }
+        });
+        assert_eq!(refused.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn noise_xx_authenticates_both_static_keys() {
+        let identity = Arc::new(ServerIdentity::generate());
+        let connect = |static_key: Option<&NoiseStaticKey>| {
+            let (socket, server_socket) = Pipe::pair();
+            let server_identity = Arc::clone(&identity);
+            let server = thread::spawn(move || {
+                EncryptedStream::<Negotiated, _>::dh_handshake(server_socket, &server_identity)
+                    .unwrap()
+            });
+            let client = match static_key {
+                Some(key) => EncryptedStream::<Negotiated, _>::dh_handshake_client_static(
+                    socket,
+                    key,
+                    None,
+                    None,
+                    |_| Ok(()),
+                ),
+                None => EncryptedStream::dh_handshake_client(socket, |_| Ok(())),
+            };
+            (server.join().unwrap(), client.unwrap())
+        };
+
+        // a client with a long-term key looks the same to the server on every connection
+        let client_key = NoiseStaticKey::generate();
+        for _ in 0..2 {
+            let (server, client) = connect(Some(&client_key));
+            assert_eq!(server.peer_static_key(), Some(client_key.public_key()));
+            let server_key = identity.noise_static_key();
+            assert_eq!(client.peer_static_key(), Some(server_key.public_key()));
+        }
+        // and one without is a stranger every time
+        let (first, _) = connect(None);
+        let (second, _) = connect(None);
+        assert!(first.peer_static_key().is_some());
+        assert_ne!(first.peer_static_key(), second.peer_static_key());
+
+        // the DH suites have no static keys to pin
+        let (server, _) = connected_pair();
+        assert_eq!(server.peer_static_key(), None);
+    }
+
+    // Run a PSK handshake with the given secrets and hand back both sides' results
+    fn psk_handshake<C: KeyExchange + Send + 'static>(
+        server_psk: &'static [u8],
+        client_psk: &'static [u8],
+    ) -> Handshake<C> {
+        let identity = ServerIdentity::generate();
+        let (socket, server_socket) = Pipe::pair();
+        let server = thread::spawn(move || {
+            let psk = PreSharedKey::new(server_psk);
+            EncryptedStream::dh_handshake_psk(server_socket, &identity, &psk)
+        });
+        let psk = PreSharedKey::new(client_psk);
+        let client = EncryptedStream::dh_handshake_client_psk(socket, &psk, |_| Ok(()));
+        (server.join().unwrap(), client)
+    }
+
+    // Accounts kept in memory, as a server's --accounts file would keep them; None is reserved
+    #[derive(Default)]
+    pub(crate) struct MemoryAccounts(Mutex<std::collections::HashMap<String, Option<SrpVerifier>>>);
+
+    impl AccountStore for MemoryAccounts {
+        fn lookup(&self, username: &str) -> Option<SrpVerifier> {
+            self.0.lock().unwrap().get(username).cloned().flatten()
+        }
+
+        fn reserve(&self, username: &str) -> io::Result<()> {
+            match self.0.lock().unwrap().entry(username.to_string()) {
+                std::collections::hash_map::Entry::Occupied(_) => {
+                    Err(ErrorKind::AlreadyExists.into())
+                }
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    entry.insert(None);
+                    Ok(())
+                }
+            }
+        }
+
+        fn register(&self, username: &str, verifier: SrpVerifier) -> io::Result<()> {
+            let mut accounts = self.0.lock().unwrap();
+            let entry = accounts.entry(username.to_string()).or_default();
+            if entry.is_some() {
+                return Err(ErrorKind::AlreadyExists.into());
+            }
+            *entry = Some(verifier);
+            Ok(())
+        }
+
+        fn release(&self, username: &str) {
+            let mut accounts = self.0.lock().unwrap();
+            if let Some(None) = accounts.get(username) {
+                accounts.remove(username);
+            }
+        }
+    }
+
+    // Handshake a client with the given credentials against a server holding accounts
+    fn login(
+        accounts: &Arc<MemoryAccounts>,
+        credentials: Option<Credentials>,
+    ) -> Handshake<X25519DiffieHellman> {
+        let identity = ServerIdentity::generate();
+        let (socket, server_socket) = Pipe::pair();
+        let accounts = Arc::clone(accounts);
+        let server = thread::spawn(move || {
+            EncryptedStream::dh_handshake_accounts(server_socket, &identity, None, &*accounts)
+        });
+        let client = match credentials {
+            Some(credentials) => {
+                EncryptedStream::dh_handshake_client_login(socket, None, &credentials, |_| Ok(()))
+            }
+            None => EncryptedStream::dh_handshake_client(socket, |_| Ok(())),
+        };
+        (server.join().unwrap(), client)
+    }
+
+    #[test]
+    fn registered_users_log_in_with_their_password_only() {
+        let accounts = Arc::new(MemoryAccounts::default());
+        let register = |username: &str| Credentials::Register {
+            username: username.to_string(),
+            password: "hunter2".to_string(),
+        };
+        let (server, client) = login(&accounts, Some(register("alice")));
+        assert_eq!(server.unwrap().account(), Some("alice"));
+        assert_eq!(client.unwrap().account(), Some("alice"));
+        let (server, client) = login(&accounts, Some(register("alice")));
+        assert_eq!(client.err().unwrap().kind(), ErrorKind::AlreadyExists);
+        assert!(server.is_err());
+
+        let (server, client) = login(
+            &accounts,
+            Some(Credentials::Login {
+                username: "alice".to_string(),
+                password: "hunter2".to_string(),
+            }),
+        );
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+        assert_eq!(server.account(), Some("alice"));
+        client.send("hello").unwrap();
+        assert_eq!(server.recv().unwrap(), Some("hello".to_string()));
+
+        // a wrong password and an unknown user look exactly the same from the outside
+        for (username, password) in [("alice", "hunter3"), ("bob", "hunter2")] {
+            let (server, client) = login(
+                &accounts,
+                Some(Credentials::Login {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                }),
+            );
+            let client_err = client.err().unwrap();
+            assert_eq!(client_err.kind(), ErrorKind::PermissionDenied);
+            assert_eq!(
+                client_err.to_string(),
+                "login failed: wrong username or password"
+            );
+            assert_eq!(server.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        }
+
+        // guests still get in, just without an account
+        let (server, client) = login(&accounts, None);
+        assert_eq!(server.unwrap().account(), None);
+        assert_eq!(client.unwrap().account(), None);
+    }
+
+    #[test]
+    fn mismatched_pre_shared_keys_fail_the_handshake() {
+        let (server, client) = psk_handshake::<AeadDiffieHellman>(b"team secret", b"team secret");
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+        client.send("hello").unwrap();
+        assert_eq!(server.recv().unwrap(), Some("hello".to_string()));
+
+        let (server, client) = psk_handshake::<AeadDiffieHellman>(b"team secret", b"guess");
+        let client_err = client.err().unwrap();
+        assert_eq!(client_err.kind(), ErrorKind::InvalidData);
+        assert!(client_err.to_string().contains("key confirmation failed"));
+        assert!(server.is_err());
+
+        let (server, client) = psk_handshake::<NoiseXX>(b"team secret", b"guess");
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    // Copy one direction of a proxied connection, keeping a copy of every byte
+    fn record_pump(mut from: TcpStream, mut to: TcpStream, log: Arc<Mutex<Vec<u8>>>) {
+        let mut buf = [0_u8; 4096];
+        while let Ok(n @ 1..) = from.read(&mut buf) {
+            log.lock().unwrap().extend_from_slice(&buf[..n]);
+            if to.write_all(&buf[..n]).is_err() {
+                break;
+            }
+        }
+        let _ = to.shutdown(Shutdown::Write);
+    }
+
+    // A short chat between seeded peers, relayed through a proxy that records both directions
+    fn seeded_session_transcript() -> (Vec<u8>, Vec<u8>) {
+        let identity = ServerIdentity::generate_with_rng(&mut ChaCha20Rng::seed_from_u64(3));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let crypto = X25519DiffieHellman::with_rng(ChaCha20Rng::seed_from_u64(1));
+            let mut stream =
+                EncryptedStream::dh_handshake_with(socket, &identity, None, crypto).unwrap();
+            stream.send("Enter username: ").unwrap();
+            assert_eq!(stream.recv().unwrap(), Some("alice".to_string()));
+            stream.send("Username granted!").unwrap();
+        });
+
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let relay = thread::spawn(move || {
+            let (client_side, _) = proxy.accept().unwrap();
+            let server_side = TcpStream::connect(addr).unwrap();
+            let sent: Arc<Mutex<Vec<u8>>> = Arc::default();
+            let received: Arc<Mutex<Vec<u8>>> = Arc::default();
+            let upstream = {
+                let (from, to) = (client_side.try_clone().unwrap(), server_side.try_clone());
+                let log = Arc::clone(&sent);
+                thread::spawn(move || record_pump(from, to.unwrap(), log))
+            };
+            record_pump(server_side, client_side, Arc::clone(&received));
+            upstream.join().unwrap();
+            let sent = sent.lock().unwrap().clone();
+            let received = received.lock().unwrap().clone();
+            (sent, received)
+        });
+
+        let socket = TcpStream::connect(proxy_addr).unwrap();
+        let crypto = X25519DiffieHellman::with_rng(ChaCha20Rng::seed_from_u64(2));
+        let mut client =
+            EncryptedStream::dh_handshake_client_with(socket, None, crypto, |_| Ok(())).unwrap();
+        assert_eq!(client.recv().unwrap(), Some("Enter username:".to_string()));
+        client.send("alice").unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            Some("Username granted!".to_string())
+        );
+        server.join().unwrap();
+        drop(client);
+
+        relay.join().unwrap()
+    }
+
+    #[test]
+    fn seeded_sessions_replay_byte_for_byte() {
+        let (sent, received) = seeded_session_transcript();
+        assert!(!sent.is_empty() && !received.is_empty());
+        assert_eq!(seeded_session_transcript(), (sent, received));
+    }
+
+    // Handshake a server offering what S supports with a client offering what C supports
+    fn negotiate<S, C>() -> Handshake<S, C>
+    where
+        S: KeyExchange + Send + 'static,
+        C: KeyExchange,
+    {
+        let identity = ServerIdentity::generate();
+        let (socket, server_socket) = Pipe::pair();
+        let server = thread::spawn(move || EncryptedStream::dh_handshake(server_socket, &identity));
+        let client = EncryptedStream::dh_handshake_client(socket, |_| Ok(()));
+        (server.join().unwrap(), client)
+    }
+
+    #[test]
+    fn hellos_settle_on_the_strongest_mutual_suite() {
+        let (server, client) = negotiate::<Negotiated, Negotiated>();
+        assert_eq!(server.unwrap().cipher_suite(), CipherSuite::NoiseXX);
+        assert_eq!(client.unwrap().cipher_suite(), CipherSuite::NoiseXX);
+
+        let (server, client) = negotiate::<Negotiated, X25519DiffieHellman>();
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+        assert_eq!(server.cipher_suite(), CipherSuite::X25519Aes128Gcm);
+        client.send("hello").unwrap();
+        assert_eq!(server.recv().unwrap(), Some("hello".to_string()));
+
+        let (server, client) = negotiate::<Negotiated, PrimeDiffieHellman>();
+        let strongest_ecb = CipherSuite::DhAes128Ecb(DhGroup::Ffdhe4096);
+        assert_eq!(server.unwrap().cipher_suite(), strongest_ecb);
+        assert_eq!(client.unwrap().cipher_suite(), strongest_ecb);
+
+        for suite in CipherSuite::ALL {
+            assert_eq!(CipherSuite::from_id(suite.id()), Some(suite));
+            assert_eq!(Negotiated::for_suite(suite).unwrap().suite(), suite);
+        }
+    }
+
+    #[test]
+    fn peers_without_a_mutual_suite_say_so() {
+        let (server, client) = negotiate::<NoiseXX, X25519DiffieHellman>();
+        let client_err = client.err().unwrap();
+        assert_eq!(client_err.kind(), ErrorKind::Unsupported);
+        assert_eq!(
+            client_err.to_string(),
+            "no cipher suite in common: we offer X25519_AES_128_GCM; \
+             the peer offers NOISE_XX_25519_AES_128_GCM"
+        );
+        let server_err = server.err().unwrap();
+        assert_eq!(server_err.kind(), ErrorKind::Unsupported);
+        assert!(server_err.to_string().contains("no cipher suite in common"));
+    }
+
+    #[test]
+    fn stripping_suites_from_the_hello_is_detected() {
+        let identity = ServerIdentity::generate();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            EncryptedStream::<Negotiated>::dh_handshake(socket, &identity)
+        });
+
+        // rewrite the client's hello to offer only the weakest AEAD suite, then stay out of the way
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut client_side, _) = proxy.accept().unwrap();
+            let mut server_side = TcpStream::connect(addr).unwrap();
+            read_record(&mut client_side).unwrap();
+            let weakest = CipherSuite::DhAes128Gcm(DhGroup::Modp2048);
+            write_record(&mut server_side, &[PROTOCOL_VERSION, 1, weakest.id()]).unwrap();
+            let (from, to) = (client_side.try_clone().unwrap(), server_side.try_clone());
+            thread::spawn(move || record_pump(from, to.unwrap(), Arc::default()));
+            record_pump(server_side, client_side, Arc::default());
+        });
+
+        let socket = TcpStream::connect(proxy_addr).unwrap();
+        let client = EncryptedStream::<Negotiated>::dh_handshake_client(socket, |_| Ok(()));
+        let client_err = client.err().unwrap();
+        assert_eq!(client_err.kind(), ErrorKind::InvalidData);
+        assert!(client_err.to_string().contains("tampered with"));
+        // the server can't tell, but the client never sends it anything
+        assert!(server.join().unwrap().is_ok());
     }
 }
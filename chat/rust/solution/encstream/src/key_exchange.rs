@@ -0,0 +1,254 @@
+use crate::{handshake_aborted, read_record, write_record, CipherSuite};
+use crypto_utils::{
+    AeadDiffieHellman, Crypto, CryptoError, NoiseStaticKey, NoiseXX, PrimeDiffieHellman,
+    ServerIdentity, ServerPublicKey, X25519DiffieHellman, NOISE_KEY_LEN,
+};
+use std::io::{self, ErrorKind, Read, Write};
+
+// An Ed25519 identity key followed by its signature, as carried in the second Noise message
+const IDENTITY_LEN: usize = 32;
+
+/** How a cipher suite agrees on session keys over a fresh connection.
+    EncryptedStream::dh_handshake and dh_handshake_client pick the implementation from
+    the stream's type parameter, so switching handshakes is just a matter of naming another C.
+    self is a fresh instance to run the handshake with (e.g. one built with_rng);
+    both sides return (sending, receiving) ciphers.
+*/
+pub trait KeyExchange: Crypto + Clone + Default {
+    // The suite this instance runs, as named in the hello exchange
+    fn suite(&self) -> CipherSuite;
+
+    // A fresh instance running suite, or None if this type can't
+    fn for_suite(suite: CipherSuite) -> Option<Self>;
+
+    // Everything for_suite accepts, strongest first; what we offer unless handed an instance
+    fn supported_suites() -> Vec<CipherSuite> {
+        CipherSuite::ALL
+            .into_iter()
+            .filter(|&suite| Self::for_suite(suite).is_some())
+            .collect()
+    }
+
+    fn server_handshake<S: Read + Write>(
+        self,
+        socket: &mut S,
+        identity: &ServerIdentity,
+    ) -> io::Result<(Self, Self)>;
+
+    fn client_handshake<S, F>(self, socket: &mut S, check_identity: F) -> io::Result<(Self, Self)>
+    where
+        S: Read + Write,
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>;
+
+    // Authenticate the client with static_key, in suites that authenticate clients at all
+    fn set_static_key(&mut self, _static_key: &NoiseStaticKey) {}
+
+    // The peer's long-term key as the handshake authenticated it, in suites that have one
+    fn peer_static_key(&self) -> Option<&[u8; NOISE_KEY_LEN]> {
+        None
+    }
+}
+
+impl KeyExchange for PrimeDiffieHellman {
+    fn suite(&self) -> CipherSuite {
+        CipherSuite::DhAes128Ecb(self.group())
+    }
+
+    fn for_suite(suite: CipherSuite) -> Option<Self> {
+        match suite {
+            CipherSuite::DhAes128Ecb(group) => Some(PrimeDiffieHellman::with_group(group)),
+            _ => None,
+        }
+    }
+
+    fn server_handshake<S: Read + Write>(
+        self,
+        socket: &mut S,
+        identity: &ServerIdentity,
+    ) -> io::Result<(Self, Self)> {
+        signed_dh_server(self, socket, identity)
+    }
+
+    fn client_handshake<S, F>(self, socket: &mut S, check_identity: F) -> io::Result<(Self, Self)>
+    where
+        S: Read + Write,
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        signed_dh_client(self, socket, check_identity)
+    }
+}
+
+impl KeyExchange for AeadDiffieHellman {
+    fn suite(&self) -> CipherSuite {
+        CipherSuite::DhAes128Gcm(self.group())
+    }
+
+    fn for_suite(suite: CipherSuite) -> Option<Self> {
+        match suite {
+            CipherSuite::DhAes128Gcm(group) => Some(AeadDiffieHellman::with_group(group)),
+            _ => None,
+        }
+    }
+
+    fn server_handshake<S: Read + Write>(
+        self,
+        socket: &mut S,
+        identity: &ServerIdentity,
+    ) -> io::Result<(Self, Self)> {
+        signed_dh_server(self, socket, identity)
+    }
+
+    fn client_handshake<S, F>(self, socket: &mut S, check_identity: F) -> io::Result<(Self, Self)>
+    where
+        S: Read + Write,
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        signed_dh_client(self, socket, check_identity)
+    }
+}
+
+impl KeyExchange for X25519DiffieHellman {
+    fn suite(&self) -> CipherSuite {
+        CipherSuite::X25519Aes128Gcm
+    }
+
+    fn for_suite(suite: CipherSuite) -> Option<Self> {
+        (suite == CipherSuite::X25519Aes128Gcm).then(X25519DiffieHellman::new)
+    }
+
+    fn server_handshake<S: Read + Write>(
+        self,
+        socket: &mut S,
+        identity: &ServerIdentity,
+    ) -> io::Result<(Self, Self)> {
+        signed_dh_server(self, socket, identity)
+    }
+
+    fn client_handshake<S, F>(self, socket: &mut S, check_identity: F) -> io::Result<(Self, Self)>
+    where
+        S: Read + Write,
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        signed_dh_client(self, socket, check_identity)
+    }
+}
+
+/** Noise_XX, with the server's static key vouched for by its long-term identity.
+    The second handshake message carries the identity key and its signature over the
+    server's Noise static key, so known_hosts pinning works exactly as for the DH suites.
+    The server's static key comes from its identity; the client's from self, or a fresh one
+    if it has none, which the server can look at through peer_static_key.
+*/
+impl KeyExchange for NoiseXX {
+    fn suite(&self) -> CipherSuite {
+        CipherSuite::NoiseXX
+    }
+
+    fn for_suite(suite: CipherSuite) -> Option<Self> {
+        (suite == CipherSuite::NoiseXX).then(NoiseXX::default)
+    }
+
+    fn server_handshake<S: Read + Write>(
+        self,
+        socket: &mut S,
+        identity: &ServerIdentity,
+    ) -> io::Result<(Self, Self)> {
+        let mut noise = self.responder(&identity.noise_static_key())?;
+
+        let e = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+        noise.read_message(&e).map_err(handshake_aborted)?;
+
+        let mut payload = identity.public_key().as_bytes().to_vec();
+        payload.extend_from_slice(&identity.sign_handshake(noise.static_public_key()));
+        write_record(socket, &noise.write_message(&payload)?)?;
+
+        let s = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+        noise.read_message(&s).map_err(handshake_aborted)?;
+
+        noise.finish().map_err(handshake_aborted)
+    }
+
+    fn client_handshake<S, F>(self, socket: &mut S, check_identity: F) -> io::Result<(Self, Self)>
+    where
+        S: Read + Write,
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        let mut noise = self.initiator()?;
+        write_record(socket, &noise.write_message(&[])?)?;
+
+        let reply = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+        let payload = noise.read_message(&reply).map_err(handshake_aborted)?;
+        if payload.len() < IDENTITY_LEN {
+            return Err(handshake_aborted(CryptoError::Truncated));
+        }
+        let (identity_bytes, signature) = payload.split_at(IDENTITY_LEN);
+        let server_key = ServerPublicKey::from_bytes(identity_bytes).map_err(handshake_aborted)?;
+        check_identity(&server_key)?;
+        let server_static = noise
+            .remote_static()
+            .ok_or(handshake_aborted(CryptoError::BadKey))?;
+        server_key
+            .verify_handshake(server_static, signature)
+            .map_err(handshake_aborted)?;
+
+        write_record(socket, &noise.write_message(&[])?)?;
+        noise.finish().map_err(handshake_aborted)
+    }
+
+    fn set_static_key(&mut self, static_key: &NoiseStaticKey) {
+        *self = std::mem::take(self).with_static_key(static_key.clone());
+    }
+
+    fn peer_static_key(&self) -> Option<&[u8; NOISE_KEY_LEN]> {
+        self.remote_static_key()
+    }
+}
+
+// we announce the server's long-term identity key, then send our ephemeral public key
+// signed by it, so clients that remember the identity can detect a man in the middle.
+fn signed_dh_server<C: Crypto + Clone, S: Read + Write>(
+    mut crypto: C,
+    socket: &mut S,
+    identity: &ServerIdentity,
+) -> io::Result<(C, C)> {
+    // public keys are as wide as the group's prime, so they travel as records too
+    let pubkey = crypto.init_keys();
+    write_record(socket, identity.public_key().as_bytes())?;
+    write_record(socket, &pubkey)?;
+    write_record(socket, &identity.sign_handshake(&pubkey))?;
+
+    let pub_key_bytes = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+
+    // a bad peer key means someone is trying to force the shared secret; hang up
+    crypto
+        .handshake(&pub_key_bytes)
+        .map_err(handshake_aborted)?;
+    Ok((crypto.clone(), crypto))
+}
+
+// check_identity must accept the announced identity key, and the server's DH key
+// must carry a valid signature from it, before we send anything.
+fn signed_dh_client<C, S, F>(mut crypto: C, socket: &mut S, check_identity: F) -> io::Result<(C, C)>
+where
+    C: Crypto + Clone,
+    S: Read + Write,
+    F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+{
+    let identity_bytes = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+    let pub_key_bytes = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+    let signature = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+
+    let server_key = ServerPublicKey::from_bytes(&identity_bytes).map_err(handshake_aborted)?;
+    check_identity(&server_key)?;
+    server_key
+        .verify_handshake(&pub_key_bytes, &signature)
+        .map_err(handshake_aborted)?;
+
+    let pubkey = crypto.init_keys();
+    write_record(socket, &pubkey)?;
+
+    crypto
+        .handshake(&pub_key_bytes)
+        .map_err(handshake_aborted)?;
+    Ok((crypto.clone(), crypto))
+}
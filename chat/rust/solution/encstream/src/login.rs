@@ -0,0 +1,380 @@
+use crate::{confirm_psk, handshake_aborted, read_record, write_record, Role};
+use crypto_utils::{Crypto, ServerIdentity, SrpClient, SrpServer, SrpVerifier, SRP_SALT_LEN};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use zeroize::Zeroize;
+
+// What the client asks for, in the first byte of its login request
+const GUEST: u8 = 0;
+const LOGIN: u8 = 1;
+const REGISTER: u8 = 2;
+
+// How the server answers a login or registration, in the first byte of its reply
+const CHALLENGE: u8 = 0;
+const NAME_TAKEN: u8 = 1;
+const INVALID_NAME: u8 = 2;
+const NO_ACCOUNTS: u8 = 3;
+
+/** Where a server keeps its registered accounts.
+    Only SRP verifiers are stored, never passwords; see crypto_utils::SrpVerifier.
+*/
+pub trait AccountStore {
+    fn lookup(&self, username: &str) -> Option<SrpVerifier>;
+    // Holds the name for a registration in progress; fails with ErrorKind::AlreadyExists
+    // if someone has already registered or reserved it
+    fn reserve(&self, username: &str) -> io::Result<()>;
+    // Stores the account under a name reserved for it
+    fn register(&self, username: &str, verifier: SrpVerifier) -> io::Result<()>;
+    // Frees a reserved name whose registration didn't go through
+    fn release(&self, username: &str);
+}
+
+// A reserved name, released again unless the registration it was held for is stored
+struct Reservation<'a> {
+    accounts: &'a dyn AccountStore,
+    username: &'a str,
+    registered: bool,
+}
+
+impl Reservation<'_> {
+    fn register(mut self, verifier: SrpVerifier) -> io::Result<()> {
+        self.accounts.register(self.username, verifier)?;
+        self.registered = true;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.registered {
+            self.accounts.release(self.username);
+        }
+    }
+}
+
+// What a client logs in with; without any it joins as a guest
+pub enum Credentials {
+    Login { username: String, password: String },
+    Register { username: String, password: String },
+}
+
+impl Credentials {
+    pub fn username(&self) -> &str {
+        match self {
+            Credentials::Login { username, .. } | Credentials::Register { username, .. } => {
+                username
+            }
+        }
+    }
+}
+
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        match self {
+            Credentials::Login { password, .. } | Credentials::Register { password, .. } => {
+                password.zeroize()
+            }
+        }
+    }
+}
+
+// The password stays out of logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Credentials::Login { .. } => "Login",
+            Credentials::Register { .. } => "Register",
+        };
+        f.debug_struct(kind)
+            .field("username", &self.username())
+            .finish_non_exhaustive()
+    }
+}
+
+// Account names end up in chat lines, so they can't be empty or contain whitespace
+pub fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= u8::MAX as usize
+        && !username
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "malformed login")
+}
+
+fn name_taken(username: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!("{} is already registered", username),
+    )
+}
+
+// Deliberately the same whether the account exists or not
+fn login_failed() -> io::Error {
+    io::Error::new(
+        ErrorKind::PermissionDenied,
+        "login failed: wrong username or password",
+    )
+}
+
+/** Server side of the login step, run on the freshly agreed keys.
+    The client's request is [kind][name length: u8][name], followed for a registration by
+    [verifier length: u16][verifier], and then for both by its SRP public key A.
+    We answer [CHALLENGE][salt][iterations: u32][B], or just the reason we won't.
+    Unknown names get a decoy challenge, so they fail the same way a wrong password does.
+    A registration holds its name from the request on, so a second one for the same name is
+    turned away right there, but it's only stored once the client has confirmed the password
+    behind its verifier.
+*/
+pub(crate) fn server_login<C: Crypto, S: Read + Write>(
+    socket: &mut S,
+    keys: (C, C),
+    identity: &ServerIdentity,
+    accounts: Option<&dyn AccountStore>,
+) -> io::Result<((C, C), Option<String>)> {
+    let request = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+    let request = keys.1.decrypt(&request).map_err(handshake_aborted)?;
+    let (&kind, rest) = request.split_first().ok_or_else(malformed)?;
+    if kind == GUEST {
+        return Ok((keys, None));
+    }
+    let (&name_len, rest) = rest.split_first().ok_or_else(malformed)?;
+    if rest.len() < name_len as usize {
+        return Err(malformed());
+    }
+    let (username, rest) = rest.split_at(name_len as usize);
+    let username = std::str::from_utf8(username).unwrap_or_default();
+
+    let reply = |socket: &mut S, code: u8| write_record(socket, &keys.0.encrypt(&[code])?);
+    let Some(accounts) = accounts else {
+        reply(socket, NO_ACCOUNTS)?;
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "the client tried to log in, but we have no accounts",
+        ));
+    };
+    if !valid_username(username) {
+        reply(socket, INVALID_NAME)?;
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "the client asked for an invalid username",
+        ));
+    }
+
+    let (verifier, client_public, reservation) = match kind {
+        LOGIN => (
+            accounts
+                .lookup(username)
+                .unwrap_or_else(|| SrpVerifier::decoy(identity, username)),
+            rest,
+            None,
+        ),
+        REGISTER => {
+            if rest.len() < 2 {
+                return Err(malformed());
+            }
+            let (len, rest) = rest.split_at(2);
+            let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+            if rest.len() < len {
+                return Err(malformed());
+            }
+            let (verifier, client_public) = rest.split_at(len);
+            let verifier = SrpVerifier::from_bytes(verifier).map_err(handshake_aborted)?;
+            if let Err(e) = accounts.reserve(username) {
+                if e.kind() != ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+                reply(socket, NAME_TAKEN)?;
+                return Err(name_taken(username));
+            }
+            let reservation = Reservation {
+                accounts,
+                username,
+                registered: false,
+            };
+            (verifier, client_public, Some(reservation))
+        }
+        _ => return Err(malformed()),
+    };
+
+    let srp = SrpServer::new(&keys.0, username, &verifier);
+    let mut challenge = vec![CHALLENGE];
+    challenge.extend_from_slice(verifier.salt());
+    challenge.extend_from_slice(&verifier.iterations().to_be_bytes());
+    challenge.extend_from_slice(&srp.public_key());
+    write_record(socket, &keys.0.encrypt(&challenge)?)?;
+
+    let key = srp.session_key(client_public).map_err(handshake_aborted)?;
+    // the client confirms first: our confirmation would let it test password guesses offline
+    let keys =
+        confirm_psk(socket, keys, &key, Role::Server, Role::Client).map_err(|_| login_failed())?;
+    if let Some(reservation) = reservation {
+        reservation.register(verifier)?;
+    }
+    Ok((keys, Some(username.to_string())))
+}
+
+// Client side of the login step; see server_login
+pub(crate) fn client_login<C: Crypto, S: Read + Write>(
+    socket: &mut S,
+    keys: (C, C),
+    credentials: Option<&Credentials>,
+) -> io::Result<((C, C), Option<String>)> {
+    let Some(credentials) = credentials else {
+        write_record(socket, &keys.0.encrypt(&[GUEST])?)?;
+        return Ok((keys, None));
+    };
+    let (kind, username, password) = match credentials {
+        Credentials::Login { username, password } => (LOGIN, username, password),
+        Credentials::Register { username, password } => (REGISTER, username, password),
+    };
+    if !valid_username(username) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "usernames can't be empty or contain spaces",
+        ));
+    }
+
+    let srp = SrpClient::new(&keys.0, username, password);
+    let mut request = vec![kind, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    if kind == REGISTER {
+        let verifier = SrpVerifier::new(username, password).to_bytes();
+        request.extend_from_slice(&(verifier.len() as u16).to_be_bytes());
+        request.extend_from_slice(&verifier);
+    }
+    request.extend_from_slice(&srp.public_key());
+    write_record(socket, &keys.0.encrypt(&request)?)?;
+
+    let reply = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+    let reply = keys.1.decrypt(&reply).map_err(handshake_aborted)?;
+    let challenge = match reply.split_first() {
+        Some((&CHALLENGE, challenge)) if challenge.len() > SRP_SALT_LEN + 4 => challenge,
+        Some((&NAME_TAKEN, _)) => return Err(name_taken(username)),
+        Some((&INVALID_NAME, _)) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("the server won't accept {} as a username", username),
+            ))
+        }
+        Some((&NO_ACCOUNTS, _)) => {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the server has no accounts to log in to",
+            ))
+        }
+        _ => return Err(malformed()),
+    };
+    let (salt, rest) = challenge.split_at(SRP_SALT_LEN);
+    let (iterations, server_public) = rest.split_at(4);
+    let iterations = u32::from_be_bytes(iterations.try_into().unwrap());
+
+    let key = srp
+        .session_key(salt, iterations, server_public)
+        .map_err(handshake_aborted)?;
+    let keys =
+        confirm_psk(socket, keys, &key, Role::Client, Role::Client).map_err(|_| login_failed())?;
+    Ok((keys, Some(username.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{connected_pair, MemoryAccounts};
+    use crate::Pipe;
+    use crypto_utils::X25519DiffieHellman;
+    use std::sync::Arc;
+    use std::thread;
+
+    type Keyed = (Pipe, (X25519DiffieHellman, X25519DiffieHellman));
+
+    // Both ends of a connection that's done with its key exchange, ready for another login step
+    fn keyed_pair() -> (Keyed, Keyed) {
+        let (server, client) = connected_pair();
+        let keyed = |stream: crate::EncryptedStream<_, Pipe>| {
+            let records = stream.records;
+            (stream.socket, (records.send_crypto, records.recv_crypto))
+        };
+        (keyed(server), keyed(client))
+    }
+
+    // A registration request whose verifier need not match the password the client proves
+    fn register_request(username: &str, verifier_password: &str, srp: &SrpClient) -> Vec<u8> {
+        let mut request = vec![REGISTER, username.len() as u8];
+        request.extend_from_slice(username.as_bytes());
+        let verifier = SrpVerifier::new(username, verifier_password).to_bytes();
+        request.extend_from_slice(&(verifier.len() as u16).to_be_bytes());
+        request.extend_from_slice(&verifier);
+        request.extend_from_slice(&srp.public_key());
+        request
+    }
+
+    #[test]
+    fn registrations_that_fail_leave_the_name_free() {
+        let accounts = Arc::new(MemoryAccounts::default());
+        let server = |socket: Pipe, keys| {
+            let accounts = Arc::clone(&accounts);
+            thread::spawn(move || {
+                let mut socket = socket;
+                let identity = ServerIdentity::generate();
+                server_login(&mut socket, keys, &identity, Some(&*accounts))
+            })
+        };
+
+        // the client can't confirm the password behind the verifier it sent
+        let ((server_socket, server_keys), (mut socket, keys)) = keyed_pair();
+        let server_thread = server(server_socket, server_keys);
+        let srp = SrpClient::new(&keys.0, "alice", "hunter3");
+        let request = register_request("alice", "hunter2", &srp);
+        write_record(&mut socket, &keys.0.encrypt(&request).unwrap()).unwrap();
+        let challenge = read_record(&mut socket).unwrap().unwrap();
+        let challenge = keys.1.decrypt(&challenge).unwrap();
+        let (salt, rest) = challenge[1..].split_at(SRP_SALT_LEN);
+        let (iterations, server_public) = rest.split_at(4);
+        let iterations = u32::from_be_bytes(iterations.try_into().unwrap());
+        let key = srp.session_key(salt, iterations, server_public).unwrap();
+        assert!(confirm_psk(&mut socket, keys, &key, Role::Client, Role::Client).is_err());
+        let err = server_thread.join().unwrap().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(accounts.lookup("alice").is_none());
+
+        // the client hangs up halfway through, and until then the name is held for it
+        let register = Credentials::Register {
+            username: "alice".to_string(),
+            password: "hunter2".to_string(),
+        };
+        let ((server_socket, server_keys), (mut socket, keys)) = keyed_pair();
+        let server_thread = server(server_socket, server_keys);
+        let srp = SrpClient::new(&keys.0, "alice", "hunter2");
+        let request = register_request("alice", "hunter2", &srp);
+        write_record(&mut socket, &keys.0.encrypt(&request).unwrap()).unwrap();
+        read_record(&mut socket).unwrap().unwrap();
+        let ((racing_socket, racing_keys), (mut racer, keys)) = keyed_pair();
+        let racing_thread = server(racing_socket, racing_keys);
+        let racing = client_login(&mut racer, keys, Some(&register));
+        assert_eq!(racing.err().unwrap().kind(), ErrorKind::AlreadyExists);
+        let racing = racing_thread.join().unwrap();
+        assert_eq!(racing.err().unwrap().kind(), ErrorKind::AlreadyExists);
+        drop(socket);
+        assert!(server_thread.join().unwrap().is_err());
+        assert!(accounts.lookup("alice").is_none());
+
+        // so the name is still there for whoever completes a registration
+        for taken in [false, true] {
+            let ((server_socket, server_keys), (mut socket, keys)) = keyed_pair();
+            let server_thread = server(server_socket, server_keys);
+            let client = client_login(&mut socket, keys, Some(&register));
+            let server = server_thread.join().unwrap();
+            if taken {
+                assert_eq!(client.err().unwrap().kind(), ErrorKind::AlreadyExists);
+                assert_eq!(server.err().unwrap().kind(), ErrorKind::AlreadyExists);
+            } else {
+                assert_eq!(client.unwrap().1.as_deref(), Some("alice"));
+                assert_eq!(server.unwrap().1.as_deref(), Some("alice"));
+            }
+            assert!(accounts.lookup("alice").is_some());
+        }
+    }
+}
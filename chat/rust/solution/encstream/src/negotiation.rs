@@ -0,0 +1,393 @@
+use crate::{read_record, write_record, KeyExchange, Role};
+use crypto_utils::{
+    AeadDiffieHellman, Crypto, CryptoError, DhGroup, NoiseStaticKey, NoiseXX, PreSharedKey,
+    PrimeDiffieHellman, ServerIdentity, ServerPublicKey, X25519DiffieHellman, NOISE_KEY_LEN,
+};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use zeroize::Zeroizing;
+
+// Bumped whenever the handshake changes incompatibly; peers settle on the lower of their two
+pub const PROTOCOL_VERSION: u8 = 1;
+const MIN_PROTOCOL_VERSION: u8 = 1;
+
+// The suite id in a server hello that found nothing in common with the client
+const NO_SUITE: u8 = 0;
+
+/** A key agreement and the cipher it feeds, as offered in the hello exchange.
+    Suites are ranked by their position in ALL, so both ends agree on what "strongest" means
+    and adding a better suite at the front rolls it out to every peer that knows it.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherSuite {
+    NoiseXX,
+    X25519Aes128Gcm,
+    DhAes128Gcm(DhGroup),
+    DhAes128Ecb(DhGroup),
+}
+
+impl CipherSuite {
+    // Every suite we know, strongest first
+    pub const ALL: [CipherSuite; 14] = [
+        CipherSuite::NoiseXX,
+        CipherSuite::X25519Aes128Gcm,
+        CipherSuite::DhAes128Gcm(DhGroup::Ffdhe4096),
+        CipherSuite::DhAes128Gcm(DhGroup::Modp4096),
+        CipherSuite::DhAes128Gcm(DhGroup::Ffdhe3072),
+        CipherSuite::DhAes128Gcm(DhGroup::Modp3072),
+        CipherSuite::DhAes128Gcm(DhGroup::Ffdhe2048),
+        CipherSuite::DhAes128Gcm(DhGroup::Modp2048),
+        CipherSuite::DhAes128Ecb(DhGroup::Ffdhe4096),
+        CipherSuite::DhAes128Ecb(DhGroup::Modp4096),
+        CipherSuite::DhAes128Ecb(DhGroup::Ffdhe3072),
+        CipherSuite::DhAes128Ecb(DhGroup::Modp3072),
+        CipherSuite::DhAes128Ecb(DhGroup::Ffdhe2048),
+        CipherSuite::DhAes128Ecb(DhGroup::Modp2048),
+    ];
+
+    // The byte that names this suite on the wire; never reuse one
+    pub fn id(self) -> u8 {
+        match self {
+            CipherSuite::DhAes128Ecb(group) => 0x10 | group_id(group),
+            CipherSuite::DhAes128Gcm(group) => 0x20 | group_id(group),
+            CipherSuite::X25519Aes128Gcm => 0x31,
+            CipherSuite::NoiseXX => 0x41,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<CipherSuite> {
+        CipherSuite::ALL.into_iter().find(|suite| suite.id() == id)
+    }
+}
+
+fn group_id(group: DhGroup) -> u8 {
+    match group {
+        DhGroup::Modp2048 => 1,
+        DhGroup::Modp3072 => 2,
+        DhGroup::Modp4096 => 3,
+        DhGroup::Ffdhe2048 => 4,
+        DhGroup::Ffdhe3072 => 5,
+        DhGroup::Ffdhe4096 => 6,
+    }
+}
+
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherSuite::NoiseXX => write!(f, "NOISE_XX_25519_AES_128_GCM"),
+            CipherSuite::X25519Aes128Gcm => write!(f, "X25519_AES_128_GCM"),
+            CipherSuite::DhAes128Gcm(group) => {
+                write!(f, "{}_AES_128_GCM", format!("{:?}", group).to_uppercase())
+            }
+            CipherSuite::DhAes128Ecb(group) => {
+                write!(f, "{}_AES_128_ECB", format!("{:?}", group).to_uppercase())
+            }
+        }
+    }
+}
+
+// Suites travel as a count followed by that many ids
+fn encode_suites(suites: &[CipherSuite], out: &mut Vec<u8>) {
+    out.push(suites.len() as u8);
+    out.extend(suites.iter().map(|suite| suite.id()));
+}
+
+fn decode_suites(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    match bytes.split_first() {
+        Some((&count, ids)) if ids.len() == count as usize => Ok(ids.to_vec()),
+        _ => Err(io::Error::new(ErrorKind::InvalidData, "malformed hello")),
+    }
+}
+
+// Ids from a newer peer that we don't know are listed rather than dropped, to help debugging
+fn describe(ids: &[u8]) -> String {
+    let names: Vec<String> = ids
+        .iter()
+        .map(|&id| match CipherSuite::from_id(id) {
+            Some(suite) => suite.to_string(),
+            None => format!("unknown suite {:#04x}", id),
+        })
+        .collect();
+    if names.is_empty() {
+        "nothing".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+fn strongest_mutual(ours: &[CipherSuite], theirs: &[u8]) -> Option<CipherSuite> {
+    CipherSuite::ALL
+        .into_iter()
+        .find(|suite| ours.contains(suite) && theirs.contains(&suite.id()))
+}
+
+fn no_common_version(theirs: u8) -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        format!(
+            "no protocol version in common: we speak {} to {}, the peer speaks {}",
+            MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, theirs
+        ),
+    )
+}
+
+fn no_common_suite(ours: &[CipherSuite], theirs: &[u8]) -> io::Error {
+    let ours: Vec<u8> = ours.iter().map(|suite| suite.id()).collect();
+    io::Error::new(
+        ErrorKind::Unsupported,
+        format!(
+            "no cipher suite in common: we offer {}; the peer offers {}",
+            describe(&ours),
+            describe(theirs)
+        ),
+    )
+}
+
+/** Server side of the hello exchange.
+    The client hello is [version][count][suite ids...], and we answer with
+    [version][chosen id][count][our ids...]. The choice is NO_SUITE if there is none;
+    our list goes along either way, so the client can tell its user why.
+    Returns the chosen suite and both hellos, which confirm_hellos later vouches for.
+*/
+pub(crate) fn server_hello<S: Read + Write>(
+    socket: &mut S,
+    ours: &[CipherSuite],
+) -> io::Result<(CipherSuite, Vec<u8>)> {
+    let client_hello = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+    let (&client_version, client_suites) = client_hello
+        .split_first()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed hello"))?;
+    let theirs = decode_suites(client_suites)?;
+
+    let version = client_version.min(PROTOCOL_VERSION);
+    let choice = if version < MIN_PROTOCOL_VERSION {
+        None
+    } else {
+        strongest_mutual(ours, &theirs)
+    };
+    let mut server_hello = vec![version, choice.map_or(NO_SUITE, CipherSuite::id)];
+    encode_suites(ours, &mut server_hello);
+    write_record(socket, &server_hello)?;
+
+    match choice {
+        Some(suite) => Ok((suite, [client_hello, server_hello].concat())),
+        None if version < MIN_PROTOCOL_VERSION => Err(no_common_version(client_version)),
+        None => Err(no_common_suite(ours, &theirs)),
+    }
+}
+
+// Client side of the hello exchange; see server_hello
+pub(crate) fn client_hello<S: Read + Write>(
+    socket: &mut S,
+    ours: &[CipherSuite],
+) -> io::Result<(CipherSuite, Vec<u8>)> {
+    let mut client_hello = vec![PROTOCOL_VERSION];
+    encode_suites(ours, &mut client_hello);
+    write_record(socket, &client_hello)?;
+
+    let server_hello = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+    if server_hello.len() < 2 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "malformed hello"));
+    }
+    let (version, chosen) = (server_hello[0], server_hello[1]);
+    let theirs = decode_suites(&server_hello[2..])?;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(no_common_version(version));
+    }
+    match CipherSuite::from_id(chosen) {
+        Some(suite) if ours.contains(&suite) => Ok((suite, [client_hello, server_hello].concat())),
+        Some(_) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "the server chose a cipher suite we did not offer",
+        )),
+        None => Err(no_common_suite(ours, &theirs)),
+    }
+}
+
+/** Prove that both ends saw the same hellos, now that there are keys to do it with.
+    The hellos themselves travel in the clear, so someone in the middle could strip the strong
+    suites from them; the server sends back everything it saw, encrypted under its new key,
+    and the client hangs up unless that matches what it sent and received.
+*/
+pub(crate) fn confirm_hellos<C: Crypto, S: Read + Write>(
+    socket: &mut S,
+    keys: (C, C),
+    hellos: &[u8],
+    role: Role,
+) -> io::Result<(C, C)> {
+    match role {
+        Role::Server => write_record(socket, &keys.0.encrypt(hellos)?)?,
+        Role::Client => {
+            let confirmation = read_record(socket)?.ok_or(ErrorKind::UnexpectedEof)?;
+            match keys.1.decrypt(&confirmation) {
+                Ok(seen) if seen == hellos => {}
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "the server saw different hellos: the negotiation was tampered with",
+                    ))
+                }
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/** Whichever suite the hello exchange settled on, for peers that accept any of them.
+    `EncryptedStream::<Negotiated>::dh_handshake` offers every suite in CipherSuite::ALL;
+    naming a concrete Crypto instead restricts the offer to what that type can run.
+*/
+#[derive(Clone, Debug)]
+pub enum Negotiated {
+    DhAes128Ecb(PrimeDiffieHellman),
+    DhAes128Gcm(AeadDiffieHellman),
+    X25519Aes128Gcm(X25519DiffieHellman),
+    NoiseXX(NoiseXX),
+}
+
+// Forward a call to the suite inside
+macro_rules! dispatch {
+    ($negotiated:expr, $crypto:ident => $call:expr) => {
+        match $negotiated {
+            Negotiated::DhAes128Ecb($crypto) => $call,
+            Negotiated::DhAes128Gcm($crypto) => $call,
+            Negotiated::X25519Aes128Gcm($crypto) => $call,
+            Negotiated::NoiseXX($crypto) => $call,
+        }
+    };
+}
+
+impl Crypto for Negotiated {
+    // Each suite has its own key type, so public keys only ever exist here in wire form
+    type PublicKey = Vec<u8>;
+
+    fn init_keys(&mut self) -> Vec<u8> {
+        dispatch!(self, crypto => crypto.init_keys())
+    }
+
+    fn handshake(&mut self, other_pub_key: &[u8]) -> Result<(), CryptoError> {
+        dispatch!(self, crypto => crypto.handshake(other_pub_key))
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        dispatch!(self, crypto => crypto.encrypt(plaintext))
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        dispatch!(self, crypto => crypto.decrypt(ciphertext))
+    }
+
+    fn rekey(&mut self) -> Result<(), CryptoError> {
+        dispatch!(self, crypto => crypto.rekey())
+    }
+
+    fn mix_psk(&mut self, psk: &PreSharedKey) -> Result<(), CryptoError> {
+        dispatch!(self, crypto => crypto.mix_psk(psk))
+    }
+
+    fn stream_key(&self, salt: &[u8], context: &[u8]) -> Result<Zeroizing<[u8; 16]>, CryptoError> {
+        dispatch!(self, crypto => crypto.stream_key(salt, context))
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        dispatch!(self, crypto => crypto.fill_random(buf))
+    }
+
+    fn serialize(&self, pub_key: &Vec<u8>) -> Vec<u8> {
+        pub_key.clone()
+    }
+
+    fn deserialize(&self, pub_key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Ok(pub_key.to_vec())
+    }
+}
+
+impl KeyExchange for Negotiated {
+    fn suite(&self) -> CipherSuite {
+        dispatch!(self, crypto => crypto.suite())
+    }
+
+    fn for_suite(suite: CipherSuite) -> Option<Self> {
+        match suite {
+            CipherSuite::DhAes128Ecb(_) => {
+                PrimeDiffieHellman::for_suite(suite).map(Self::DhAes128Ecb)
+            }
+            CipherSuite::DhAes128Gcm(_) => {
+                AeadDiffieHellman::for_suite(suite).map(Self::DhAes128Gcm)
+            }
+            CipherSuite::X25519Aes128Gcm => {
+                X25519DiffieHellman::for_suite(suite).map(Self::X25519Aes128Gcm)
+            }
+            CipherSuite::NoiseXX => NoiseXX::for_suite(suite).map(Self::NoiseXX),
+        }
+    }
+
+    fn server_handshake<S: Read + Write>(
+        self,
+        socket: &mut S,
+        identity: &ServerIdentity,
+    ) -> io::Result<(Self, Self)> {
+        match self {
+            Negotiated::DhAes128Ecb(crypto) => {
+                wrap(crypto.server_handshake(socket, identity), Self::DhAes128Ecb)
+            }
+            Negotiated::DhAes128Gcm(crypto) => {
+                wrap(crypto.server_handshake(socket, identity), Self::DhAes128Gcm)
+            }
+            Negotiated::X25519Aes128Gcm(crypto) => wrap(
+                crypto.server_handshake(socket, identity),
+                Self::X25519Aes128Gcm,
+            ),
+            Negotiated::NoiseXX(crypto) => {
+                wrap(crypto.server_handshake(socket, identity), Self::NoiseXX)
+            }
+        }
+    }
+
+    fn client_handshake<S, F>(self, socket: &mut S, check_identity: F) -> io::Result<(Self, Self)>
+    where
+        S: Read + Write,
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        match self {
+            Negotiated::DhAes128Ecb(crypto) => wrap(
+                crypto.client_handshake(socket, check_identity),
+                Self::DhAes128Ecb,
+            ),
+            Negotiated::DhAes128Gcm(crypto) => wrap(
+                crypto.client_handshake(socket, check_identity),
+                Self::DhAes128Gcm,
+            ),
+            Negotiated::X25519Aes128Gcm(crypto) => wrap(
+                crypto.client_handshake(socket, check_identity),
+                Self::X25519Aes128Gcm,
+            ),
+            Negotiated::NoiseXX(crypto) => wrap(
+                crypto.client_handshake(socket, check_identity),
+                Self::NoiseXX,
+            ),
+        }
+    }
+
+    fn set_static_key(&mut self, static_key: &NoiseStaticKey) {
+        dispatch!(self, crypto => crypto.set_static_key(static_key))
+    }
+
+    fn peer_static_key(&self) -> Option<&[u8; NOISE_KEY_LEN]> {
+        dispatch!(self, crypto => crypto.peer_static_key())
+    }
+}
+
+fn wrap<C>(
+    keys: io::Result<(C, C)>,
+    suite: fn(C) -> Negotiated,
+) -> io::Result<(Negotiated, Negotiated)> {
+    keys.map(|(send, recv)| (suite(send), suite(recv)))
+}
+
+// The strongest suite we know
+impl Default for Negotiated {
+    fn default() -> Self {
+        Negotiated::NoiseXX(NoiseXX::default())
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{Close, TryClone};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+
+/** One end of an in-memory duplex connection, so both ends of a handshake can run in a test
+    without any sockets. What one end writes the other reads, in order; writes never block,
+    the buffers just grow. Closing an end, or dropping its last clone, hangs up: the other end
+    reads whatever is left and then the end of the stream, and writes either way fail with
+    BrokenPipe.
+*/
+#[derive(Debug)]
+pub struct Pipe {
+    end: Arc<End>,
+}
+
+// Shared by an end and all its clones; when the last one goes, so does the connection
+#[derive(Debug)]
+struct End {
+    incoming: Arc<Channel>,
+    outgoing: Arc<Channel>,
+}
+
+// One direction of the connection
+#[derive(Debug, Default)]
+struct Channel {
+    buffer: Mutex<Buffer>,
+    readable: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    // Both ends of a fresh connection, like UnixStream::pair
+    pub fn pair() -> (Pipe, Pipe) {
+        let (there, back) = (Arc::<Channel>::default(), Arc::<Channel>::default());
+        let first = End {
+            incoming: Arc::clone(&back),
+            outgoing: Arc::clone(&there),
+        };
+        let second = End {
+            incoming: there,
+            outgoing: back,
+        };
+        (
+            Pipe {
+                end: Arc::new(first),
+            },
+            Pipe {
+                end: Arc::new(second),
+            },
+        )
+    }
+}
+
+impl Channel {
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+impl Read for Pipe {
+    // Blocks until the peer has written something or hung up
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let channel = &self.end.incoming;
+        let mut buffer = channel
+            .readable
+            .wait_while(channel.buffer.lock().unwrap(), |b| {
+                b.data.is_empty() && !b.closed
+            })
+            .unwrap();
+        buffer.data.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let channel = &self.end.outgoing;
+        let mut buffer = channel.buffer.lock().unwrap();
+        if buffer.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        buffer.data.extend(buf);
+        channel.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Close for Pipe {
+    fn close(&mut self) -> io::Result<()> {
+        self.end.incoming.close();
+        self.end.outgoing.close();
+        Ok(())
+    }
+}
+
+impl TryClone for Pipe {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Pipe {
+            end: Arc::clone(&self.end),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn hanging_up_reaches_the_peer_and_every_clone() {
+        let (mut ours, mut theirs) = Pipe::pair();
+        let mut clone = ours.try_clone().unwrap();
+
+        // a blocked read wakes up for the peer's write
+        let reader = thread::spawn(move || {
+            let mut buf = [0_u8; 5];
+            theirs.read_exact(&mut buf).unwrap();
+            (theirs, buf)
+        });
+        clone.write_all(b"hello").unwrap();
+        let (mut theirs, buf) = reader.join().unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // one clone is gone but ours still holds the end open, until it closes
+        drop(clone);
+        theirs.write_all(b"bye").unwrap();
+        ours.close().unwrap();
+        let mut rest = Vec::new();
+        ours.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"bye");
+        assert_eq!(theirs.read(&mut [0_u8; 1]).unwrap(), 0);
+        let err = theirs.write_all(b"anyone there?").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(ours.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+
+        // and dropping the last handle of an end hangs up too
+        let (ours, mut theirs) = Pipe::pair();
+        drop(ours);
+        assert_eq!(theirs.read(&mut [0_u8; 1]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{
+    RekeyPolicy, Role, CLIENT_TO_SERVER, CONTENT_DATA, CONTENT_KEY_UPDATE, CONTENT_STREAM,
+    INNER_HEADER_LEN, SERVER_TO_CLIENT,
+};
+use crypto_utils::{Crypto, CryptoError};
+use std::io::{self, ErrorKind};
+
+/** The record layer of an established connection, without the IO: it seals our records with
+    their direction, sequence number and content type, checks the peer's, and keeps track of
+    when our sending key is due for a change. EncryptedStream and AsyncEncryptedStream write
+    what it seals and hand it what they read, so both speak exactly the same protocol.
+*/
+#[derive(Clone)]
+pub(crate) struct RecordLayer<C> {
+    pub(crate) send_crypto: C,
+    pub(crate) recv_crypto: C,
+    pub(crate) role: Role,
+    pub(crate) send_seq: u64,
+    pub(crate) recv_seq: u64,
+    pub(crate) rekey_policy: RekeyPolicy,
+    // Records from the peer we dropped as forged, reflected or replayed
+    pub(crate) dropped: u64,
+    records_since_rekey: u64,
+    bytes_since_rekey: u64,
+}
+
+impl<C: Crypto> RecordLayer<C> {
+    pub(crate) fn new(send_crypto: C, recv_crypto: C, role: Role) -> Self {
+        RecordLayer {
+            send_crypto,
+            recv_crypto,
+            role,
+            send_seq: 0,
+            recv_seq: 0,
+            rekey_policy: RekeyPolicy::default(),
+            dropped: 0,
+            records_since_rekey: 0,
+            bytes_since_rekey: 0,
+        }
+    }
+
+    pub(crate) fn send_direction(&self) -> u8 {
+        match self.role {
+            Role::Server => SERVER_TO_CLIENT,
+            Role::Client => CLIENT_TO_SERVER,
+        }
+    }
+
+    pub(crate) fn recv_direction(&self) -> u8 {
+        match self.role {
+            Role::Server => CLIENT_TO_SERVER,
+            Role::Client => SERVER_TO_CLIENT,
+        }
+    }
+
+    // Seal one record with our direction, the next sequence number and its content type
+    pub(crate) fn seal(&mut self, content_type: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::with_capacity(INNER_HEADER_LEN + payload.len());
+        plaintext.push(self.send_direction());
+        plaintext.extend_from_slice(&self.send_seq.to_be_bytes());
+        plaintext.push(content_type);
+        plaintext.extend_from_slice(payload);
+        let record = self.send_crypto.encrypt(&plaintext)?;
+        self.send_seq += 1;
+        if content_type != CONTENT_KEY_UPDATE {
+            self.records_since_rekey += 1;
+            self.bytes_since_rekey += payload.len() as u64;
+        }
+        Ok(record)
+    }
+
+    // Whether the current sending key has carried as much as the policy allows
+    pub(crate) fn rekey_due(&self) -> bool {
+        self.records_since_rekey >= self.rekey_policy.max_records
+            || self.bytes_since_rekey >= self.rekey_policy.max_bytes
+    }
+
+    // The key-update record, sealed under the old key; everything sealed after it uses the new one
+    pub(crate) fn seal_key_update(&mut self) -> io::Result<Vec<u8>> {
+        let record = self.seal(CONTENT_KEY_UPDATE, &[])?;
+        self.send_crypto.rekey()?;
+        self.records_since_rekey = 0;
+        self.bytes_since_rekey = 0;
+        Ok(record)
+    }
+
+    // Decrypt a record, or None if it failed authentication and was dropped
+    pub(crate) fn decrypt(&mut self, raw: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match self.recv_crypto.decrypt(raw) {
+            Ok(plaintext) => Ok(Some(plaintext)),
+            Err(CryptoError::AuthenticationFailed) => {
+                self.dropped += 1;
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /** Open a record the peer sent, returning its content type and payload if it's a message
+        or a stream announcement. None means there's nothing to hand up: the record was forged,
+        replayed or reflected and has been dropped, or it was a key update we've now applied.
+    */
+    pub(crate) fn open(&mut self, raw: &[u8]) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let Some(plaintext) = self.decrypt(raw)? else {
+            return Ok(None);
+        };
+        match self.check_sequence(plaintext)? {
+            Some((CONTENT_KEY_UPDATE, _)) => {
+                self.recv_crypto.rekey()?;
+                Ok(None)
+            }
+            Some((content_type @ (CONTENT_DATA | CONTENT_STREAM), payload)) => {
+                Ok(Some((content_type, payload)))
+            }
+            Some((content_type, _)) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown record type {}", content_type),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    // Strip the inner header, returning the content type and payload, or None if the record should be dropped
+    fn check_sequence(&mut self, mut plaintext: Vec<u8>) -> io::Result<Option<(u8, Vec<u8>)>> {
+        if plaintext.len() < INNER_HEADER_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "record too short for its sequence header",
+            ));
+        }
+        // reflected back from our own direction
+        if plaintext[0] != self.recv_direction() {
+            self.dropped += 1;
+            return Ok(None);
+        }
+        let mut seq = [0_u8; 8];
+        seq.copy_from_slice(&plaintext[1..9]);
+        let seq = u64::from_be_bytes(seq);
+
+        // replayed
+        if seq < self.recv_seq {
+            self.dropped += 1;
+            return Ok(None);
+        }
+        if seq > self.recv_seq {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("expected record {} but got {}", self.recv_seq, seq),
+            ));
+        }
+        self.recv_seq += 1;
+        let content_type = plaintext[9];
+        Ok(Some((content_type, plaintext.split_off(INNER_HEADER_LEN))))
+    }
+}
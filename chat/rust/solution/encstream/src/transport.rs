@@ -0,0 +1,44 @@
+use std::io;
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/** What EncryptedStream needs from its transport for close(), and to hang up on a failed
+    handshake: shut the connection down in both directions, so the peer's next read sees the
+    end of the stream and so do any clones of ours. Transports with nothing to shut down,
+    like stdio, can just return Ok(()).
+*/
+pub trait Close {
+    fn close(&mut self) -> io::Result<()>;
+}
+
+// What EncryptedStream needs from its transport for try_clone(): a second handle on the same connection
+pub trait TryClone: Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Close for TcpStream {
+    fn close(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+impl TryClone for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl Close for UnixStream {
+    fn close(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl TryClone for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
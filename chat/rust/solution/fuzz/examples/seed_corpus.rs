@@ -0,0 +1,309 @@
+/* Regenerate the checked-in seeds under corpus/ from real sessions between the seeded peers:
+   cargo run --example seed_corpus
+   Seeds are named seed-*, so whatever libFuzzer adds next to them stays out of git.
+*/
+use chat_fuzz::{
+    client_crypto, identity, rng, sessions, ClientSuites, Target, PASSWORD, PSK, STREAM_CONTEXT,
+    STREAM_FOLLOWS, USERNAME,
+};
+use crypto_utils::{
+    Crypto, DhGroup, PreSharedKey, ServerPublicKey, SrpClient, SrpVerifier, StreamEncryptor,
+    UserIdentity, STREAM_CHUNK_LEN,
+};
+use encstream::{CipherSuite, Close, Credentials, EncryptedStream, KeyExchange, Pipe};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+fn write_seeds(target: &str, seeds: Vec<(&str, Vec<u8>)>) {
+    let dir: PathBuf = [env!("CARGO_MANIFEST_DIR"), "corpus", target]
+        .iter()
+        .collect();
+    fs::create_dir_all(&dir).unwrap();
+    for (name, seed) in seeds {
+        fs::write(dir.join(format!("seed-{}", name)), seed).unwrap();
+    }
+}
+
+fn tagged(tag: u8, bytes: &[u8]) -> Vec<u8> {
+    let mut seed = vec![tag];
+    seed.extend_from_slice(bytes);
+    seed
+}
+
+fn decrypt_seeds() -> Vec<(&'static str, Vec<u8>)> {
+    let sessions = sessions();
+    let message = b"Username granted!";
+    let mut stream =
+        StreamEncryptor::new(&sessions.x25519.theirs, STREAM_CONTEXT, Vec::new()).unwrap();
+    // a bit over one chunk, so there's a full chunk and a short last one
+    stream
+        .write_all(&vec![b'x'; STREAM_CHUNK_LEN + 100])
+        .unwrap();
+    vec![
+        (
+            "ecb",
+            tagged(0, &sessions.ecb.theirs.encrypt(message).unwrap()),
+        ),
+        (
+            "gcm",
+            tagged(1, &sessions.gcm.theirs.encrypt(message).unwrap()),
+        ),
+        (
+            "x25519",
+            tagged(2, &sessions.x25519.theirs.encrypt(message).unwrap()),
+        ),
+        (
+            "python",
+            tagged(3, &sessions.python.theirs.encrypt(message).unwrap()),
+        ),
+        ("stream", tagged(4, &stream.finish().unwrap())),
+    ]
+}
+
+fn deserialize_seeds() -> Vec<(&'static str, Vec<u8>)> {
+    let sessions = sessions();
+    let mut user = UserIdentity::generate_with_rng(rng(4));
+    let verifier = SrpVerifier::generate_with_rng("alice", "hunter2", 1, &mut rng(5));
+    vec![
+        ("ecb", tagged(0, &sessions.ecb.their_public_key)),
+        ("x25519", tagged(1, &sessions.x25519.their_public_key)),
+        ("python", tagged(2, &sessions.python.their_public_key)),
+        ("server-key", tagged(3, identity().public_key().as_bytes())),
+        ("user-key", tagged(4, &user.public_key().to_bytes())),
+        (
+            "prekey-bundle",
+            tagged(5, &user.prekey_bundle(2).to_bytes()),
+        ),
+        ("srp-verifier", tagged(6, &verifier.to_bytes())),
+        (
+            "srp-public-key",
+            tagged(
+                7,
+                &SrpClient::new(&client_crypto(), "alice", "hunter2").public_key(),
+            ),
+        ),
+    ]
+}
+
+// The client's end of a Pipe, keeping a copy of everything it sends
+struct Recording {
+    pipe: Pipe,
+    sent: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for Recording {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.pipe.read(buf)
+    }
+}
+
+impl Write for Recording {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.pipe.write(buf)?;
+        self.sent.lock().unwrap().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pipe.flush()
+    }
+}
+
+impl Close for Recording {
+    fn close(&mut self) -> io::Result<()> {
+        self.pipe.close()
+    }
+}
+
+// The seeded clients take the seeded server at its word
+fn accept(_: &ServerPublicKey) -> io::Result<()> {
+    Ok(())
+}
+
+/** Everything a seeded client sends in one session with target's seeded server.
+    connect runs the client's handshake; if that goes through, chat runs on the stream.
+*/
+fn record<C, F, G>(target: Target, connect: F, chat: G) -> Vec<u8>
+where
+    C: KeyExchange,
+    F: FnOnce(Recording) -> io::Result<EncryptedStream<C, Recording>>,
+    G: FnOnce(&mut EncryptedStream<C, Recording>),
+{
+    let (pipe, server_end) = Pipe::pair();
+    let server = thread::spawn(move || target.serve(server_end));
+
+    let sent = Arc::default();
+    let socket = Recording {
+        pipe,
+        sent: Arc::clone(&sent),
+    };
+    if let Ok(mut client) = connect(socket) {
+        chat(&mut client);
+        client.close();
+    }
+    server.join().unwrap();
+    let sent = sent.lock().unwrap().clone();
+    sent
+}
+
+// A target must get through what it was seeded with, or the seeds are worthless
+fn check(target: Target, seeds: &[(&str, Vec<u8>)]) {
+    for (name, seed) in seeds {
+        assert!(
+            target.replay(seed) > 0,
+            "the {:?} target rejected the recorded {} session",
+            target,
+            name
+        );
+    }
+}
+
+fn recv_seeds() -> Vec<(&'static str, Vec<u8>)> {
+    let recv_seed = |chat: fn(&mut EncryptedStream<_, Recording>)| {
+        record(
+            Target::Recv,
+            |socket| {
+                EncryptedStream::dh_handshake_client_with(socket, None, client_crypto(), accept)
+            },
+            chat,
+        )
+    };
+    let seeds = vec![
+        (
+            "chat",
+            recv_seed(|client| {
+                client.send("alice").unwrap();
+                client.send("hello everyone").unwrap();
+                client.send("/list").unwrap();
+            }),
+        ),
+        (
+            "rekey",
+            recv_seed(|client| {
+                client.send("alice").unwrap();
+                client.rekey().unwrap();
+                client.send("still here").unwrap();
+            }),
+        ),
+        (
+            "stream",
+            recv_seed(|client| {
+                client.send("alice").unwrap();
+                client.send(STREAM_FOLLOWS).unwrap();
+                client.send_stream(&b"a small file"[..]).unwrap();
+                client.send("sent it").unwrap();
+            }),
+        ),
+    ];
+    check(Target::Recv, &seeds);
+    seeds
+}
+
+fn chat(client: &mut EncryptedStream<ClientSuites, Recording>) {
+    client.send("alice").unwrap();
+    client.send("hello everyone").unwrap();
+}
+
+fn handshake_seeds() -> Vec<(&'static str, Vec<u8>)> {
+    // offering everything settles on the strongest suite; the rest are offered one at a time
+    let negotiated = record(
+        Target::Handshake,
+        |socket| EncryptedStream::<ClientSuites, _>::dh_handshake_client(socket, accept),
+        chat,
+    );
+    let suite_seed = |suite| {
+        record(
+            Target::Handshake,
+            |socket| {
+                let crypto = ClientSuites::for_suite(suite).unwrap();
+                EncryptedStream::dh_handshake_client_with(socket, None, crypto, accept)
+            },
+            chat,
+        )
+    };
+    let seeds = vec![
+        ("negotiated", negotiated),
+        ("x25519", suite_seed(CipherSuite::X25519Aes128Gcm)),
+        (
+            "gcm",
+            suite_seed(CipherSuite::DhAes128Gcm(DhGroup::Modp2048)),
+        ),
+        (
+            "ecb",
+            suite_seed(CipherSuite::DhAes128Ecb(DhGroup::Modp2048)),
+        ),
+    ];
+    check(Target::Handshake, &seeds);
+    seeds
+}
+
+fn login_seeds() -> Vec<(&'static str, Vec<u8>)> {
+    let psk = PreSharedKey::new(PSK);
+    let login_seed = |credentials: Credentials| {
+        record(
+            Target::Login,
+            |socket| {
+                EncryptedStream::<ClientSuites, _>::dh_handshake_client_login(
+                    socket,
+                    Some(&psk),
+                    &credentials,
+                    accept,
+                )
+            },
+            chat,
+        )
+    };
+    let credentials = |username: &str, password: &str| Credentials::Login {
+        username: username.to_string(),
+        password: password.to_string(),
+    };
+    let seeds = vec![
+        ("login", login_seed(credentials(USERNAME, PASSWORD))),
+        (
+            "register",
+            login_seed(Credentials::Register {
+                username: "bob".to_string(),
+                password: "correct horse".to_string(),
+            }),
+        ),
+        (
+            "guest",
+            record(
+                Target::Login,
+                |socket| {
+                    EncryptedStream::<ClientSuites, _>::dh_handshake_client_psk(
+                        socket, &psk, accept,
+                    )
+                },
+                chat,
+            ),
+        ),
+    ];
+    check(Target::Login, &seeds);
+    // these get as far as the password check and no further, like the attacks they stand for
+    let failed = vec![
+        (
+            "wrong-password",
+            login_seed(credentials(USERNAME, "hunter3")),
+        ),
+        ("unknown-user", login_seed(credentials("mallory", PASSWORD))),
+    ];
+    seeds.into_iter().chain(failed).collect()
+}
+
+fn main() {
+    write_seeds("decrypt", decrypt_seeds());
+    write_seeds("deserialize", deserialize_seeds());
+    write_seeds("recv", recv_seeds());
+    write_seeds("handshake", handshake_seeds());
+    write_seeds("login", login_seeds());
+    println!(
+        "Seeds written to {}",
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("corpus")
+            .display()
+    );
+}
@@ -0,0 +1,27 @@
+#![no_main]
+// Every cipher's decrypt() on bytes straight off the wire. The first byte picks the cipher;
+// the rest is the ciphertext, or for StreamDecryptor the whole stream after its announcement.
+use chat_fuzz::{sessions, STREAM_CONTEXT};
+use crypto_utils::{Crypto, StreamDecryptor};
+use libfuzzer_sys::fuzz_target;
+use std::io;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&cipher, ciphertext)) = data.split_first() else {
+        return;
+    };
+    let sessions = sessions();
+    match cipher % 5 {
+        0 => drop(sessions.ecb.ours.decrypt(ciphertext)),
+        1 => drop(sessions.gcm.ours.decrypt(ciphertext)),
+        2 => drop(sessions.x25519.ours.decrypt(ciphertext)),
+        3 => drop(sessions.python.ours.decrypt(ciphertext)),
+        _ => {
+            if let Ok(mut stream) =
+                StreamDecryptor::new(&sessions.x25519.ours, STREAM_CONTEXT, ciphertext)
+            {
+                let _ = io::copy(&mut stream, &mut io::sink());
+            }
+        }
+    }
+});
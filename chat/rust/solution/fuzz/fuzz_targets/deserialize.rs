@@ -0,0 +1,32 @@
+#![no_main]
+// Everything that turns a peer's bytes into a key. The first byte picks the parser;
+// key-agreement handshakes run in full, since validating the key is where the math happens.
+use chat_fuzz::{server_crypto, sessions};
+use crypto_utils::{Crypto, PrekeyBundle, ServerPublicKey, SrpServer, SrpVerifier, UserPublicKey};
+use libfuzzer_sys::fuzz_target;
+use std::sync::OnceLock;
+
+fn srp_server() -> &'static SrpServer {
+    static SERVER: OnceLock<SrpServer> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let verifier = SrpVerifier::with_iterations("alice", "hunter2", 1);
+        SrpServer::new(&server_crypto(), "alice", &verifier)
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&parser, key)) = data.split_first() else {
+        return;
+    };
+    let sessions = sessions();
+    match parser % 8 {
+        0 => drop(sessions.ecb.ours.clone().handshake(key)),
+        1 => drop(sessions.x25519.ours.clone().handshake(key)),
+        2 => drop(sessions.python.ours.clone().handshake(key)),
+        3 => drop(ServerPublicKey::from_bytes(key)),
+        4 => drop(UserPublicKey::from_bytes(key)),
+        5 => drop(PrekeyBundle::from_bytes(key)),
+        6 => drop(SrpVerifier::from_bytes(key)),
+        _ => drop(srp_server().session_key(key)),
+    }
+});
@@ -0,0 +1,8 @@
+#![no_main]
+// A hostile client of a server that offers every suite: the input is every byte it sends,
+// from the hello through whichever key exchange it picks to the records read by recv().
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    chat_fuzz::Target::Handshake.replay(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+// A hostile client of a server with a team PSK and accounts: the input is every byte it
+// sends, through the PSK confirmation and the SRP login or registration to its first records.
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    chat_fuzz::Target::Login.replay(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+// A hostile client: the input is every byte it sends a fresh server connection,
+// from the hello through the handshake to the records read by recv().
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    chat_fuzz::Target::Recv.replay(data);
+});
@@ -0,0 +1,352 @@
+/* Shared setup for the fuzz targets and for examples/seed_corpus.rs.
+   Every key and nonce comes from a fixed seed, so what the seeder records from real sessions
+   still authenticates when a target replays it, and mutations start from inputs that get
+   past the MACs instead of bouncing off them.
+
+   cargo +nightly fuzz run handshake
+*/
+use crypto_utils::{
+    AeadDiffieHellman, Crypto, CryptoError, DhGroup, NoiseStaticKey, NoiseXX, PreSharedKey,
+    PrimeDiffieHellman, PythonCompatDiffieHellman, ServerIdentity, ServerPublicKey, SrpVerifier,
+    X25519DiffieHellman, NOISE_KEY_LEN,
+};
+use encstream::{AccountStore, CipherSuite, Close, EncryptedStream, KeyExchange, Negotiated};
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Mutex, OnceLock};
+use zeroize::Zeroizing;
+
+// Seeds for our end of every session, the peer's end, the server's identity key,
+// and the salt of the one account the login target starts with
+const OUR_SEED: u64 = 1;
+const THEIR_SEED: u64 = 2;
+const IDENTITY_SEED: u64 = 3;
+const ACCOUNT_SEED: u64 = 5;
+
+// That account, and the team PSK the login target's server expects
+pub const USERNAME: &str = "alice";
+pub const PASSWORD: &str = "hunter2";
+pub const PSK: &[u8] = b"chat fuzz team secret";
+
+// A message saying the client's next record starts a stream. recv() can't hand back
+// a stream it has run into, so the targets need telling when to call recv_stream().
+pub const STREAM_FOLLOWS: &str = "/stream";
+
+// What the decrypt target's StreamDecryptor is bound to
+pub const STREAM_CONTEXT: &[u8] = b"chat fuzz stream";
+
+pub fn rng(seed: u64) -> ChaCha20Rng {
+    ChaCha20Rng::seed_from_u64(seed)
+}
+
+// Both ends of one session, and the public key the peer sent us
+pub struct Session<C> {
+    pub ours: C,
+    pub theirs: C,
+    pub their_public_key: Vec<u8>,
+}
+
+fn session<C: Crypto>(mut ours: C, mut theirs: C) -> Session<C> {
+    let our_public_key = ours.init_keys();
+    let their_public_key = theirs.init_keys();
+    ours.handshake(&their_public_key)
+        .expect("seeded keys are valid");
+    theirs
+        .handshake(&our_public_key)
+        .expect("seeded keys are valid");
+    Session {
+        ours,
+        theirs,
+        their_public_key,
+    }
+}
+
+pub struct Sessions {
+    pub ecb: Session<PrimeDiffieHellman>,
+    pub gcm: Session<AeadDiffieHellman>,
+    pub x25519: Session<X25519DiffieHellman>,
+    // crypto.py's scheme has no way to seed it, so only its shape is worth fuzzing
+    pub python: Session<PythonCompatDiffieHellman>,
+}
+
+pub fn sessions() -> &'static Sessions {
+    static SESSIONS: OnceLock<Sessions> = OnceLock::new();
+    SESSIONS.get_or_init(|| Sessions {
+        ecb: session(
+            PrimeDiffieHellman::with_rng(DhGroup::Modp2048, rng(OUR_SEED)),
+            PrimeDiffieHellman::with_rng(DhGroup::Modp2048, rng(THEIR_SEED)),
+        ),
+        gcm: session(
+            AeadDiffieHellman::with_rng(DhGroup::Modp2048, rng(OUR_SEED)),
+            AeadDiffieHellman::with_rng(DhGroup::Modp2048, rng(THEIR_SEED)),
+        ),
+        x25519: session(
+            X25519DiffieHellman::with_rng(rng(OUR_SEED)),
+            X25519DiffieHellman::with_rng(rng(THEIR_SEED)),
+        ),
+        python: session(
+            PythonCompatDiffieHellman::new(),
+            PythonCompatDiffieHellman::new(),
+        ),
+    })
+}
+
+pub fn identity() -> &'static ServerIdentity {
+    static IDENTITY: OnceLock<ServerIdentity> = OnceLock::new();
+    IDENTITY.get_or_init(|| ServerIdentity::generate_with_rng(&mut rng(IDENTITY_SEED)))
+}
+
+// The server's and client's key exchange for the recv target's sessions
+pub fn server_crypto() -> X25519DiffieHellman {
+    X25519DiffieHellman::with_rng(rng(OUR_SEED))
+}
+
+pub fn client_crypto() -> X25519DiffieHellman {
+    X25519DiffieHellman::with_rng(rng(THEIR_SEED))
+}
+
+// A client that has already sent everything it ever will, and never reads the replies
+struct Replay<'a>(&'a [u8]);
+
+impl Read for Replay<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Replay<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Close for Replay<'_> {
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/** Whichever suite the hello exchange settled on, every instance drawing from rng(SEED).
+    dh_handshake builds its keys with for_suite rather than from an instance we hand it,
+    so the seed has to travel in the type.
+*/
+#[derive(Clone, Debug)]
+pub struct Seeded<const SEED: u64>(Negotiated);
+
+pub type ServerSuites = Seeded<OUR_SEED>;
+pub type ClientSuites = Seeded<THEIR_SEED>;
+
+impl<const SEED: u64> Default for Seeded<SEED> {
+    fn default() -> Self {
+        Self::for_suite(CipherSuite::NoiseXX).expect("every suite can be seeded")
+    }
+}
+
+impl<const SEED: u64> Crypto for Seeded<SEED> {
+    type PublicKey = Vec<u8>;
+
+    fn init_keys(&mut self) -> Vec<u8> {
+        self.0.init_keys()
+    }
+
+    fn handshake(&mut self, other_pub_key: &[u8]) -> Result<(), CryptoError> {
+        self.0.handshake(other_pub_key)
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.0.encrypt(plaintext)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.0.decrypt(ciphertext)
+    }
+
+    fn rekey(&mut self) -> Result<(), CryptoError> {
+        self.0.rekey()
+    }
+
+    fn mix_psk(&mut self, psk: &PreSharedKey) -> Result<(), CryptoError> {
+        self.0.mix_psk(psk)
+    }
+
+    fn stream_key(&self, salt: &[u8], context: &[u8]) -> Result<Zeroizing<[u8; 16]>, CryptoError> {
+        self.0.stream_key(salt, context)
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        self.0.fill_random(buf)
+    }
+
+    fn serialize(&self, pub_key: &Vec<u8>) -> Vec<u8> {
+        self.0.serialize(pub_key)
+    }
+
+    fn deserialize(&self, pub_key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.0.deserialize(pub_key)
+    }
+}
+
+impl<const SEED: u64> KeyExchange for Seeded<SEED> {
+    fn suite(&self) -> CipherSuite {
+        self.0.suite()
+    }
+
+    fn for_suite(suite: CipherSuite) -> Option<Self> {
+        let negotiated = match suite {
+            CipherSuite::DhAes128Ecb(group) => {
+                Negotiated::DhAes128Ecb(PrimeDiffieHellman::with_rng(group, rng(SEED)))
+            }
+            CipherSuite::DhAes128Gcm(group) => {
+                Negotiated::DhAes128Gcm(AeadDiffieHellman::with_rng(group, rng(SEED)))
+            }
+            CipherSuite::X25519Aes128Gcm => {
+                Negotiated::X25519Aes128Gcm(X25519DiffieHellman::with_rng(rng(SEED)))
+            }
+            CipherSuite::NoiseXX => Negotiated::NoiseXX(NoiseXX::with_rng(rng(SEED))),
+        };
+        Some(Seeded(negotiated))
+    }
+
+    fn server_handshake<S: Read + Write>(
+        self,
+        socket: &mut S,
+        identity: &ServerIdentity,
+    ) -> io::Result<(Self, Self)> {
+        let (send, recv) = self.0.server_handshake(socket, identity)?;
+        Ok((Seeded(send), Seeded(recv)))
+    }
+
+    fn client_handshake<S, F>(self, socket: &mut S, check_identity: F) -> io::Result<(Self, Self)>
+    where
+        S: Read + Write,
+        F: FnOnce(&ServerPublicKey) -> io::Result<()>,
+    {
+        let (send, recv) = self.0.client_handshake(socket, check_identity)?;
+        Ok((Seeded(send), Seeded(recv)))
+    }
+
+    fn set_static_key(&mut self, static_key: &NoiseStaticKey) {
+        self.0.set_static_key(static_key)
+    }
+
+    fn peer_static_key(&self) -> Option<&[u8; NOISE_KEY_LEN]> {
+        self.0.peer_static_key()
+    }
+}
+
+// The accounts a fresh login target server starts with: just USERNAME.
+// None marks a name reserved by a registration still in progress.
+struct Accounts(Mutex<HashMap<String, Option<SrpVerifier>>>);
+
+impl Accounts {
+    fn new() -> Accounts {
+        static VERIFIER: OnceLock<SrpVerifier> = OnceLock::new();
+        // one PBKDF2 round: the server never runs it, and the seeder's client shouldn't wait
+        let verifier = VERIFIER.get_or_init(|| {
+            SrpVerifier::generate_with_rng(USERNAME, PASSWORD, 1, &mut rng(ACCOUNT_SEED))
+        });
+        Accounts(Mutex::new(HashMap::from([(
+            USERNAME.to_string(),
+            Some(verifier.clone()),
+        )])))
+    }
+}
+
+impl AccountStore for Accounts {
+    fn lookup(&self, username: &str) -> Option<SrpVerifier> {
+        self.0.lock().unwrap().get(username).cloned().flatten()
+    }
+
+    fn reserve(&self, username: &str) -> io::Result<()> {
+        let mut accounts = self.0.lock().unwrap();
+        if accounts.contains_key(username) {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, "name taken"));
+        }
+        accounts.insert(username.to_string(), None);
+        Ok(())
+    }
+
+    fn register(&self, username: &str, verifier: SrpVerifier) -> io::Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(username.to_string(), Some(verifier));
+        Ok(())
+    }
+
+    fn release(&self, username: &str) {
+        let mut accounts = self.0.lock().unwrap();
+        if let Some(None) = accounts.get(username) {
+            accounts.remove(username);
+        }
+    }
+}
+
+// Read messages, and streams announced by STREAM_FOLLOWS, until the stream gives up;
+// say how many there were
+fn received<C: KeyExchange, S: Read + Write>(stream: io::Result<EncryptedStream<C, S>>) -> usize {
+    let mut received = 0;
+    let Ok(mut stream) = stream else {
+        return 0;
+    };
+    while let Ok(Some(message)) = stream.recv() {
+        received += 1;
+        if message == STREAM_FOLLOWS && !matches!(stream.recv_stream(io::sink()), Ok(Some(_))) {
+            break;
+        }
+    }
+    received
+}
+
+// The server end of each target that fuzzes whole connections
+#[derive(Clone, Copy, Debug)]
+pub enum Target {
+    // dh_handshake_with a seeded X25519 instance, so only the records vary much
+    Recv,
+    // dh_handshake over every seeded suite: the hello negotiation and each key exchange
+    Handshake,
+    // dh_handshake_accounts with PSK: its confirmation, then the SRP login or registration
+    Login,
+}
+
+impl Target {
+    /** Run this target's seeded server over socket and read messages until it gives up.
+        Returns how many it read.
+    */
+    pub fn serve<S: Read + Write + Close>(self, socket: S) -> usize {
+        match self {
+            Target::Recv => received(EncryptedStream::dh_handshake_with(
+                socket,
+                identity(),
+                None,
+                server_crypto(),
+            )),
+            Target::Handshake => received(EncryptedStream::<ServerSuites, S>::dh_handshake(
+                socket,
+                identity(),
+            )),
+            Target::Login => {
+                let accounts = Accounts::new();
+                received(EncryptedStream::<ServerSuites, S>::dh_handshake_accounts(
+                    socket,
+                    identity(),
+                    Some(&PreSharedKey::new(PSK)),
+                    &accounts,
+                ))
+            }
+        }
+    }
+
+    /** Hand `data` to a fresh server connection as everything its client ever sends.
+        Whatever the server writes back is thrown away.
+    */
+    pub fn replay(self, data: &[u8]) -> usize {
+        self.serve(Replay(data))
+    }
+}
@@ -0,0 +1,165 @@
+use crypto_utils::SrpVerifier;
+use encstream::{valid_username, AccountStore};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/** Registered accounts, one `username verifier-hex` line per account.
+    Only SRP verifiers are kept, never passwords. A verifier still lets whoever holds it test
+    password guesses, so on unix the file is created readable by its owner only.
+    Handshakes run on their own threads, hence the lock. Names held for a registration that
+    hasn't finished are kept alongside, with no verifier yet, and never reach the file.
+*/
+pub struct Accounts {
+    path: PathBuf,
+    verifiers: Mutex<HashMap<String, Option<SrpVerifier>>>,
+}
+
+impl Accounts {
+    // A missing file is just no accounts yet; it gets created on the first registration
+    pub fn load(path: &Path) -> io::Result<Accounts> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut verifiers = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = line
+                .split_once(char::is_whitespace)
+                .filter(|(username, _)| valid_username(username))
+                .and_then(|(username, verifier)| {
+                    let verifier = hex::decode(verifier.trim()).ok()?;
+                    Some((username, SrpVerifier::from_bytes(&verifier).ok()?))
+                });
+            match entry {
+                Some((username, verifier)) => {
+                    verifiers.insert(username.to_string(), Some(verifier))
+                }
+                None => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{}:{}: malformed account entry", path.display(), number + 1),
+                    ))
+                }
+            };
+        }
+
+        Ok(Accounts {
+            path: path.to_path_buf(),
+            verifiers: Mutex::new(verifiers),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.verifiers.lock().unwrap().values().flatten().count()
+    }
+
+    pub fn is_registered(&self, username: &str) -> bool {
+        self.lookup(username).is_some()
+    }
+}
+
+impl AccountStore for Accounts {
+    fn lookup(&self, username: &str) -> Option<SrpVerifier> {
+        self.verifiers
+            .lock()
+            .unwrap()
+            .get(username)
+            .cloned()
+            .flatten()
+    }
+
+    fn reserve(&self, username: &str) -> io::Result<()> {
+        let mut verifiers = self.verifiers.lock().unwrap();
+        if verifiers.contains_key(username) {
+            return Err(already_registered(username));
+        }
+        verifiers.insert(username.to_string(), None);
+        Ok(())
+    }
+
+    fn register(&self, username: &str, verifier: SrpVerifier) -> io::Result<()> {
+        let mut verifiers = self.verifiers.lock().unwrap();
+        if let Some(Some(_)) = verifiers.get(username) {
+            return Err(already_registered(username));
+        }
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&self.path)?;
+        writeln!(file, "{} {}", username, hex::encode(verifier.to_bytes()))?;
+        verifiers.insert(username.to_string(), Some(verifier));
+        println!("Registered account {}", username);
+        Ok(())
+    }
+
+    fn release(&self, username: &str) {
+        let mut verifiers = self.verifiers.lock().unwrap();
+        if let Some(None) = verifiers.get(username) {
+            verifiers.remove(username);
+        }
+    }
+}
+
+fn already_registered(username: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!("{} is already registered", username),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accounts_survive_a_restart_and_names_are_taken_once() {
+        let path = std::env::temp_dir().join(format!("accounts_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let verifier = SrpVerifier::with_iterations("alice", "hunter2", 10);
+
+        let accounts = Accounts::load(&path).unwrap();
+        accounts.register("alice", verifier.clone()).unwrap();
+        let taken = accounts.register("alice", SrpVerifier::with_iterations("alice", "x", 10));
+        assert_eq!(taken.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(
+            accounts.reserve("alice").unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+
+        // a reservation holds the name off until it's released, and never reaches the file
+        accounts.reserve("bob").unwrap();
+        assert_eq!(
+            accounts.reserve("bob").unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+        assert!(!accounts.is_registered("bob"));
+        accounts.release("bob");
+        accounts.reserve("bob").unwrap();
+
+        let accounts = Accounts::load(&path).unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts.lookup("alice"), Some(verifier));
+        assert_eq!(accounts.lookup("bob"), None);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0);
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
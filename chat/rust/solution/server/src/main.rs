@@ -1,18 +1,37 @@
-use encstream::EncryptedStream;
+mod accounts;
+
+use accounts::Accounts;
+use crypto_utils::{
+    Crypto, NoiseXX, PreSharedKey, PrekeyBundle, PythonCompatDiffieHellman, ServerIdentity,
+    NOISE_PARAMS,
+};
+use encstream::{EncryptedStream, KeyExchange, Negotiated};
 use std::collections::HashMap;
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
 use std::sync::mpsc::{channel, Sender};
-use std::{io, thread};
+use std::sync::Arc;
+use std::{env, io, thread};
 
 const LOCAL: &str = "127.0.0.1:4040";
 
-enum Message {
-    Connected(EncryptedStream),
+// Where the long-term identity key lives unless a path is given on the command line
+const IDENTITY_FILE: &str = "server_identity.key";
+
+enum Message<C> {
+    Connected(EncryptedStream<C>),
     Disconnected,
     Text(String),
 }
 
-fn accept(channel: Sender<(SocketAddr, Message)>) {
+// Runs the server side of the handshake on a freshly accepted socket
+type Handshake<C> = Arc<dyn Fn(TcpStream) -> io::Result<EncryptedStream<C>> + Send + Sync>;
+
+// Everything a Crypto implementation needs for its streams to be handed between threads
+trait ServerCrypto: Crypto + Clone + Default + Send + 'static {}
+impl<C: Crypto + Clone + Default + Send + 'static> ServerCrypto for C {}
+
+fn accept<C: ServerCrypto>(channel: Sender<(SocketAddr, Message<C>)>, handshake: Handshake<C>) {
     loop {
         let socket = match TcpListener::bind(LOCAL) {
             Ok(socket) => socket,
@@ -23,7 +42,8 @@ fn accept(channel: Sender<(SocketAddr, Message)>) {
             match stream {
                 Ok(stream) => {
                     let local_channel = channel.clone();
-                    thread::spawn(move || handle_stream(stream, local_channel));
+                    let handshake = handshake.clone();
+                    thread::spawn(move || handle_stream(stream, local_channel, &handshake));
                 }
                 Err(e) => {
                     eprintln!("Accepting socket shutdown {}", e);
@@ -33,9 +53,19 @@ fn accept(channel: Sender<(SocketAddr, Message)>) {
     }
 }
 
-fn handle_stream(socket: TcpStream, channel: Sender<(SocketAddr, Message)>) -> io::Result<()> {
+fn handle_stream<C: ServerCrypto>(
+    socket: TcpStream,
+    channel: Sender<(SocketAddr, Message<C>)>,
+    handshake: &Handshake<C>,
+) -> io::Result<()> {
     let addr = socket.peer_addr()?;
-    let mut enc_stream = EncryptedStream::dh_handshake(socket)?;
+    let mut enc_stream = match handshake(socket) {
+        Ok(enc_stream) => enc_stream,
+        Err(e) => {
+            eprintln!("Handshake with {} failed: {}", addr, e);
+            return Err(e);
+        }
+    };
     let foreign_stream = enc_stream.try_clone()?;
 
     // Notify the server that we've established a connection
@@ -43,57 +73,92 @@ fn handle_stream(socket: TcpStream, channel: Sender<(SocketAddr, Message)>) -> i
         .send((addr, Message::Connected(foreign_stream)))
         .unwrap();
 
+    let mut dropped = 0;
     loop {
-        let msg = match enc_stream.recv() {
-            Ok(Some(txt)) => Message::Text(txt),
-            Ok(None) => {
-                drop(Message::Disconnected);
-                break;
+        let received = enc_stream.recv();
+        // recv() skips forged and replayed records; someone may be tampering with the connection
+        if enc_stream.dropped_records() > dropped {
+            let newly_dropped = enc_stream.dropped_records() - dropped;
+            eprintln!(
+                "Dropped {} forged or replayed records from {}",
+                newly_dropped, addr
+            );
+            dropped = enc_stream.dropped_records();
+        }
+        let msg = match received {
+            Ok(Some(txt)) => {
+                println!("Received: {:?}", &txt);
+                Message::Text(txt)
             }
-            _ => {
-                continue;
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Error receiving message from {}: {:?}", addr, e);
+                break;
             }
         };
 
         channel.send((addr, msg)).unwrap();
     }
 
+    // Let the server forget this client, whichever way the connection ended
+    channel.send((addr, Message::Disconnected)).unwrap();
     Ok(())
 }
 
-struct ClientConnection {
-    stream: EncryptedStream,
+struct ClientConnection<C> {
+    stream: EncryptedStream<C>,
     username: Option<String>,
+    // What this user published for others to start end-to-end sessions with them
+    prekeys: Option<PrekeyBundle>,
 }
 
-impl ClientConnection {
+impl<C: ServerCrypto> ClientConnection<C> {
     fn send(&mut self, txt: &str) {
-        if let Err(e) = self.stream.send(txt) {
-            eprintln!("Error sending message to client: {:?}", e);
+        match self.stream.send(txt) {
+            Ok(()) => println!("Sent: {}", txt.trim()),
+            Err(e) => eprintln!("Error sending message to client: {:?}", e),
         }
     }
 }
 
-#[derive(Default)]
-struct ChatServer {
-    clients: HashMap<SocketAddr, ClientConnection>,
+struct ChatServer<C> {
+    clients: HashMap<SocketAddr, ClientConnection<C>>,
+    // Registered names, which only their owners can use; None if the server has no accounts
+    accounts: Option<Arc<Accounts>>,
 }
 
-impl ChatServer {
-    pub fn new() -> Self {
-        Default::default()
+impl<C: ServerCrypto> ChatServer<C> {
+    pub fn new(accounts: Option<Arc<Accounts>>) -> Self {
+        ChatServer {
+            clients: HashMap::new(),
+            accounts,
+        }
     }
 
-    fn handle_msg(&mut self, addr: SocketAddr, msg: Message) {
+    fn handle_msg(&mut self, addr: SocketAddr, msg: Message<C>) {
         match msg {
             Message::Connected(stream) => {
+                let account = stream.account().map(str::to_string);
                 let mut client = ClientConnection {
                     stream,
                     username: None,
+                    prekeys: None,
                 };
 
-                // We ignore the possible failure here because it'll come back to us via a disconnect later
-                client.send("Enter username: ");
+                // Logged-in clients already proved who they are, so they skip the username prompt
+                match account {
+                    Some(account) if self.is_connected(&account) => {
+                        client.send("Already logged in elsewhere");
+                        client.stream.close();
+                        return;
+                    }
+                    Some(account) => {
+                        client.username = Some(account);
+                        client.send("Username granted!");
+                    }
+                    // We ignore the possible failure here because it'll come back to us via a disconnect later
+                    None => client.send("Enter username: "),
+                }
 
                 self.clients.insert(addr, client);
             }
@@ -101,27 +166,27 @@ impl ChatServer {
                 self.clients.remove(&addr);
             }
             Message::Text(txt) => {
-                let username = {
-                    self.clients
-                        .get_mut(&addr)
-                        .expect("Text messages should only come from clients that are known")
-                        .username
-                        .clone()
+                // A client can keep talking after /quit or being turned away; whatever it
+                // managed to send before its connection closed is dropped here
+                let Some(client) = self.clients.get(&addr) else {
+                    return;
                 };
-                let proposed_username = txt.clone();
+                let username = client.username.clone();
+                // the client sends the name as the line it typed, ending and all
+                let proposed_username = txt.trim_end_matches(['\r', '\n']).to_string();
                 // Negotiating username
-                if username == None {
+                if username.is_none() {
                     // user name is taken
-                    let is_unique = !self
-                        .clients
-                        .values()
-                        .any(move |c| c.username.as_ref() == Some(&txt));
-                    let client = self
-                        .clients
-                        .get_mut(&addr)
-                        .expect("Text messages should only come from clients that are known");
+                    let is_unique = !self.is_connected(&proposed_username);
+                    let is_registered = self
+                        .accounts
+                        .as_ref()
+                        .is_some_and(|a| a.is_registered(&proposed_username));
+                    let client = self.clients.get_mut(&addr).unwrap();
                     if !is_unique {
                         client.send("Username taken!\nEnter username: ");
+                    } else if is_registered {
+                        client.send("Username is registered; log in to use it\nEnter username: ");
                     } else {
                         client.username = Some(proposed_username);
                         client.send("Username granted!");
@@ -133,6 +198,12 @@ impl ChatServer {
         }
     }
 
+    fn is_connected(&self, username: &str) -> bool {
+        self.clients
+            .values()
+            .any(|c| c.username.as_deref() == Some(username))
+    }
+
     pub fn handle_chat_msg(&mut self, addr: SocketAddr, msg: &str) {
         if msg.is_empty() {
             return;
@@ -143,10 +214,11 @@ impl ChatServer {
                 client.stream.close();
                 self.clients.remove(&addr);
             } else if msg == "/list" {
+                // clients still picking a name aren't in the chat yet
                 let usernames = self
                     .clients
-                    .iter()
-                    .map(|c| c.1.username.as_ref().unwrap())
+                    .values()
+                    .filter_map(|c| c.username.as_ref())
                     .collect::<Vec<&String>>();
                 let username_list = format!("Users: {:?}", usernames);
                 let client = self.clients.get_mut(&addr).unwrap();
@@ -158,8 +230,19 @@ impl ChatServer {
                     "
                     /quit - quit the chat
                     /list - list usernames
+                    /msg <user> <text> - send an end-to-end encrypted direct message
                     /help - show this help message",
                 );
+            } else if let Some(bundle) = msg.strip_prefix("/prekeys ") {
+                let client = self.clients.get_mut(&addr).unwrap();
+                match PrekeyBundle::from_hex(bundle) {
+                    Ok(bundle) => client.prekeys = Some(bundle),
+                    Err(e) => client.send(&format!("Invalid prekey bundle: {}", e)),
+                }
+            } else if let Some(username) = msg.strip_prefix("/bundle ") {
+                self.hand_out_prekeys(addr, username);
+            } else if let Some(dm) = msg.strip_prefix("/dm ") {
+                self.relay_direct_message(addr, dm);
             } else {
                 let client = self.clients.get_mut(&addr).unwrap();
 
@@ -179,15 +262,136 @@ impl ChatServer {
             }
         }
     }
+
+    // Give the asking client one of `username`'s bundles, or an empty reply if there's none
+    fn hand_out_prekeys(&mut self, addr: SocketAddr, username: &str) {
+        let bundle = self
+            .clients
+            .values_mut()
+            .find(|c| c.username.as_deref() == Some(username))
+            .and_then(|c| c.prekeys.as_mut())
+            .map(PrekeyBundle::take_one);
+        let reply = match bundle {
+            Some(bundle) => format!("/bundle {} {}", username, bundle.to_hex()),
+            None => format!("/bundle {}", username),
+        };
+        self.clients.get_mut(&addr).unwrap().send(&reply);
+    }
+
+    // Pass an end-to-end encrypted message along as it is; only the recipient can read it
+    fn relay_direct_message(&mut self, addr: SocketAddr, dm: &str) {
+        let sender = self.clients[&addr].username.clone().unwrap();
+        let (recipient, ciphertext) = dm.split_once(' ').unwrap_or((dm, ""));
+        let relayed = format!("/dm {} {}", sender, ciphertext);
+        match self
+            .clients
+            .values_mut()
+            .find(|c| c.username.as_deref() == Some(recipient))
+        {
+            Some(client) => client.send(&relayed),
+            None => {
+                let client = self.clients.get_mut(&addr).unwrap();
+                client.send(&format!("No such user: {}", recipient));
+            }
+        }
+    }
 }
 
-fn main() {
+fn run<C: ServerCrypto>(handshake: Handshake<C>, accounts: Option<Arc<Accounts>>) {
     // Create a channel to send messages to the server
     let (send, recv) = channel();
-    thread::spawn(move || accept(send));
+    thread::spawn(move || accept(send, handshake));
 
-    let mut server = ChatServer::new();
+    let mut server = ChatServer::new(accounts);
     while let Ok((addr, msg)) = recv.recv() {
         server.handle_msg(addr, msg)
     }
 }
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // Speak chat/py's unauthenticated protocol so Python clients can connect
+    if args.first().map(String::as_str) == Some("--python-compat") {
+        println!("Running in Python compatibility mode: connections are NOT secure");
+        run::<PythonCompatDiffieHellman>(Arc::new(EncryptedStream::python_compat_handshake), None);
+        return;
+    }
+
+    // Only negotiate the Noise handshake; clients that can't speak it are turned away
+    let noise = args.first().map(String::as_str) == Some("--noise");
+    if noise {
+        args.remove(0);
+    }
+
+    // Private server: only clients started with the same --psk secret get in
+    let psk = if args.first().map(String::as_str) == Some("--psk") && args.len() >= 2 {
+        let path = args.drain(..2).nth(1).unwrap_or_default();
+        match PreSharedKey::load(Path::new(&path)) {
+            Ok(psk) => Some(psk),
+            Err(e) => panic!("could not load pre-shared key from {}: {}", path, e),
+        }
+    } else {
+        None
+    };
+
+    // Let users register accounts and log in to them with a password
+    let accounts = if args.first().map(String::as_str) == Some("--accounts") && args.len() >= 2 {
+        let path = args.drain(..2).nth(1).unwrap_or_default();
+        match Accounts::load(Path::new(&path)) {
+            Ok(accounts) => Some(Arc::new(accounts)),
+            Err(e) => panic!("could not load accounts from {}: {}", path, e),
+        }
+    } else {
+        None
+    };
+
+    let identity_path = args.pop().unwrap_or_else(|| IDENTITY_FILE.to_string());
+    let identity = match ServerIdentity::load_or_generate(Path::new(&identity_path)) {
+        Ok(identity) => identity,
+        Err(e) => panic!(
+            "could not load server identity from {}: {}",
+            identity_path, e
+        ),
+    };
+    // Clients pin this to authenticate the server
+    println!("Server public key: {}", identity.public_key().to_hex());
+    println!("Fingerprint: {}", identity.public_key().fingerprint());
+
+    if psk.is_some() {
+        println!("Pre-shared key required");
+    }
+    if let Some(accounts) = &accounts {
+        println!("{} registered account(s)", accounts.len());
+    }
+
+    if noise {
+        println!("Using the {} handshake", NOISE_PARAMS);
+        let handshake = signed_handshake(identity, psk, accounts.clone());
+        run::<NoiseXX>(handshake, accounts);
+    } else {
+        let handshake = signed_handshake(identity, psk, accounts.clone());
+        run::<Negotiated>(handshake, accounts);
+    }
+}
+
+fn signed_handshake<C: ServerCrypto + KeyExchange>(
+    identity: ServerIdentity,
+    psk: Option<PreSharedKey>,
+    accounts: Option<Arc<Accounts>>,
+) -> Handshake<C> {
+    Arc::new(move |socket| {
+        let stream = match (&psk, &accounts) {
+            (psk, Some(accounts)) => {
+                EncryptedStream::dh_handshake_accounts(socket, &identity, psk.as_ref(), &**accounts)
+            }
+            (Some(psk), None) => EncryptedStream::dh_handshake_psk(socket, &identity, psk),
+            (None, None) => EncryptedStream::dh_handshake(socket, &identity),
+        }?;
+        println!("Negotiated {}", stream.cipher_suite());
+        if let Some(account) = stream.account() {
+            println!("Logged in as {}", account);
+        }
+        Ok(stream)
+    })
+}